@@ -2,7 +2,7 @@ use std::str;
 use error::{BoostError, BoostResult};
 ///BencodeValue, one of int, string, list, dictionary.
 pub enum BencodeValue<'a> {
-    Integer(i32),
+    Integer(i64),
     Str(&'a [u8]),
     List(Vec<BencodeValue<'a>>),
     Dict(Vec<(&'a [u8],BencodeValue<'a>)>)
@@ -16,6 +16,15 @@ impl<'a> BencodeValue<'a> {
         res
     }
 
+    ///Looks up the value for the given key if this is a dictionary
+    pub fn dict_get(&self, key: &str) -> Option<&BencodeValue<'a>> {
+        match *self {
+            BencodeValue::Dict(ref d) => d.iter().find(|r| r.0 == key.as_bytes()).map(|r| &r.1),
+            _ => None
+        }
+    }
+
+    ///Encodes this value to its bencoded form
     pub fn bencode(&self) -> Vec<u8> {
         match self {
             &BencodeValue::Integer(i) => {
@@ -61,12 +70,16 @@ impl<'a> BencodeValue<'a> {
 
 ///parses a bencoded data string, returns a result and the index after the last character parsed
 fn bdec<'a>(data: &'a [u8]) -> (BoostResult<BencodeValue<'a>>, usize) {
+    //data comes off the network, so running out of it is an error, never a panic
+    if data.is_empty() {
+        return (Err(BoostError::BencodeDecodingErr),0)
+    }
     if data[0] as char == 'd' {
         //list
         let mut pos = 1;
         let mut dct = Vec::new();
         //loop while first unparsed part of dict is e
-        while data[pos] as char != 'e' {
+        while pos < data.len() && data[pos] as char != 'e' {
 
             match bdec(&data[pos .. data.len()]) {
                 //parse string as key, if ok, parse value
@@ -81,10 +94,15 @@ fn bdec<'a>(data: &'a [u8]) -> (BoostResult<BencodeValue<'a>>, usize) {
                         (err, _) => return (err, 0)
                     }
                 },
+                //keys must be strings
+                (Ok(_), _) => return (Err(BoostError::BencodeDecodingErr),0),
                 //if recursion has err, just return err
                 (err, _) => return (err, 0)
             }
         }
+        if pos >= data.len() {
+            return (Err(BoostError::BencodeDecodingErr),0)
+        }
         (Ok(BencodeValue::Dict(dct)), pos+1)
 
     } else if data[0] as char == 'i' {
@@ -94,7 +112,7 @@ fn bdec<'a>(data: &'a [u8]) -> (BoostResult<BencodeValue<'a>>, usize) {
             //parse from after i to before e
             let int_data = &data[1 .. int_end];
             if let Ok(int_str) = str::from_utf8(int_data) {
-                match int_str.parse::<i32>() {
+                match int_str.parse::<i64>() {
                     Ok(int) => (Ok(BencodeValue::Integer(int)),int_end+1),
                     Err(_) => (Err(BoostError::BencodeDecodingErr),0)
                 }
@@ -111,7 +129,7 @@ fn bdec<'a>(data: &'a [u8]) -> (BoostResult<BencodeValue<'a>>, usize) {
         let mut pos = 1;
         let mut lst = Vec::new();
         //loop while first unparsed part of list is e
-        while data[pos] as char != 'e' {
+        while pos < data.len() && data[pos] as char != 'e' {
             match bdec(&data[pos .. data.len()]) {
                 //if result is ok, push result and advance position
                 (Ok(val), end) => {
@@ -122,6 +140,9 @@ fn bdec<'a>(data: &'a [u8]) -> (BoostResult<BencodeValue<'a>>, usize) {
                 (err, _) => return (err, 0)
             }
         }
+        if pos >= data.len() {
+            return (Err(BoostError::BencodeDecodingErr),0)
+        }
         (Ok(BencodeValue::List(lst)), pos+1)
 
     } else {
@@ -131,10 +152,9 @@ fn bdec<'a>(data: &'a [u8]) -> (BoostResult<BencodeValue<'a>>, usize) {
             let (blen, string) = data.split_at(idx);
             //parse len
             if let Ok(len) = str::from_utf8(blen) {
-                if let Ok(slen) = len.parse::<usize>() {
-                    (Ok(BencodeValue::Str(&string[1 .. slen+1])), (len.len() + slen+1) )
-                } else {
-                    (Err(BoostError::BencodeDecodingErr),0)
+                match len.parse::<usize>() {
+                    Ok(slen) if slen < string.len() => (Ok(BencodeValue::Str(&string[1 .. slen+1])), (len.len() + slen+1) ),
+                    _ => (Err(BoostError::BencodeDecodingErr),0)
                 }
             } else {
                 (Err(BoostError::BencodeDecodingErr),0)
@@ -1,5 +1,9 @@
 use std::fmt;
-///A struct that holds a bitvector
+use error::{BoostError, BoostResult};
+
+///A struct that holds a bitvector. Bit 0 is the high bit of the first byte,
+///the same order as a bitfield message
+#[derive(Clone, PartialEq, Eq)]
 pub struct BitVector {
     vec: Vec<u8>,
     num_bits: usize
@@ -8,11 +12,22 @@ pub struct BitVector {
 impl BitVector {
     ///Creates a new bitvector with the specified number of bits
     pub fn new(num_bits: usize) -> Self {
-        let mut bytes = num_bits / 8;
-        if num_bits % 8 != 0 {
-            bytes += 1;
+        BitVector { vec: vec![0; num_bits.div_ceil(8)], num_bits }
+    }
+
+    ///Creates a bitvector of num_bits bits from raw bytes, such as the payload of a
+    ///bitfield message. There must be just enough bytes for the bits, and the spare
+    ///bits at the end of the last byte must all be 0
+    pub fn from_bytes(bytes: &[u8], num_bits: usize) -> BoostResult<Self> {
+        let mut bitvector = BitVector::new(num_bits);
+        if bytes.len() != bitvector.vec.len() {
+            return Err(BoostError::BitTorrentProtocolErr(format!("Bitfield was {} bytes for {} bits", bytes.len(), num_bits)))
+        }
+        bitvector.vec.copy_from_slice(bytes);
+        if bitvector.vec.last().is_some_and(|&last| last & bitvector.spare_mask() != 0) {
+            return Err(BoostError::BitTorrentProtocolErr(String::from("Bitfield had spare bits set")))
         }
-        BitVector { vec: Vec::with_capacity(bytes), num_bits }
+        Ok(bitvector)
     }
 
     ///Sets the given bit from a 0 to a 1
@@ -20,7 +35,16 @@ impl BitVector {
         if index < self.num_bits {
             let byte = index / 8;
             let bit = index % 8;
-            self.vec[byte] = 0x80 >> bit;
+            self.vec[byte] |= 0x80 >> bit;
+        }
+    }
+
+    ///Sets the given bit from a 1 back to a 0
+    pub fn unset_index(&mut self, index: usize) {
+        if index < self.num_bits {
+            let byte = index / 8;
+            let bit = index % 8;
+            self.vec[byte] &= !(0x80 >> bit);
         }
     }
 
@@ -37,12 +61,17 @@ impl BitVector {
 
     ///checks if all the bits are 1
     pub fn is_complete(&self) -> bool {
-        for index in 0..self.num_bits {
-            if !self.index_isset(index) {
-                return false
-            }
-        }
-        true
+        self.count_ones() == self.num_bits
+    }
+
+    ///checks if all the bits are 0
+    pub fn is_empty(&self) -> bool {
+        self.vec.iter().all(|&byte| byte == 0)
+    }
+
+    ///counts the bits that are 1
+    pub fn count_ones(&self) -> usize {
+        self.vec.iter().map(|byte| byte.count_ones() as usize).sum()
     }
 
     ///clears all set 1s
@@ -52,6 +81,17 @@ impl BitVector {
        }
     }
 
+    ///sets every bit to 1
+    pub fn set_all(&mut self) {
+        for byte in self.vec.iter_mut() {
+            *byte = 0xff;
+        }
+        let mask = self.spare_mask();
+        if let Some(last) = self.vec.last_mut() {
+            *last &= !mask;
+        }
+    }
+
     ///returns true if this bitvector and the other have the same length
     ///and some bits in common
     pub fn intersects(&self, other: &Self) -> bool {
@@ -67,26 +107,70 @@ impl BitVector {
        }
     }
 
+    ///returns the bits set in both this bitvector and the other. Bits past
+    ///the end of the other count as 0
+    pub fn and(&self, other: &Self) -> Self {
+        self.combine(other, |a, b| a & b)
+    }
+
+    ///returns the bits set in either this bitvector or the other. The
+    ///result is as long as this one
+    pub fn or(&self, other: &Self) -> Self {
+        self.combine(other, |a, b| a | b)
+    }
+
+    ///returns the bits set in this bitvector but not the other, such as the
+    ///pieces a peer has that we don't
+    pub fn and_not(&self, other: &Self) -> Self {
+        self.combine(other, |a, b| a & !b)
+    }
+
+    ///iterates over the indices of the bits that are 1, in order
+    pub fn iter_set(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.num_bits).filter(move |&idx| self.index_isset(idx))
+    }
+
+    ///iterates over the indices of the bits that are 0, in order
+    pub fn iter_unset(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.num_bits).filter(move |&idx| !self.index_isset(idx))
+    }
+
+    ///the index of the first 0 bit, or the length if there isn't one
     pub fn first_unset_index(&self) -> usize {
-        for idx in 0..self.num_bits {
-            if !self.index_isset(idx) {
-                return idx
-            }
-        }
-        self.num_bits
+        self.iter_unset().next().unwrap_or(self.num_bits)
     }
 
+    ///the number of bits
     pub fn bit_len(&self) -> usize {
         self.num_bits
     }
 
-    pub fn byte_len(&self) -> usize {
-        self.vec.len()
-    }
-
+    ///the raw bytes, as sent in a bitfield message
     pub fn as_bytes(&self) -> &[u8] {
         self.vec.as_slice()
     }
+
+    ///combines each byte of this bitvector with the same byte of the other
+    fn combine<F: Fn(u8, u8) -> u8>(&self, other: &Self, op: F) -> Self {
+        let mut result = BitVector::new(self.num_bits);
+        for (idx, byte) in result.vec.iter_mut().enumerate() {
+            *byte = op(self.vec[idx], other.vec.get(idx).cloned().unwrap_or(0));
+        }
+        //other may be longer, and its bits past our end must not leak into the spare bits
+        let mask = result.spare_mask();
+        if let Some(last) = result.vec.last_mut() {
+            *last &= !mask;
+        }
+        result
+    }
+
+    ///the spare bits at the end of the last byte that don't stand for anything
+    fn spare_mask(&self) -> u8 {
+        match self.num_bits % 8 {
+            0 => 0,
+            used => 0xff >> used
+        }
+    }
 }
 
 impl fmt::Display for BitVector {
@@ -102,3 +186,91 @@ impl fmt::Display for BitVector {
         write!(f, "{}", res)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_bytes_checks_length_and_spare_bits() {
+        let bitvector = BitVector::from_bytes(&[0b1010_0000, 0b1000_0000], 9).ok().unwrap();
+        assert!(bitvector.index_isset(0) && bitvector.index_isset(2) && bitvector.index_isset(8));
+        assert!(!bitvector.index_isset(1));
+        assert!(BitVector::from_bytes(&[0, 0b0100_0000], 9).is_err());
+        assert!(BitVector::from_bytes(&[0, 0, 0], 9).is_err());
+        assert!(BitVector::from_bytes(&[0], 9).is_err());
+        assert!(BitVector::from_bytes(&[0xff], 8).ok().unwrap().is_complete());
+    }
+
+    #[test]
+    fn counts_ones() {
+        let mut bitvector = BitVector::new(11);
+        assert!(bitvector.is_empty());
+        assert_eq!(bitvector.count_ones(), 0);
+        bitvector.set_index(0);
+        bitvector.set_index(10);
+        bitvector.set_index(11);
+        assert_eq!(bitvector.count_ones(), 2);
+        bitvector.unset_index(0);
+        assert_eq!(bitvector.count_ones(), 1);
+        bitvector.set_all();
+        assert_eq!(bitvector.count_ones(), 11);
+        assert!(bitvector.is_complete());
+        assert_eq!(bitvector.as_bytes(), &[0xff, 0b1110_0000][..]);
+        bitvector.clear();
+        assert!(bitvector.is_empty());
+    }
+
+    #[test]
+    fn iterates_set_and_unset_bits() {
+        let mut bitvector = BitVector::new(10);
+        assert_eq!(bitvector.first_unset_index(), 0);
+        for idx in &[0, 1, 3, 9] {
+            bitvector.set_index(*idx);
+        }
+        assert_eq!(bitvector.iter_set().collect::<Vec<_>>(), vec![0, 1, 3, 9]);
+        assert_eq!(bitvector.iter_unset().collect::<Vec<_>>(), vec![2, 4, 5, 6, 7, 8]);
+        assert_eq!(bitvector.first_unset_index(), 2);
+        bitvector.set_all();
+        assert_eq!(bitvector.iter_unset().next(), None);
+        assert_eq!(bitvector.first_unset_index(), 10);
+    }
+
+    #[test]
+    fn and_and_or() {
+        let mut ours = BitVector::new(10);
+        let mut theirs = BitVector::new(10);
+        for idx in &[0, 3, 9] {
+            ours.set_index(*idx);
+        }
+        for idx in &[3, 4, 9] {
+            theirs.set_index(*idx);
+        }
+        assert_eq!(ours.and(&theirs).iter_set().collect::<Vec<_>>(), vec![3, 9]);
+        assert_eq!(ours.or(&theirs).iter_set().collect::<Vec<_>>(), vec![0, 3, 4, 9]);
+        assert!(ours.and(&BitVector::new(10)).is_empty());
+        assert!(ours.or(&BitVector::new(10)) == ours);
+        //a shorter other counts as 0 past its end, a longer one is cut to our length
+        let mut short = BitVector::new(4);
+        short.set_all();
+        assert_eq!(ours.and(&short).iter_set().collect::<Vec<_>>(), vec![0, 3]);
+        let mut long = BitVector::new(16);
+        long.set_all();
+        let or = ours.or(&long);
+        assert!(or.is_complete());
+        assert_eq!(or.as_bytes(), &[0xff, 0b1100_0000][..]);
+    }
+
+    #[test]
+    fn and_not_keeps_spare_bits_clear() {
+        let mut ours = BitVector::new(4);
+        ours.set_index(1);
+        let mut longer = BitVector::new(12);
+        longer.set_index(2);
+        let mut all = BitVector::new(4);
+        all.set_all();
+        assert_eq!(all.and_not(&ours).iter_set().collect::<Vec<_>>(), vec![0, 2, 3]);
+        assert_eq!(all.and_not(&longer).as_bytes(), &[0b1101_0000][..]);
+        assert!(ours.and_not(&all).is_empty());
+    }
+}
@@ -0,0 +1,51 @@
+use std::collections::VecDeque;
+use error::{BoostError, BoostResult};
+use torrentfile::TorrentFile;
+
+///How much piece data the read cache holds at most
+pub const READ_CACHE_BYTES: u64 = 16 * 1024 * 1024;
+
+///Keeps the most recently read pieces in memory, so serving a peer the
+///blocks of a piece one request at a time only reads it from disk once
+pub struct ReadCache {
+    ///most recently used piece first
+    pieces: VecDeque<(u32, Vec<u8>)>,
+    capacity: usize
+}
+
+impl ReadCache {
+    ///Creates a cache that holds as many whole pieces of the given length as fit in READ_CACHE_BYTES
+    pub fn new(piece_len: u64) -> Self {
+        let capacity = READ_CACHE_BYTES.checked_div(piece_len).unwrap_or(1).max(1) as usize;
+        ReadCache { pieces: VecDeque::with_capacity(capacity), capacity }
+    }
+
+    ///Reads a block of a piece, from the cache if the piece is in it or from the
+    ///torrent file otherwise. piece_offset and piece_size give where the piece is
+    ///in the torrent, and the block must lie within it
+    pub fn read_block(&mut self,
+                      torrent_file: &mut TorrentFile,
+                      piece_index: u32,
+                      piece_offset: u64,
+                      piece_size: usize,
+                      begin: u32,
+                      length: u32) -> BoostResult<Vec<u8>> {
+        let piece = match self.pieces.iter().position(|&(index, _)| index == piece_index) {
+            Some(idx) => self.pieces.remove(idx).expect("The cached piece was just found"),
+            None => {
+                let mut data = vec![0; piece_size];
+                let read = torrent_file.read(piece_offset, &mut data)?;
+                if read != piece_size {
+                    return Err(BoostError::FileReadErr(format!("piece {}", piece_index)))
+                }
+                if self.pieces.len() >= self.capacity {
+                    self.pieces.pop_back();
+                }
+                (piece_index, data)
+            }
+        };
+        let block = piece.1[begin as usize..(begin + length) as usize].to_vec();
+        self.pieces.push_front(piece);
+        Ok(block)
+    }
+}
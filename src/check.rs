@@ -0,0 +1,155 @@
+use bitvector::BitVector;
+use error::BoostResult;
+use hashpool::{HashJob, HashPool, Hashed};
+use meta::MetaInfo;
+
+///Most pieces read and waiting to be hashed at once, so checking a large
+///torrent doesn't read all of it into memory
+const MAX_CHECKING: usize = 16;
+
+///Reads every piece of the torrent with read, given the offset into the
+///torrent's data to fill the buffer from, and checks it against its hash, on
+///a HashPool so every core helps. progress is called with how many pieces have
+///been checked and how many there are after each one. Returns the pieces that are good
+pub fn check_pieces<R, F>(mut read: R, meta_info: &MetaInfo, mut progress: F) -> BoostResult<BitVector>
+    where R: FnMut(u64, &mut [u8]) -> BoostResult<usize>, F: FnMut(usize, usize) {
+    let num_pieces = meta_info.num_pieces();
+    let total_len = meta_info.file_info.total_bytes();
+    let mut completed = BitVector::new(num_pieces);
+    let mut hash_pool = HashPool::new();
+    let mut checked = 0;
+    for index in 0..num_pieces {
+        let start = index as u64 * meta_info.piece_len;
+        let mut data = vec![0; meta_info.piece_len.min(total_len - start) as usize];
+        //a file cut short just fails the pieces past its end
+        let read = read(start, &mut data)?;
+        data.truncate(read);
+        hash_pool.submit(HashJob { index: index as u32, data, expected: meta_info.piece_hashes[index] });
+        while hash_pool.pending() >= MAX_CHECKING {
+            match hash_pool.recv() {
+                Some(hashed) => record(hashed, &mut completed, &mut checked, num_pieces, &mut progress),
+                None => break
+            }
+        }
+    }
+    while let Some(hashed) = hash_pool.recv() {
+        record(hashed, &mut completed, &mut checked, num_pieces, &mut progress);
+    }
+    Ok(completed)
+}
+
+///marks a checked piece if it was good and reports the progress
+fn record<F: FnMut(usize, usize)>(hashed: Hashed, completed: &mut BitVector, checked: &mut usize, num_pieces: usize, progress: &mut F) {
+    if hashed.correct {
+        completed.set_index(hashed.index as usize);
+    }
+    *checked += 1;
+    progress(*checked, num_pieces);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use meta::FileInfo;
+    use resume;
+    use sha1::Sha1;
+    use torrentfile::TorrentFile;
+    use std::env;
+    use std::fs;
+    use std::io::Write;
+
+    fn sha1(data: &[u8]) -> [u8; 20] {
+        let mut hasher = Sha1::new();
+        hasher.update(data);
+        hasher.digest().bytes()
+    }
+
+    ///a multifile torrent of files with the given lengths, with 4 byte pieces of
+    ///the bytes 0, 1, 2... under a fresh root directory
+    fn multifile(name: &str, lens: &[u64]) -> (MetaInfo, Vec<u8>) {
+        let rootdir = env::temp_dir().join(format!("boost-check-{}-{}", name, ::std::process::id()));
+        let _ = fs::remove_dir_all(&rootdir);
+        fs::create_dir_all(rootdir.join("sub")).unwrap();
+        let data: Vec<u8> = (0..lens.iter().sum::<u64>()).map(|byte| byte as u8).collect();
+        let files = lens.iter().enumerate()
+            .map(|(idx, &len)| FileInfo::Single { filename: format!("sub/{}", idx), filelength: len })
+            .collect();
+        let meta_info = MetaInfo {
+            announce_url: None,
+            piece_len: 4,
+            info_hash: [0; 20],
+            piece_hashes: data.chunks(4).map(sha1).collect(),
+            file_info: FileInfo::Multi { rootdir: rootdir.to_string_lossy().into_owned(), files },
+            private: false,
+            nodes: Vec::new()
+        };
+        (meta_info, data)
+    }
+
+    fn write_file(meta_info: &MetaInfo, idx: usize, data: &[u8]) {
+        if let FileInfo::Multi { ref rootdir, .. } = meta_info.file_info {
+            fs::File::create(format!("{}/sub/{}", rootdir, idx)).unwrap().write_all(data).unwrap();
+        }
+    }
+
+    #[test]
+    fn reads_pieces_across_files() {
+        let (meta_info, data) = multifile("across", &[3, 6, 1]);
+        write_file(&meta_info, 0, &data[0..3]);
+        write_file(&meta_info, 1, &data[3..9]);
+        write_file(&meta_info, 2, &data[9..10]);
+        let mut torrent_file = TorrentFile::open(&meta_info);
+        assert!(torrent_file.has_data() && torrent_file.problems().is_empty());
+        let mut buffer = [0; 8];
+        assert_eq!(torrent_file.read(2, &mut buffer).ok().unwrap(), 8);
+        assert_eq!(&buffer[..], &data[2..10]);
+        let completed = check_pieces(|offset, buffer| torrent_file.read(offset, buffer), &meta_info, |_, _| ()).ok().unwrap();
+        assert!(completed.is_complete());
+    }
+
+    #[test]
+    fn missing_and_short_files_fail_their_pieces() {
+        let (meta_info, data) = multifile("short", &[4, 4, 4]);
+        write_file(&meta_info, 0, &data[0..4]);
+        write_file(&meta_info, 2, &data[8..11]);
+        let mut torrent_file = TorrentFile::open(&meta_info);
+        let problems = torrent_file.problems();
+        assert_eq!(problems.len(), 2);
+        assert!(problems[0].ends_with("sub/1 is missing"));
+        assert!(problems[1].ends_with("sub/2 is 3 bytes but should be 4 bytes"));
+        let mut buffer = [0; 8];
+        assert_eq!(torrent_file.read(0, &mut buffer).ok().unwrap(), 4);
+        let completed = check_pieces(|offset, buffer| torrent_file.read(offset, buffer), &meta_info, |_, _| ()).ok().unwrap();
+        assert_eq!(completed.iter_set().collect::<Vec<_>>(), vec![0]);
+    }
+
+    #[test]
+    fn nothing_there() {
+        let (meta_info, _) = multifile("empty", &[4, 4]);
+        let mut torrent_file = TorrentFile::open(&meta_info);
+        assert!(!torrent_file.has_data());
+        let completed = check_pieces(|offset, buffer| torrent_file.read(offset, buffer), &meta_info, |_, _| ()).ok().unwrap();
+        assert!(completed.is_empty());
+    }
+
+    #[test]
+    fn checks_what_was_written() {
+        let (meta_info, data) = multifile("written", &[3, 6, 5, 4]);
+        let mut torrent_file = TorrentFile::open(&meta_info);
+        assert!(!torrent_file.has_data());
+        //the last two files are skipped, only the pieces in the first two are downloaded
+        torrent_file.set_skipped_files(vec![false, false, true, true]);
+        for piece in 0..3 {
+            assert_eq!(torrent_file.write(piece * 4, &data[piece as usize * 4..piece as usize * 4 + 4]).ok().unwrap(), 4);
+        }
+        torrent_file.flush().ok().unwrap();
+        let mut torrent_file = TorrentFile::open(&meta_info);
+        let problems = torrent_file.problems();
+        assert_eq!(problems.len(), 2);
+        assert!(problems[0].ends_with("sub/2 is 3 bytes but should be 5 bytes"));
+        assert!(problems[1].ends_with("sub/3 is missing"));
+        let completed = check_pieces(|offset, buffer| torrent_file.read(offset, buffer), &meta_info, |_, _| ()).ok().unwrap();
+        assert_eq!(completed.iter_set().collect::<Vec<_>>(), vec![0, 1, 2]);
+        assert_eq!(resume::data_stamp(&meta_info).map(|(len, _)| len), Some(12));
+    }
+}
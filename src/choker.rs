@@ -0,0 +1,170 @@
+use rand::Rng;
+
+///How often the choker picks who to unchoke
+pub const CHOKE_INTERVAL_SECS: u64 = 10;
+///How many peers are unchoked for their rates, on top of the optimistic unchoke
+pub const UNCHOKE_SLOTS: usize = 4;
+///How many choking rounds the optimistic unchoke lasts, 30 seconds
+const OPTIMISTIC_ROUNDS: u32 = 3;
+///How much more likely a newly connected peer is to get the optimistic unchoke,
+///since it has nothing to offer yet and needs some pieces to start trading
+const NEW_PEER_WEIGHT: usize = 3;
+
+///What the choker needs to know about a peer to rank it
+pub struct ChokeCandidate<K> {
+    pub key: K,
+    ///whether the peer wants pieces from us
+    pub interested: bool,
+    ///whether the peer has stopped sending us blocks, see Peer::is_snubbing
+    pub snubbed: bool,
+    ///bytes per second we got from and sent to the peer since the last round
+    pub download_rate: f64,
+    pub upload_rate: f64,
+    ///whether the peer connected recently
+    pub is_new: bool
+}
+
+///Tit-for-tat choking. Every round the interested peers that send to us the fastest
+///get the regular unchoke slots, or those we send to the fastest once we are
+///seeding and nobody has anything for us. Peers that snub us don't get a regular
+///slot. One more peer is unchoked optimistically and kept for a few rounds, so
+///peers we haven't traded with get a chance to show their rate
+pub struct Choker<K> {
+    optimistic: Option<K>,
+    rounds_since_optimistic: u32
+}
+
+impl<K: Copy + Eq> Choker<K> {
+    pub fn new() -> Self {
+        Choker { optimistic: None, rounds_since_optimistic: 0 }
+    }
+
+    ///Runs one choking round and returns the peers that should be unchoked,
+    ///every other peer should be choked
+    pub fn run_round<R: Rng>(&mut self, candidates: &[ChokeCandidate<K>], seeding: bool, rng: &mut R) -> Vec<K> {
+        let mut ranked: Vec<&ChokeCandidate<K>> = candidates.iter()
+            .filter(|candidate| candidate.interested && !candidate.snubbed)
+            .collect();
+        let rate = |candidate: &ChokeCandidate<K>| if seeding { candidate.upload_rate } else { candidate.download_rate };
+        ranked.sort_by(|a, b| rate(b).partial_cmp(&rate(a)).unwrap_or(::std::cmp::Ordering::Equal));
+        let mut unchoked: Vec<K> = ranked.iter().take(UNCHOKE_SLOTS).map(|candidate| candidate.key).collect();
+
+        //keep the optimistic unchoke while it lasts and the peer still wants pieces
+        self.rounds_since_optimistic += 1;
+        let keep = self.optimistic.is_some_and(|key| {
+            self.rounds_since_optimistic < OPTIMISTIC_ROUNDS
+                && candidates.iter().any(|candidate| candidate.key == key && candidate.interested)
+        });
+        if !keep {
+            self.optimistic = self.pick_optimistic(candidates, &unchoked, rng);
+            self.rounds_since_optimistic = 0;
+        }
+        if let Some(key) = self.optimistic {
+            if !unchoked.contains(&key) {
+                unchoked.push(key);
+            }
+        }
+        unchoked
+    }
+
+    ///picks an interested peer that didn't get a regular slot at random, with new peers weighted up
+    fn pick_optimistic<R: Rng>(&self, candidates: &[ChokeCandidate<K>], unchoked: &[K], rng: &mut R) -> Option<K> {
+        let choices: Vec<&ChokeCandidate<K>> = candidates.iter()
+            .filter(|candidate| candidate.interested && !unchoked.contains(&candidate.key))
+            .collect();
+        let weight = |candidate: &ChokeCandidate<K>| if candidate.is_new { NEW_PEER_WEIGHT } else { 1 };
+        let total: usize = choices.iter().map(|candidate| weight(candidate)).sum();
+        if total == 0 {
+            return None
+        }
+        let mut pick = rng.gen_range(0, total);
+        for candidate in choices {
+            if pick < weight(candidate) {
+                return Some(candidate.key)
+            }
+            pick -= weight(candidate);
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{SeedableRng, StdRng};
+
+    fn candidate(key: u32, download_rate: f64, upload_rate: f64) -> ChokeCandidate<u32> {
+        ChokeCandidate { key, interested: true, snubbed: false, download_rate, upload_rate, is_new: false }
+    }
+
+    #[test]
+    fn fastest_peers_get_the_slots() {
+        let mut rng = StdRng::from_seed(&[1usize][..]);
+        //peer k sends to us at 100k bytes a second, and we send to it the slower the higher k is
+        let candidates: Vec<ChokeCandidate<u32>> = (0..8).map(|key| candidate(key, key as f64 * 100.0, (8 - key) as f64)).collect();
+
+        let mut choker = Choker::new();
+        let unchoked = choker.run_round(&candidates, false, &mut rng);
+        assert_eq!(unchoked.len(), UNCHOKE_SLOTS + 1);
+        assert_eq!(&unchoked[0..UNCHOKE_SLOTS], &[7, 6, 5, 4]);
+        //the optimistic unchoke goes to someone without a slot
+        assert!(unchoked[UNCHOKE_SLOTS] < 4);
+
+        //seeding, nobody sends us anything so what we send them counts
+        let mut choker = Choker::new();
+        let unchoked = choker.run_round(&candidates, true, &mut rng);
+        assert_eq!(&unchoked[0..UNCHOKE_SLOTS], &[0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn snubbing_and_uninterested_peers_get_no_slot() {
+        let mut rng = StdRng::from_seed(&[2usize][..]);
+        let mut snubbing = candidate(0, 1000.0, 0.0);
+        snubbing.snubbed = true;
+        let mut uninterested = candidate(1, 900.0, 0.0);
+        uninterested.interested = false;
+        let candidates = vec![snubbing, uninterested, candidate(2, 1.0, 0.0), candidate(3, 2.0, 0.0)];
+        let mut choker = Choker::new();
+        for _ in 0..20 {
+            let unchoked = choker.run_round(&candidates, false, &mut rng);
+            assert_eq!(&unchoked[0..2], &[3, 2]);
+            assert!(!unchoked.contains(&1));
+            //a snubbing peer can only ever be the optimistic unchoke
+            assert!(unchoked.len() == 2 || unchoked == vec![3, 2, 0]);
+        }
+    }
+
+    #[test]
+    fn optimistic_unchoke_rotates() {
+        let mut rng = StdRng::from_seed(&[3usize][..]);
+        let mut candidates: Vec<ChokeCandidate<u32>> = (0..4).map(|key| candidate(key, 1000.0, 0.0)).collect();
+        candidates.extend((4..14).map(|key| candidate(key, 0.0, 0.0)));
+        candidates[13].is_new = true;
+        let mut choker = Choker::new();
+        let mut picks = Vec::new();
+        for round in 0..300 {
+            let unchoked = choker.run_round(&candidates, false, &mut rng);
+            let optimistic = choker.optimistic.expect("no optimistic unchoke");
+            assert!(optimistic >= 4);
+            assert_eq!(unchoked.last(), Some(&optimistic));
+            //kept for OPTIMISTIC_ROUNDS rounds, then picked again
+            if round % OPTIMISTIC_ROUNDS as usize == 0 {
+                picks.push(optimistic);
+            } else {
+                assert_eq!(Some(&optimistic), picks.last());
+            }
+        }
+        //everyone gets a turn, and the new peer is weighted up: 3 in 12 rather than 1 in 10
+        assert!((4..14).all(|key| picks.contains(&key)));
+        let new_picks = picks.iter().filter(|&&key| key == 13).count();
+        assert!(new_picks > picks.len() / 10 + 5, "new peer picked {} of {} times", new_picks, picks.len());
+
+        //an optimistic unchoke that loses interest is replaced straight away
+        let mut choker = Choker::new();
+        choker.run_round(&candidates, false, &mut rng);
+        let optimistic = choker.optimistic.unwrap() as usize;
+        candidates[optimistic].interested = false;
+        choker.run_round(&candidates, false, &mut rng);
+        assert!(choker.optimistic != Some(optimistic as u32));
+    }
+}
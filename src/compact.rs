@@ -0,0 +1,50 @@
+use byteorder::{NetworkEndian, ByteOrder};
+use std::net::{Ipv4Addr, SocketAddrV4};
+
+///Length of an address in the compact form trackers, the DHT and ut_pex share
+pub const COMPACT_ADDR_LEN: usize = 6;
+
+///Encodes an address in the 6 byte compact form, ip then port in network byte order
+pub fn compact_addr(addr: SocketAddrV4) -> [u8; COMPACT_ADDR_LEN] {
+    let mut buf = [0u8; COMPACT_ADDR_LEN];
+    buf[0..4].copy_from_slice(&addr.ip().octets());
+    NetworkEndian::write_u16(&mut buf[4..6], addr.port());
+    buf
+}
+
+///Decodes a 6 byte compact address
+pub fn parse_compact_addr(buf: &[u8]) -> SocketAddrV4 {
+    let ip = Ipv4Addr::from(NetworkEndian::read_u32(&buf[0..4]));
+    let port = NetworkEndian::read_u16(&buf[4..6]);
+    SocketAddrV4::new(ip, port)
+}
+
+///Decodes a string of compact addresses. Any trailing partial address is ignored
+pub fn parse_compact_peers(buf: &[u8]) -> Vec<SocketAddrV4> {
+    buf.chunks(COMPACT_ADDR_LEN)
+        .filter(|chunk| chunk.len() == COMPACT_ADDR_LEN)
+        .map(parse_compact_addr)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips() {
+        let addr = SocketAddrV4::new(Ipv4Addr::new(10, 1, 2, 254), 6881);
+        let compact = compact_addr(addr);
+        assert_eq!(compact, [10, 1, 2, 254, 0x1a, 0xe1]);
+        assert_eq!(parse_compact_addr(&compact), addr);
+    }
+
+    #[test]
+    fn ignores_trailing_bytes() {
+        let mut buf = compact_addr(SocketAddrV4::new(Ipv4Addr::new(1, 2, 3, 4), 1)).to_vec();
+        buf.extend_from_slice(&compact_addr(SocketAddrV4::new(Ipv4Addr::new(5, 6, 7, 8), 2)));
+        buf.extend_from_slice(&[9, 9, 9]);
+        assert_eq!(parse_compact_peers(&buf), vec![SocketAddrV4::new(Ipv4Addr::new(1, 2, 3, 4), 1),
+                                                   SocketAddrV4::new(Ipv4Addr::new(5, 6, 7, 8), 2)]);
+    }
+}
@@ -0,0 +1,814 @@
+use bencode::BencodeValue;
+use byteorder::{NetworkEndian, ByteOrder};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, ToSocketAddrs, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+use std::{str, thread};
+use sha1::Sha1;
+use rand;
+use error::{BoostError, BoostResult};
+use compact::{compact_addr, parse_compact_addr};
+
+///Well known nodes to bootstrap from when we know of no other nodes
+pub const DEFAULT_ROUTERS: [&str; 3] = ["router.bittorrent.com:6881",
+                                        "router.utorrent.com:6881",
+                                        "dht.transmissionbt.com:6881"];
+
+///How many nodes a bucket holds, and how many nodes lookups return
+const K: usize = 8;
+///How many queries a lookup has in flight at once
+const ALPHA: usize = 3;
+///The most rounds of queries a lookup makes before giving up on getting closer
+const MAX_LOOKUP_ROUNDS: usize = 10;
+///How long to wait for the replies to one round of a lookup
+const QUERY_TIMEOUT_MILLIS: u64 = 2000;
+///Nodes not heard from in this long are questionable and may be replaced
+const NODE_GOOD_SECS: u64 = 15 * 60;
+///How often the secret that tokens are made from changes
+const TOKEN_ROTATE_SECS: u64 = 5 * 60;
+///The most peers stored for any one info hash from announces
+const MAX_STORED_PEERS: usize = 100;
+///The most info hashes peers are stored for from announces
+const MAX_STORED_TORRENTS: usize = 1000;
+///How long an announced peer is kept without announcing again
+const PEER_EXPIRY_SECS: u64 = 30 * 60;
+///The most peers we tell a querying node about at once
+const MAX_RETURNED_PEERS: usize = 50;
+
+pub type NodeId = [u8; 20];
+
+///The closest nodes that answered a lookup with their tokens, and the peers they gave
+type LookupResult = (Vec<(Node, Option<Vec<u8>>)>, Vec<SocketAddrV4>);
+
+///The address a query went to, and the lookup waiting for the reply
+type PendingQuery = (SocketAddrV4, mpsc::Sender<LookupReply>);
+
+///A node we know about
+#[derive(Debug, Clone, Copy)]
+pub struct Node {
+    pub id: NodeId,
+    pub addr: SocketAddrV4,
+    last_seen: Option<Instant>,
+    failed_queries: u32
+}
+
+impl Node {
+    fn new(id: NodeId, addr: SocketAddrV4) -> Self {
+        Node { id, addr, last_seen: None, failed_queries: 0 }
+    }
+
+    ///nodes that haven't answered in a while or keep failing to answer can be replaced
+    fn is_questionable(&self) -> bool {
+        match self.last_seen {
+            Some(seen) => seen.elapsed() > Duration::from_secs(NODE_GOOD_SECS) || self.failed_queries > 1,
+            None => true
+        }
+    }
+}
+
+///The Kademlia routing table. Bucket i holds the nodes whose ids share exactly
+///i leading bits with our own id
+pub struct RoutingTable {
+    own_id: NodeId,
+    buckets: Vec<Vec<Node>>
+}
+
+impl RoutingTable {
+    ///Creates an empty routing table around our own id
+    pub fn new(own_id: NodeId) -> Self {
+        RoutingTable { own_id, buckets: vec![Vec::new(); 160] }
+    }
+
+    fn bucket_index(&self, id: &NodeId) -> Option<usize> {
+        let prefix = common_prefix_len(&self.own_id, id);
+        if prefix == 160 {
+            None
+        } else {
+            Some(prefix)
+        }
+    }
+
+    ///Adds or refreshes a node. If the node's bucket is full, the node replaces a
+    ///questionable one or is dropped
+    pub fn insert(&mut self, node: Node) {
+        let idx = match self.bucket_index(&node.id) {
+            Some(idx) => idx,
+            None => return //that's us
+        };
+        let bucket = &mut self.buckets[idx];
+        if let Some(existing) = bucket.iter_mut().find(|existing| existing.id == node.id) {
+            existing.addr = node.addr;
+            if node.last_seen.is_some() {
+                existing.last_seen = node.last_seen;
+                existing.failed_queries = 0;
+            }
+            return
+        }
+        if bucket.len() < K {
+            bucket.push(node);
+        } else if let Some(pos) = bucket.iter().position(|existing| existing.is_questionable()) {
+            bucket[pos] = node;
+        }
+    }
+
+    ///Records that a node failed to answer a query
+    pub fn mark_failed(&mut self, id: &NodeId) {
+        if let Some(idx) = self.bucket_index(id) {
+            if let Some(node) = self.buckets[idx].iter_mut().find(|node| node.id == *id) {
+                node.failed_queries += 1;
+            }
+        }
+    }
+
+    ///Gets the count nodes closest to the target
+    pub fn closest(&self, target: &NodeId, count: usize) -> Vec<Node> {
+        let mut nodes: Vec<Node> = self.buckets.iter().flat_map(|bucket| bucket.iter().cloned()).collect();
+        nodes.sort_by_key(|node| distance(&node.id, target));
+        nodes.truncate(count);
+        nodes
+    }
+
+    ///How many nodes the table holds
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(|bucket| bucket.len()).sum()
+    }
+
+    ///Saves our id and every node to a bencoded file so the next run can skip bootstrapping
+    pub fn save(&self, path: &str) -> BoostResult<()> {
+        let mut nodes = Vec::new();
+        for node in self.buckets.iter().flat_map(|bucket| bucket.iter()) {
+            nodes.extend_from_slice(&compact_node(node));
+        }
+        let dict = BencodeValue::Dict(vec![
+            (&b"id"[..], BencodeValue::Str(&self.own_id)),
+            (b"nodes", BencodeValue::Str(nodes.as_slice()))
+        ]);
+        let mut file = File::create(path).map_err(|_| BoostError::FileOpenErr(String::from(path)))?;
+        file.write_all(dict.bencode().as_slice()).map_err(|_| BoostError::FileWriteErr(String::from(path)))
+    }
+
+    ///Loads a routing table saved with save
+    pub fn load(path: &str) -> BoostResult<Self> {
+        let mut file = File::open(path).map_err(|_| BoostError::FileOpenErr(String::from(path)))?;
+        let mut buf = Vec::new();
+        file.read_to_end(&mut buf).map_err(|_| BoostError::FileReadErr(String::from(path)))?;
+        let dict = BencodeValue::bdecode(buf.as_slice())?;
+        let own_id = match dict.dict_get("id") {
+            Some(&BencodeValue::Str(id)) if id.len() == 20 => to_node_id(id),
+            _ => return Err(BoostError::BencodeValueErr(String::from("DHT state has no node id")))
+        };
+        let mut table = RoutingTable::new(own_id);
+        if let Some(&BencodeValue::Str(nodes)) = dict.dict_get("nodes") {
+            for node in parse_compact_nodes(nodes) {
+                table.insert(node);
+            }
+        }
+        Ok(table)
+    }
+}
+
+///The queries a node can be sent
+#[derive(Debug)]
+pub enum Query {
+    Ping,
+    FindNode { target: NodeId },
+    GetPeers { info_hash: NodeId },
+    AnnouncePeer { info_hash: NodeId, port: u16, implied_port: bool, token: Vec<u8> }
+}
+
+///A KRPC message, sent as a single bencoded UDP packet
+#[derive(Debug)]
+pub enum KrpcMessage {
+    Query { tid: Vec<u8>, id: NodeId, query: Query },
+    Response { tid: Vec<u8>, id: NodeId, nodes: Vec<Node>, values: Vec<SocketAddrV4>, token: Option<Vec<u8>> },
+    Error { tid: Vec<u8>, code: i32, message: String }
+}
+
+impl KrpcMessage {
+    ///Encodes this message to a bencoded dictionary
+    pub fn encode(&self) -> Vec<u8> {
+        match *self {
+            KrpcMessage::Query { ref tid, ref id, ref query } => {
+                let (method, target_key, target) = match *query {
+                    Query::Ping => ("ping", None, None),
+                    Query::FindNode { ref target } => ("find_node", Some("target"), Some(target)),
+                    Query::GetPeers { ref info_hash } => ("get_peers", Some("info_hash"), Some(info_hash)),
+                    Query::AnnouncePeer { ref info_hash, .. } => ("announce_peer", Some("info_hash"), Some(info_hash))
+                };
+                //arguments dict, keys in sorted order
+                let mut args = vec![(&b"id"[..], BencodeValue::Str(id))];
+                if let Query::AnnouncePeer { implied_port, .. } = *query {
+                    args.push((b"implied_port", BencodeValue::Integer(if implied_port { 1 } else { 0 })));
+                }
+                if let (Some(key), Some(target)) = (target_key, target) {
+                    args.push((key.as_bytes(), BencodeValue::Str(target)));
+                }
+                if let Query::AnnouncePeer { port, ref token, .. } = *query {
+                    args.push((b"port", BencodeValue::Integer(port as i64)));
+                    args.push((b"token", BencodeValue::Str(token.as_slice())));
+                }
+                BencodeValue::Dict(vec![
+                    (&b"a"[..], BencodeValue::Dict(args)),
+                    (b"q", BencodeValue::Str(method.as_bytes())),
+                    (b"t", BencodeValue::Str(tid.as_slice())),
+                    (b"y", BencodeValue::Str(b"q"))
+                ]).bencode()
+            },
+            KrpcMessage::Response { ref tid, ref id, ref nodes, ref values, ref token } => {
+                let compact_nodes: Vec<u8> = nodes.iter().flat_map(|node| compact_node(node).to_vec()).collect();
+                let compact_values: Vec<[u8; 6]> = values.iter().map(|addr| compact_addr(*addr)).collect();
+                let mut r = vec![(&b"id"[..], BencodeValue::Str(id))];
+                if !nodes.is_empty() {
+                    r.push((b"nodes", BencodeValue::Str(compact_nodes.as_slice())));
+                }
+                if let Some(ref token) = *token {
+                    r.push((b"token", BencodeValue::Str(token.as_slice())));
+                }
+                if !values.is_empty() {
+                    r.push((b"values", BencodeValue::List(compact_values.iter().map(|value| BencodeValue::Str(value)).collect())));
+                }
+                BencodeValue::Dict(vec![
+                    (&b"r"[..], BencodeValue::Dict(r)),
+                    (b"t", BencodeValue::Str(tid.as_slice())),
+                    (b"y", BencodeValue::Str(b"r"))
+                ]).bencode()
+            },
+            KrpcMessage::Error { ref tid, code, ref message } => {
+                BencodeValue::Dict(vec![
+                    (&b"e"[..], BencodeValue::List(vec![BencodeValue::Integer(code as i64), BencodeValue::Str(message.as_bytes())])),
+                    (b"t", BencodeValue::Str(tid.as_slice())),
+                    (b"y", BencodeValue::Str(b"e"))
+                ]).bencode()
+            }
+        }
+    }
+
+    ///Decodes a message from a received packet
+    pub fn decode(packet: &[u8]) -> BoostResult<Self> {
+        let dict = BencodeValue::bdecode(packet)?;
+        let tid = match dict.dict_get("t") {
+            Some(&BencodeValue::Str(tid)) => tid.to_vec(),
+            _ => return Err(BoostError::DHTProtocolErr(String::from("Message has no transaction id")))
+        };
+        match dict.dict_get("y") {
+            Some(&BencodeValue::Str(b"q")) => {
+                let args = dict.dict_get("a").ok_or_else(|| BoostError::DHTProtocolErr(String::from("Query has no arguments")))?;
+                let id = get_id(args, "id")?;
+                let query = match dict.dict_get("q") {
+                    Some(&BencodeValue::Str(b"ping")) => Query::Ping,
+                    Some(&BencodeValue::Str(b"find_node")) => Query::FindNode { target: get_id(args, "target")? },
+                    Some(&BencodeValue::Str(b"get_peers")) => Query::GetPeers { info_hash: get_id(args, "info_hash")? },
+                    Some(&BencodeValue::Str(b"announce_peer")) => {
+                        let info_hash = get_id(args, "info_hash")?;
+                        let implied_port = match args.dict_get("implied_port") {
+                            Some(&BencodeValue::Integer(implied)) => implied != 0,
+                            _ => false
+                        };
+                        let port = match args.dict_get("port") {
+                            Some(&BencodeValue::Integer(port)) if port > 0 && port <= 65535 => port as u16,
+                            _ if implied_port => 0,
+                            _ => return Err(BoostError::DHTProtocolErr(String::from("Announce has no port")))
+                        };
+                        let token = match args.dict_get("token") {
+                            Some(&BencodeValue::Str(token)) => token.to_vec(),
+                            _ => return Err(BoostError::DHTProtocolErr(String::from("Announce has no token")))
+                        };
+                        Query::AnnouncePeer { info_hash, port, implied_port, token }
+                    },
+                    _ => return Err(BoostError::DHTProtocolErr(String::from("Unknown query method")))
+                };
+                Ok(KrpcMessage::Query { tid, id, query })
+            },
+            Some(&BencodeValue::Str(b"r")) => {
+                let r = dict.dict_get("r").ok_or_else(|| BoostError::DHTProtocolErr(String::from("Response has no values")))?;
+                let id = get_id(r, "id")?;
+                let nodes = match r.dict_get("nodes") {
+                    Some(&BencodeValue::Str(nodes)) => parse_compact_nodes(nodes),
+                    _ => Vec::new()
+                };
+                let mut values = Vec::new();
+                if let Some(BencodeValue::List(list)) = r.dict_get("values") {
+                    for value in list {
+                        if let BencodeValue::Str(value) = *value {
+                            if value.len() == 6 {
+                                values.push(parse_compact_addr(value));
+                            }
+                        }
+                    }
+                }
+                let token = match r.dict_get("token") {
+                    Some(&BencodeValue::Str(token)) => Some(token.to_vec()),
+                    _ => None
+                };
+                Ok(KrpcMessage::Response { tid, id, nodes, values, token })
+            },
+            Some(&BencodeValue::Str(b"e")) => {
+                let (code, message) = match dict.dict_get("e") {
+                    Some(BencodeValue::List(e)) => match (e.first(), e.get(1)) {
+                        (Some(&BencodeValue::Integer(code)), Some(&BencodeValue::Str(message))) =>
+                            (code, String::from_utf8_lossy(message).into_owned()),
+                        _ => (0, String::new())
+                    },
+                    _ => (0, String::new())
+                };
+                Ok(KrpcMessage::Error { tid, code: code as i32, message })
+            },
+            _ => Err(BoostError::DHTProtocolErr(String::from("Unknown message type")))
+        }
+    }
+
+    fn tid(&self) -> &[u8] {
+        match *self {
+            KrpcMessage::Query { ref tid, .. } | KrpcMessage::Response { ref tid, .. } | KrpcMessage::Error { ref tid, .. } => tid
+        }
+    }
+}
+
+///What a node we queried during a lookup told us
+struct LookupReply {
+    from: SocketAddrV4,
+    message: KrpcMessage
+}
+
+///A DHT node. Answers queries from other nodes on its own thread, and can look up
+///peers for info hashes from any thread
+pub struct Dht {
+    socket: UdpSocket,
+    own_id: NodeId,
+    table: Mutex<RoutingTable>,
+    ///peers other nodes announced to us, oldest first, with when they announced
+    peers: Mutex<HashMap<NodeId, Vec<(SocketAddrV4, Instant)>>>,
+    ///the current and previous token secrets, and when the current one was made
+    secrets: Mutex<([u8; 20], [u8; 20], Instant)>,
+    ///lookups waiting on replies, by transaction id, with the address the query went to
+    pending: Mutex<HashMap<Vec<u8>, PendingQuery>>,
+    next_tid: AtomicUsize,
+    shut_down: AtomicBool
+}
+
+impl Dht {
+    ///Binds the DHT socket and starts answering queries. Uses the routing table
+    ///saved at state_path if there is one, otherwise starts with a new random id
+    pub fn start(bind_addr: SocketAddrV4, state_path: Option<&str>) -> BoostResult<Arc<Self>> {
+        let socket = UdpSocket::bind(bind_addr).map_err(|_| BoostError::DHTSocketErr)?;
+        //time out reads so the receive thread can notice a shut down
+        socket.set_read_timeout(Some(Duration::from_secs(1))).map_err(|_| BoostError::DHTSocketErr)?;
+        let table = match state_path.map(RoutingTable::load) {
+            Some(Ok(table)) => table,
+            _ => RoutingTable::new(rand::random::<NodeId>())
+        };
+        let dht = Arc::new(Dht {
+            socket,
+            own_id: table.own_id,
+            table: Mutex::new(table),
+            peers: Mutex::new(HashMap::new()),
+            secrets: Mutex::new((rand::random(), rand::random(), Instant::now())),
+            pending: Mutex::new(HashMap::new()),
+            next_tid: AtomicUsize::new(rand::random::<u16>() as usize),
+            shut_down: AtomicBool::new(false)
+        });
+        let recv_dht = dht.clone();
+        thread::spawn(move || recv_dht.recv_loop());
+        Ok(dht)
+    }
+
+    ///How many nodes are in the routing table
+    pub fn num_nodes(&self) -> usize {
+        self.table.lock().expect("The DHT routing table lock was poisoned").len()
+    }
+
+    ///Stops the receive thread
+    pub fn shut_down(&self) {
+        self.shut_down.store(true, Ordering::Relaxed);
+    }
+
+    ///Saves the routing table so the next run can skip bootstrapping
+    pub fn save(&self, path: &str) -> BoostResult<()> {
+        self.table.lock().expect("The DHT routing table lock was poisoned").save(path)
+    }
+
+    ///Fills the routing table by looking up our own id through the given nodes,
+    ///usually the nodes from a metainfo file or well known routers
+    pub fn bootstrap<A: ToSocketAddrs>(&self, routers: &[A]) {
+        let addrs: Vec<SocketAddrV4> = routers.iter()
+            .filter_map(|router| router.to_socket_addrs().ok())
+            .flat_map(|addrs| addrs.filter_map(|addr| if let SocketAddr::V4(addr) = addr { Some(addr) } else { None }))
+            .collect();
+        let own_id = self.own_id;
+        self.lookup(&own_id, false, &addrs);
+    }
+
+    ///Finds peers for the info hash, and tells the closest nodes that we are a
+    ///peer too, listening on the given port
+    pub fn announce(&self, info_hash: &NodeId, port: u16) -> Vec<SocketAddrV4> {
+        let (closest, peers) = self.lookup(info_hash, true, &[]);
+        for (node, token) in closest {
+            if let Some(token) = token {
+                let query = Query::AnnouncePeer { info_hash: *info_hash, port, implied_port: false, token };
+                let _ = self.send_query(node.addr, query, None);
+            }
+        }
+        peers
+    }
+
+    ///An iterative Kademlia lookup. Queries the closest known nodes to the target,
+    ///then the closer nodes they tell us about, until no closer nodes turn up.
+    ///Returns the closest nodes that answered, with their tokens, and any peers found
+    fn lookup(&self, target: &NodeId, get_peers: bool, extra: &[SocketAddrV4]) -> LookupResult {
+        let mut candidates = self.table.lock().expect("The DHT routing table lock was poisoned").closest(target, K);
+        let mut queried: Vec<SocketAddrV4> = Vec::new();
+        let mut responded: Vec<(Node, Option<Vec<u8>>)> = Vec::new();
+        let mut peers: Vec<SocketAddrV4> = Vec::new();
+        //nodes we only know the address of get queried first
+        let mut unknown: Vec<SocketAddrV4> = extra.to_vec();
+
+        for _ in 0..MAX_LOOKUP_ROUNDS {
+            candidates.sort_by_key(|node| distance(&node.id, target));
+            let mut round: Vec<SocketAddrV4> = ::std::mem::take(&mut unknown);
+            round.extend(candidates.iter()
+                         .take(K)
+                         .filter(|node| !queried.contains(&node.addr))
+                         .take(ALPHA)
+                         .map(|node| node.addr));
+            if round.is_empty() {
+                break
+            }
+
+            let (reply_sender, reply_receiver) = mpsc::channel();
+            let mut tids = Vec::new();
+            for addr in &round {
+                queried.push(*addr);
+                let query = if get_peers { Query::GetPeers { info_hash: *target } } else { Query::FindNode { target: *target } };
+                if let Ok(tid) = self.send_query(*addr, query, Some(reply_sender.clone())) {
+                    tids.push((tid, *addr));
+                }
+            }
+
+            //collect replies until everyone answered or the round times out
+            let deadline = Instant::now() + Duration::from_millis(QUERY_TIMEOUT_MILLIS);
+            let mut answered = Vec::new();
+            while answered.len() < tids.len() {
+                let now = Instant::now();
+                if now >= deadline {
+                    break
+                }
+                match reply_receiver.recv_timeout(deadline - now) {
+                    Ok(LookupReply { from, message: KrpcMessage::Response { id, nodes, values, token, .. } }) => {
+                        answered.push(from);
+                        responded.push((Node::new(id, from), token));
+                        for node in nodes {
+                            if !candidates.iter().any(|candidate| candidate.id == node.id) {
+                                candidates.push(node);
+                            }
+                        }
+                        for value in values {
+                            if !peers.contains(&value) {
+                                peers.push(value);
+                            }
+                        }
+                    },
+                    Ok(LookupReply { from, .. }) => answered.push(from),
+                    Err(_) => break
+                }
+            }
+
+            //forget the transactions and who didn't answer
+            let mut pending = self.pending.lock().expect("The DHT pending lock was poisoned");
+            let mut table = self.table.lock().expect("The DHT routing table lock was poisoned");
+            for (tid, addr) in tids {
+                pending.remove(&tid);
+                if !answered.contains(&addr) {
+                    if let Some(node) = candidates.iter().find(|node| node.addr == addr) {
+                        table.mark_failed(&node.id);
+                    }
+                    candidates.retain(|node| node.addr != addr);
+                }
+            }
+        }
+
+        responded.sort_by_key(|(node, _)| distance(&node.id, target));
+        responded.truncate(K);
+        (responded, peers)
+    }
+
+    ///Sends a query, registering the reply channel under its transaction id if given
+    fn send_query(&self, addr: SocketAddrV4, query: Query, reply_to: Option<mpsc::Sender<LookupReply>>) -> BoostResult<Vec<u8>> {
+        let tid_num = self.next_tid.fetch_add(1, Ordering::Relaxed) as u16;
+        let mut tid = vec![0u8; 2];
+        NetworkEndian::write_u16(&mut tid, tid_num);
+        if let Some(reply_to) = reply_to {
+            self.pending.lock().expect("The DHT pending lock was poisoned").insert(tid.clone(), (addr, reply_to));
+        }
+        let message = KrpcMessage::Query { tid: tid.clone(), id: self.own_id, query };
+        self.socket.send_to(message.encode().as_slice(), addr).map_err(|_| BoostError::DHTSocketErr)?;
+        Ok(tid)
+    }
+
+    ///Receives packets until shut down, answering queries and handing replies to
+    ///the lookups waiting for them
+    fn recv_loop(&self) {
+        let mut buf = [0u8; 2048];
+        while !self.shut_down.load(Ordering::Relaxed) {
+            let (len, from) = match self.socket.recv_from(&mut buf) {
+                Ok((len, SocketAddr::V4(from))) => (len, from),
+                _ => continue
+            };
+            let message = match KrpcMessage::decode(&buf[0..len]) {
+                Ok(message) => message,
+                Err(_) => continue
+            };
+            match message {
+                KrpcMessage::Query { tid, id, query } => {
+                    //anyone who queries us is a live node
+                    let mut node = Node::new(id, from);
+                    node.last_seen = Some(Instant::now());
+                    self.table.lock().expect("The DHT routing table lock was poisoned").insert(node);
+                    let reply = self.answer(tid, from, query);
+                    let _ = self.socket.send_to(reply.encode().as_slice(), from);
+                },
+                message => {
+                    //only answers to queries we sent, from the node we sent them to, count.
+                    //Anything else could be someone trying to fill our table with junk
+                    let sender = {
+                        let mut pending = self.pending.lock().expect("The DHT pending lock was poisoned");
+                        match pending.get(message.tid()) {
+                            Some(&(addr, _)) if addr == from => pending.remove(message.tid()).map(|(_, sender)| sender),
+                            _ => None
+                        }
+                    };
+                    let sender = match sender {
+                        Some(sender) => sender,
+                        None => continue
+                    };
+                    if let KrpcMessage::Response { id, ref nodes, .. } = message {
+                        let mut table = self.table.lock().expect("The DHT routing table lock was poisoned");
+                        let mut node = Node::new(id, from);
+                        node.last_seen = Some(Instant::now());
+                        table.insert(node);
+                        //nodes we are told about are worth remembering too
+                        for node in nodes {
+                            table.insert(*node);
+                        }
+                    }
+                    let _ = sender.send(LookupReply { from, message });
+                }
+            }
+        }
+    }
+
+    ///Works out the reply to a query from another node
+    fn answer(&self, tid: Vec<u8>, from: SocketAddrV4, query: Query) -> KrpcMessage {
+        let own_id = self.own_id;
+        match query {
+            Query::Ping => KrpcMessage::Response { tid, id: own_id, nodes: Vec::new(), values: Vec::new(), token: None },
+            Query::FindNode { target } => {
+                let nodes = self.table.lock().expect("The DHT routing table lock was poisoned").closest(&target, K);
+                KrpcMessage::Response { tid, id: own_id, nodes, values: Vec::new(), token: None }
+            },
+            Query::GetPeers { info_hash } => {
+                let token = Some(self.make_token(from.ip(), false));
+                let expiry = Duration::from_secs(PEER_EXPIRY_SECS);
+                let values: Vec<SocketAddrV4> = self.peers.lock().expect("The DHT peers lock was poisoned")
+                    .get(&info_hash)
+                    .map(|peers| peers.iter()
+                         .rev()
+                         .filter(|&&(_, announced)| announced.elapsed() < expiry)
+                         .take(MAX_RETURNED_PEERS)
+                         .map(|&(peer, _)| peer)
+                         .collect())
+                    .unwrap_or_default();
+                //without peers, point the querier closer to the info hash instead
+                let nodes = if values.is_empty() {
+                    self.table.lock().expect("The DHT routing table lock was poisoned").closest(&info_hash, K)
+                } else {
+                    Vec::new()
+                };
+                KrpcMessage::Response { tid, id: own_id, nodes, values, token }
+            },
+            Query::AnnouncePeer { info_hash, port, implied_port, token } => {
+                if token != self.make_token(from.ip(), false) && token != self.make_token(from.ip(), true) {
+                    return KrpcMessage::Error { tid, code: 203, message: String::from("Bad token") }
+                }
+                let port = if implied_port { from.port() } else { port };
+                let peer = SocketAddrV4::new(*from.ip(), port);
+                let mut peers = self.peers.lock().expect("The DHT peers lock was poisoned");
+                expire_peers(&mut peers);
+                if !peers.contains_key(&info_hash) && peers.len() >= MAX_STORED_TORRENTS {
+                    return KrpcMessage::Error { tid, code: 202, message: String::from("Storing too many torrents") }
+                }
+                //announcing again moves the peer to the back, last to be pushed out
+                let stored = peers.entry(info_hash).or_default();
+                stored.retain(|&(addr, _)| addr != peer);
+                if stored.len() >= MAX_STORED_PEERS {
+                    stored.remove(0);
+                }
+                stored.push((peer, Instant::now()));
+                KrpcMessage::Response { tid, id: own_id, nodes: Vec::new(), values: Vec::new(), token: None }
+            }
+        }
+    }
+
+    ///The token for an ip is a hash of the ip and a secret that changes every few
+    ///minutes. Tokens from the previous secret are still accepted
+    fn make_token(&self, ip: &Ipv4Addr, previous: bool) -> Vec<u8> {
+        let mut secrets = self.secrets.lock().expect("The DHT secrets lock was poisoned");
+        if secrets.2.elapsed() > Duration::from_secs(TOKEN_ROTATE_SECS) {
+            secrets.1 = secrets.0;
+            secrets.0 = rand::random();
+            secrets.2 = Instant::now();
+        }
+        let mut hasher = Sha1::new();
+        hasher.update(&ip.octets());
+        hasher.update(if previous { &secrets.1 } else { &secrets.0 });
+        hasher.digest().bytes()[0..8].to_vec()
+    }
+}
+
+///Forgets the announced peers that haven't announced again in a while, and
+///the info hashes left without any
+fn expire_peers(peers: &mut HashMap<NodeId, Vec<(SocketAddrV4, Instant)>>) {
+    let expiry = Duration::from_secs(PEER_EXPIRY_SECS);
+    for stored in peers.values_mut() {
+        stored.retain(|&(_, announced)| announced.elapsed() < expiry);
+    }
+    peers.retain(|_, stored| !stored.is_empty());
+}
+
+///The XOR distance between two ids, compared as big endian numbers
+fn distance(a: &NodeId, b: &NodeId) -> NodeId {
+    let mut dist = [0u8; 20];
+    for idx in 0..20 {
+        dist[idx] = a[idx] ^ b[idx];
+    }
+    dist
+}
+
+///How many leading bits two ids have in common
+fn common_prefix_len(a: &NodeId, b: &NodeId) -> usize {
+    let dist = distance(a, b);
+    for (idx, byte) in dist.iter().enumerate() {
+        if *byte != 0 {
+            return idx * 8 + byte.leading_zeros() as usize
+        }
+    }
+    160
+}
+
+fn to_node_id(bytes: &[u8]) -> NodeId {
+    let mut id = [0u8; 20];
+    id.copy_from_slice(bytes);
+    id
+}
+
+///Gets a 20 byte id out of a dictionary
+fn get_id(dict: &BencodeValue, key: &str) -> BoostResult<NodeId> {
+    match dict.dict_get(key) {
+        Some(&BencodeValue::Str(id)) if id.len() == 20 => Ok(to_node_id(id)),
+        _ => Err(BoostError::DHTProtocolErr(format!("Missing or malformed {}", key)))
+    }
+}
+
+///Encodes a node in the 26 byte compact form, id then address
+fn compact_node(node: &Node) -> [u8; 26] {
+    let mut buf = [0u8; 26];
+    buf[0..20].copy_from_slice(&node.id);
+    buf[20..26].copy_from_slice(&compact_addr(node.addr));
+    buf
+}
+
+///Decodes a string of 26 byte compact nodes. Any trailing partial node is ignored
+fn parse_compact_nodes(buf: &[u8]) -> Vec<Node> {
+    buf.chunks(26)
+        .filter(|chunk| chunk.len() == 26)
+        .map(|chunk| Node::new(to_node_id(&chunk[0..20]), parse_compact_addr(&chunk[20..26])))
+        .filter(|node| node.addr.port() != 0)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn start() -> Arc<Dht> {
+        Dht::start(SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 0), None).ok().unwrap()
+    }
+
+    fn addr(dht: &Dht) -> SocketAddrV4 {
+        match dht.socket.local_addr() {
+            Ok(SocketAddr::V4(addr)) => addr,
+            _ => panic!("DHT socket has no address")
+        }
+    }
+
+    fn knows(dht: &Dht, other: &Dht) -> bool {
+        dht.table.lock().unwrap().closest(&other.own_id, K).iter().any(|node| node.id == other.own_id)
+    }
+
+    #[test]
+    fn loopback_nodes_find_each_other_and_peers() {
+        let nodes: Vec<Arc<Dht>> = (0..4).map(|_| start()).collect();
+        let router = addr(&nodes[0]);
+        for node in &nodes[1..] {
+            node.bootstrap(&[router]);
+        }
+        //find_node: the later nodes heard about the earlier ones through the router
+        assert!(knows(&nodes[3], &nodes[0]));
+        assert!(knows(&nodes[3], &nodes[1]) && knows(&nodes[3], &nodes[2]));
+        assert!(knows(&nodes[0], &nodes[1]) && knows(&nodes[0], &nodes[3]));
+
+        //announce_peer: the get_peers lookup hands out tokens the announce uses
+        let info_hash = [0x5a; 20];
+        assert!(nodes[1].announce(&info_hash, 7000).is_empty());
+        //the announces aren't waited on, give them a moment to land
+        let announced = SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 7000);
+        for _ in 0..50 {
+            if nodes.iter().filter(|node| node.peers.lock().unwrap().contains_key(&info_hash)).count() >= 2 {
+                break
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+
+        //get_peers: another node finds the announced peer
+        let (closest, peers) = nodes[2].lookup(&info_hash, true, &[]);
+        assert_eq!(peers, vec![announced]);
+        assert!(closest.iter().all(|(_, token)| token.is_some()));
+        for node in &nodes {
+            node.shut_down();
+        }
+    }
+
+    #[test]
+    fn ignores_replies_to_queries_never_sent() {
+        let dht = start();
+        let spoofer = UdpSocket::bind("127.0.0.1:0").unwrap();
+        spoofer.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        let junk = Node::new([0x11; 20], SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 6881));
+        let reply = KrpcMessage::Response { tid: vec![0xff, 0xff], id: [0x22; 20], nodes: vec![junk], values: Vec::new(), token: None };
+        spoofer.send_to(&reply.encode(), addr(&dht)).unwrap();
+        //a ping afterwards is answered once the reply was dealt with
+        let ping = KrpcMessage::Query { tid: vec![1], id: [0x33; 20], query: Query::Ping };
+        spoofer.send_to(&ping.encode(), addr(&dht)).unwrap();
+        let mut buf = [0u8; 1024];
+        let (len, _) = spoofer.recv_from(&mut buf).unwrap();
+        match KrpcMessage::decode(&buf[0..len]) {
+            Ok(KrpcMessage::Response { id, .. }) => assert_eq!(id, dht.own_id),
+            _ => panic!("ping was not answered")
+        }
+        //only the node that queried us made it into the table
+        assert_eq!(dht.num_nodes(), 1);
+        dht.shut_down();
+    }
+
+    #[test]
+    fn announce_store_is_capped_and_expires() {
+        let dht = start();
+        let from = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 6881);
+        let announce = |info_hash: NodeId, port: u16| {
+            let token = dht.make_token(from.ip(), false);
+            dht.answer(vec![0], from, Query::AnnouncePeer { info_hash, port, implied_port: false, token })
+        };
+
+        for port in 1..(MAX_STORED_PEERS as u16 + 10) {
+            announce([1; 20], port);
+        }
+        {
+            let peers = dht.peers.lock().unwrap();
+            let stored = &peers[&[1; 20]];
+            assert_eq!(stored.len(), MAX_STORED_PEERS);
+            //the oldest got pushed out
+            assert_eq!(stored[0].0.port(), 10);
+        }
+
+        //peers that didn't announce again are forgotten
+        let long_ago = Instant::now().checked_sub(Duration::from_secs(PEER_EXPIRY_SECS + 1)).unwrap();
+        for stored in dht.peers.lock().unwrap().values_mut() {
+            for peer in stored.iter_mut() {
+                peer.1 = long_ago;
+            }
+        }
+        match dht.answer(vec![0], from, Query::GetPeers { info_hash: [1; 20] }) {
+            KrpcMessage::Response { values, .. } => assert!(values.is_empty()),
+            _ => panic!("get_peers was not answered")
+        }
+        announce([2; 20], 1);
+        assert!(!dht.peers.lock().unwrap().contains_key(&[1; 20]));
+
+        //and only so many torrents are stored
+        for idx in 0..MAX_STORED_TORRENTS {
+            let mut info_hash = [0; 20];
+            NetworkEndian::write_u32(&mut info_hash[0..4], idx as u32 + 3);
+            announce(info_hash, 1);
+        }
+        match announce([0xff; 20], 1) {
+            KrpcMessage::Error { code, .. } => assert_eq!(code, 202),
+            _ => panic!("announce was stored past the limit")
+        }
+        assert_eq!(dht.peers.lock().unwrap().len(), MAX_STORED_TORRENTS);
+        dht.shut_down();
+    }
+}
@@ -23,6 +23,11 @@ pub enum BoostError {
     BitTorrentProtocolErr(String),
     BitTorrentTCPSendErr,
     BitTorrentTCPRecvErr,
+    DHTSocketErr,
+    DHTProtocolErr(String),
+    LSDSocketErr,
+    MSEProtocolErr(String),
+    EventLoopErr,
     UnexpectedMessageType(BitTorrentMessage)
 }
 
@@ -49,6 +54,11 @@ impl fmt::Display for BoostError {
             BoostError::BitTorrentProtocolErr(ref msg) => write!(f, "Error communicating with a peer: {}", msg),
             BoostError::BitTorrentTCPSendErr => write!(f, "Error sending data to peer over TCP"),
             BoostError::BitTorrentTCPRecvErr => write!(f, "Error recieving data from peer over TCP"),
+            BoostError::DHTSocketErr => write!(f, "Error using the DHT's UDP socket"),
+            BoostError::DHTProtocolErr(ref msg) => write!(f, "Error communicating with a DHT node: {}", msg),
+            BoostError::LSDSocketErr => write!(f, "Error using the local service discovery multicast socket"),
+            BoostError::MSEProtocolErr(ref msg) => write!(f, "Error setting up an encrypted connection: {}", msg),
+            BoostError::EventLoopErr => write!(f, "Error setting up the peer event loop"),
             BoostError::UnexpectedMessageType(ref msg) => write!(f, "Got an unexpected message type: {}", msg)
         }
     }
@@ -0,0 +1,210 @@
+use bencode::BencodeValue;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str;
+use std::sync::Arc;
+use error::{BoostError, BoostResult};
+
+///The extended message id that is reserved for the extended handshake
+pub const HANDSHAKE_ID: u8 = 0;
+
+///The byte and bit of the reserved handshake bytes that advertise
+///support for the extension protocol (bit 20 counting from the right)
+pub const RESERVED_BYTE: usize = 5;
+pub const RESERVED_BIT: u8 = 0x10;
+
+///An extension that can be plugged into the extension protocol.
+///Extensions are shared between all peers, so they take care of their own locking
+pub trait Extension: Send + Sync {
+    ///The name the extension is advertised under in the handshake's m dictionary,
+    ///for example ut_pex
+    fn name(&self) -> &'static str;
+
+    ///Called once a peer's extended handshake arrives, if the peer supports this extension
+    fn on_handshake(&self, _peer_id: &[u8; 20], _handshake: &ExtendedHandshake) {}
+
+    ///Called with the payload of every message a peer sends for this extension
+    fn on_message(&self, peer_id: &[u8; 20], payload: &[u8]) -> BoostResult<()>;
+
+    ///Called when a peer disconnects, so any state kept for it can be dropped
+    fn on_disconnect(&self, _peer_id: &[u8; 20]) {}
+}
+
+///Holds every extension this client supports. The local extended message id of
+///an extension is its position in the registry plus one, since 0 is the handshake
+pub struct ExtensionRegistry {
+    extensions: Vec<Arc<dyn Extension>>
+}
+
+impl ExtensionRegistry {
+    ///Creates a registry with no extensions
+    pub fn new() -> Self {
+        ExtensionRegistry { extensions: Vec::new() }
+    }
+
+    ///Adds an extension to the registry
+    pub fn register(&mut self, extension: Arc<dyn Extension>) {
+        self.extensions.push(extension)
+    }
+
+    ///Gets the extension that peers send messages to under the given local id
+    pub fn by_id(&self, id: u8) -> Option<&dyn Extension> {
+        if id == HANDSHAKE_ID {
+            None
+        } else {
+            self.extensions.get(id as usize - 1).map(|ext| &**ext)
+        }
+    }
+
+    ///Gets the extension with the given name
+    pub fn by_name(&self, name: &str) -> Option<&dyn Extension> {
+        self.extensions.iter().find(|ext| ext.name() == name).map(|ext| &**ext)
+    }
+
+    ///Tells every extension that a peer has gone away
+    pub fn peer_disconnected(&self, peer_id: &[u8; 20]) {
+        for ext in &self.extensions {
+            ext.on_disconnect(peer_id);
+        }
+    }
+
+    ///Builds the extended handshake advertising all registered extensions.
+    ///The caller fills in the rest of the fields
+    pub fn handshake(&self) -> ExtendedHandshake {
+        let mut handshake = ExtendedHandshake::default();
+        for (idx, ext) in self.extensions.iter().enumerate() {
+            handshake.extensions.insert(String::from(ext.name()), idx as u8 + 1);
+        }
+        handshake
+    }
+}
+
+///The extended handshake, sent as extended message 0 right after the
+///BitTorrent handshake to peers that support the extension protocol
+#[derive(Debug, Default, Clone)]
+pub struct ExtendedHandshake {
+    ///maps extension names to the id the sender wants to receive them under.
+    ///An id of 0 means the extension is disabled
+    pub extensions: HashMap<String, u8>,
+    ///client name and version
+    pub version: Option<String>,
+    ///the sender's listen port
+    pub listen_port: Option<u16>,
+    ///how many outstanding requests the sender allows
+    pub request_queue: Option<u32>,
+    ///the address the sender sees the receiver as
+    pub your_ip: Option<IpAddr>,
+    ///size of the info dictionary, for ut_metadata
+    pub metadata_size: Option<u32>
+}
+
+impl ExtendedHandshake {
+    ///Encodes the handshake to the bencoded dictionary sent on the wire
+    pub fn encode(&self) -> Vec<u8> {
+        //bencoded dictionaries must be sorted by key
+        let mut names: Vec<&String> = self.extensions.keys().collect();
+        names.sort();
+        let m = names.iter()
+            .map(|name| (name.as_bytes(), BencodeValue::Integer(self.extensions[*name] as i64)))
+            .collect();
+        let ip_bytes = match self.your_ip {
+            Some(IpAddr::V4(ip)) => ip.octets().to_vec(),
+            Some(IpAddr::V6(ip)) => ip.octets().to_vec(),
+            None => Vec::new()
+        };
+
+        let mut dict = vec![(&b"m"[..], BencodeValue::Dict(m))];
+        if let Some(size) = self.metadata_size {
+            dict.push((b"metadata_size", BencodeValue::Integer(size as i64)));
+        }
+        if let Some(port) = self.listen_port {
+            dict.push((b"p", BencodeValue::Integer(port as i64)));
+        }
+        if let Some(reqq) = self.request_queue {
+            dict.push((b"reqq", BencodeValue::Integer(reqq as i64)));
+        }
+        if let Some(ref version) = self.version {
+            dict.push((b"v", BencodeValue::Str(version.as_bytes())));
+        }
+        if self.your_ip.is_some() {
+            dict.push((b"yourip", BencodeValue::Str(ip_bytes.as_slice())));
+        }
+        BencodeValue::Dict(dict).bencode()
+    }
+
+    ///Decodes a handshake from the payload of extended message 0.
+    ///Unknown keys are ignored, as are known keys with values of the wrong type
+    pub fn decode(payload: &[u8]) -> BoostResult<Self> {
+        let dict = BencodeValue::bdecode(payload)?;
+        let mut handshake = ExtendedHandshake::default();
+
+        match dict.dict_get("m") {
+            Some(BencodeValue::Dict(m)) => {
+                for &(name, ref id) in m {
+                    if let (Ok(name), &BencodeValue::Integer(id)) = (str::from_utf8(name), id) {
+                        if (0..=255).contains(&id) {
+                            handshake.extensions.insert(String::from(name), id as u8);
+                        }
+                    }
+                }
+            },
+            _ => return Err(BoostError::BencodeValueErr(String::from("Extended handshake has no m dictionary")))
+        }
+        if let Some(&BencodeValue::Str(v)) = dict.dict_get("v") {
+            handshake.version = str::from_utf8(v).ok().map(String::from);
+        }
+        if let Some(&BencodeValue::Integer(p)) = dict.dict_get("p") {
+            if (1..=65535).contains(&p) {
+                handshake.listen_port = Some(p as u16);
+            }
+        }
+        if let Some(&BencodeValue::Integer(reqq)) = dict.dict_get("reqq") {
+            if reqq > 0 {
+                handshake.request_queue = Some(reqq as u32);
+            }
+        }
+        if let Some(&BencodeValue::Str(ip)) = dict.dict_get("yourip") {
+            handshake.your_ip = match ip.len() {
+                4 => Some(IpAddr::V4(Ipv4Addr::new(ip[0], ip[1], ip[2], ip[3]))),
+                16 => {
+                    let mut octets = [0u8; 16];
+                    octets.copy_from_slice(ip);
+                    Some(IpAddr::V6(Ipv6Addr::from(octets)))
+                },
+                _ => None
+            };
+        }
+        if let Some(&BencodeValue::Integer(size)) = dict.dict_get("metadata_size") {
+            if size > 0 {
+                handshake.metadata_size = Some(size as u32);
+            }
+        }
+        Ok(handshake)
+    }
+}
+
+///The ids a single peer wants to receive each extension's messages under,
+///learned from its extended handshake
+#[derive(Debug, Default)]
+pub struct ExtensionIds {
+    ids: HashMap<String, u8>
+}
+
+impl ExtensionIds {
+    ///Updates the mapping from the handshake. Later handshakes may
+    ///enable or disable extensions, so anything not mentioned is kept
+    pub fn update(&mut self, handshake: &ExtendedHandshake) {
+        for (name, &id) in &handshake.extensions {
+            if id == 0 {
+                self.ids.remove(name);
+            } else {
+                self.ids.insert(name.clone(), id);
+            }
+        }
+    }
+
+    ///Gets the id to send the named extension's messages with, if the peer supports it
+    pub fn get(&self, name: &str) -> Option<u8> {
+        self.ids.get(name).cloned()
+    }
+}
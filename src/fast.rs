@@ -0,0 +1,63 @@
+use byteorder::{NetworkEndian, ByteOrder};
+use sha1::Sha1;
+use std::net::Ipv4Addr;
+
+///The byte and bit of the reserved handshake bytes that advertise
+///support for the fast extension (bit 2 of the last byte)
+pub const RESERVED_BYTE: usize = 7;
+pub const RESERVED_BIT: u8 = 0x04;
+
+///How many pieces a peer may request from us while choked
+pub const ALLOWED_FAST_COUNT: usize = 10;
+
+///Generates the allowed fast set for a peer with the given ip, the pieces it may
+///request even while we are choking it. This is the canonical algorithm from the
+///fast extension spec, so any client can work out the same set for the same peer
+pub fn allowed_fast_set(ip: Ipv4Addr, info_hash: &[u8], num_pieces: usize, count: usize) -> Vec<u32> {
+    let mut allowed = Vec::new();
+    //can't allow more pieces than there are
+    let count = if count > num_pieces { num_pieces } else { count };
+    //only the /24 the peer is in matters
+    let mut x = Vec::new();
+    x.extend_from_slice(&ip.octets()[0..3]);
+    x.push(0);
+    x.extend_from_slice(info_hash);
+    while allowed.len() < count {
+        let mut hasher = Sha1::new();
+        hasher.update(x.as_slice());
+        x = hasher.digest().bytes().to_vec();
+        //each hash gives up to 5 piece indices
+        for chunk in x.chunks(4) {
+            if allowed.len() >= count {
+                break
+            }
+            let index = NetworkEndian::read_u32(chunk) % num_pieces as u32;
+            if !allowed.contains(&index) {
+                allowed.push(index);
+            }
+        }
+    }
+    allowed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_the_spec_vector() {
+        let ip = Ipv4Addr::new(80, 4, 4, 200);
+        let info_hash = [0xaa; 20];
+        assert_eq!(allowed_fast_set(ip, &info_hash, 1313, 7), vec![1059, 431, 808, 1217, 287, 376, 1188]);
+        assert_eq!(allowed_fast_set(ip, &info_hash, 1313, 9), vec![1059, 431, 808, 1217, 287, 376, 1188, 353, 508]);
+        //only the /24 counts
+        assert_eq!(allowed_fast_set(Ipv4Addr::new(80, 4, 4, 1), &info_hash, 1313, 7), allowed_fast_set(ip, &info_hash, 1313, 7));
+    }
+
+    #[test]
+    fn never_more_than_the_pieces() {
+        let mut allowed = allowed_fast_set(Ipv4Addr::new(80, 4, 4, 200), &[0xaa; 20], 4, ALLOWED_FAST_COUNT);
+        allowed.sort();
+        assert_eq!(allowed, vec![0, 1, 2, 3]);
+    }
+}
@@ -0,0 +1,145 @@
+use sha1::Sha1;
+use std::sync::{Arc, Mutex, mpsc};
+use std::thread;
+
+///Most worker threads a pool starts, however many cores there are
+const MAX_WORKERS: usize = 8;
+
+///A piece's data to check against the hash the metafile has for it
+pub struct HashJob {
+    pub index: u32,
+    pub data: Vec<u8>,
+    pub expected: [u8; 20]
+}
+
+///A checked piece, with its data handed back so it can be written out
+pub struct Hashed {
+    pub index: u32,
+    pub data: Vec<u8>,
+    pub correct: bool
+}
+
+///A few worker threads that check pieces against their hashes, so hashing a
+///large piece doesn't hold up the event loop or a recheck can use every core.
+///Results come back in the order the workers finish them, not the order they were
+///submitted. The workers stop once the pool is dropped
+pub struct HashPool {
+    jobs: mpsc::Sender<HashJob>,
+    results: mpsc::Receiver<Hashed>,
+    ///jobs submitted whose results haven't been taken yet
+    pending: usize
+}
+
+impl HashPool {
+    ///Starts a worker for each core, up to MAX_WORKERS
+    pub fn new() -> Self {
+        let workers = thread::available_parallelism().map(|count| count.get()).unwrap_or(1).min(MAX_WORKERS);
+        HashPool::with_workers(workers)
+    }
+
+    ///Starts the given number of workers, at least one
+    pub fn with_workers(workers: usize) -> Self {
+        let (jobs, job_receiver) = mpsc::channel::<HashJob>();
+        let (result_sender, results) = mpsc::channel();
+        //the workers take turns waiting on the one job queue
+        let job_receiver = Arc::new(Mutex::new(job_receiver));
+        for _ in 0..workers.max(1) {
+            let job_receiver = job_receiver.clone();
+            let result_sender = result_sender.clone();
+            thread::spawn(move || loop {
+                let job = match job_receiver.lock().expect("The hash job lock was poisoned").recv() {
+                    Ok(job) => job,
+                    Err(_) => return
+                };
+                let mut hasher = Sha1::new();
+                hasher.update(job.data.as_slice());
+                let correct = hasher.digest().bytes() == job.expected;
+                if result_sender.send(Hashed { index: job.index, data: job.data, correct }).is_err() {
+                    return
+                }
+            });
+        }
+        HashPool { jobs, results, pending: 0 }
+    }
+
+    ///Queues a piece to be checked
+    pub fn submit(&mut self, job: HashJob) {
+        if self.jobs.send(job).is_ok() {
+            self.pending += 1;
+        }
+    }
+
+    ///Takes a checked piece if one is ready, without waiting
+    pub fn try_recv(&mut self) -> Option<Hashed> {
+        let hashed = self.results.try_recv().ok()?;
+        self.pending -= 1;
+        Some(hashed)
+    }
+
+    ///Waits for the next checked piece. Returns None if nothing is being checked
+    pub fn recv(&mut self) -> Option<Hashed> {
+        if self.pending == 0 {
+            return None
+        }
+        let hashed = self.results.recv().ok()?;
+        self.pending -= 1;
+        Some(hashed)
+    }
+
+    ///how many pieces are queued or being checked
+    pub fn pending(&self) -> usize {
+        self.pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    fn sha1(data: &[u8]) -> [u8; 20] {
+        let mut hasher = Sha1::new();
+        hasher.update(data);
+        hasher.digest().bytes()
+    }
+
+    #[test]
+    fn checks_pieces_against_their_hashes() {
+        let mut pool = HashPool::with_workers(2);
+        assert!(pool.recv().is_none());
+        pool.submit(HashJob { index: 0, data: vec![1; 1000], expected: sha1(&[1; 1000]) });
+        pool.submit(HashJob { index: 1, data: vec![2; 1000], expected: sha1(&[3; 1000]) });
+        assert_eq!(pool.pending(), 2);
+        let mut results: Vec<(u32, bool, usize)> = (0..2)
+            .map(|_| pool.recv().map(|hashed| (hashed.index, hashed.correct, hashed.data.len())).unwrap())
+            .collect();
+        results.sort();
+        assert_eq!(results, vec![(0, true, 1000), (1, false, 1000)]);
+        assert_eq!(pool.pending(), 0);
+        assert!(pool.try_recv().is_none());
+    }
+
+    ///Not a real test: prints how fast pools of different sizes hash large
+    ///pieces. Run it with cargo test --release -- --ignored --nocapture hash_throughput
+    #[test]
+    #[ignore]
+    fn hash_throughput() {
+        const TOTAL_BYTES: usize = 256 * 1024 * 1024;
+        for &piece_len in &[4 * 1024 * 1024, 16 * 1024 * 1024] {
+            let data = vec![0xa5; piece_len];
+            let expected = sha1(&data);
+            for &workers in &[1, 2, 4, 8] {
+                let mut pool = HashPool::with_workers(workers);
+                let started = Instant::now();
+                for index in 0..TOTAL_BYTES / piece_len {
+                    pool.submit(HashJob { index: index as u32, data: data.clone(), expected });
+                }
+                while let Some(hashed) = pool.recv() {
+                    assert!(hashed.correct);
+                }
+                let secs = started.elapsed().as_secs_f64();
+                println!("{} MiB pieces, {} workers: {:.0} MiB/s", piece_len >> 20, workers, (TOTAL_BYTES >> 20) as f64 / secs);
+            }
+        }
+    }
+}
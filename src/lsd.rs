@@ -0,0 +1,195 @@
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use std::{str, thread};
+use rand::{self, Rng};
+use error::{BoostError, BoostResult};
+use tracker::PotentialPeer;
+use util::hex_encode;
+use net2::UdpBuilder;
+
+///The multicast group and port local service discovery uses
+pub const LSD_GROUP: Ipv4Addr = Ipv4Addr::new(239, 192, 152, 143);
+pub const LSD_PORT: u16 = 6771;
+
+///How often every torrent is announced on the local network
+const ANNOUNCE_INTERVAL_SECS: u64 = 5 * 60;
+
+///A torrent we look for local peers for
+struct LocalTorrent {
+    info_hash: [u8; 20],
+    potential_peers: Arc<RwLock<Vec<PotentialPeer>>>
+}
+
+///Local service discovery. Multicasts the info hashes of our torrents to the LAN
+///and adds peers that announce the same torrents to their potential peers
+pub struct LocalDiscovery {
+    socket: UdpSocket,
+    interface: Ipv4Addr,
+    listen_port: u16,
+    ///lets us recognize our own announcements when they loop back
+    cookie: String,
+    torrents: Mutex<Vec<LocalTorrent>>,
+    last_announce: Mutex<Option<Instant>>,
+    shut_down: AtomicBool
+}
+
+impl LocalDiscovery {
+    ///Joins the multicast group on the given interface and starts announcing and
+    ///listening. listen_port is the port peers should connect to us on. Other
+    ///clients on this machine may be listening for announcements too, so the
+    ///port is shared with them
+    pub fn start(interface: Ipv4Addr, listen_port: u16) -> BoostResult<Arc<Self>> {
+        let socket = UdpBuilder::new_v4()
+            .and_then(|builder| builder.reuse_address(true)?.bind(SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), LSD_PORT)))
+            .map_err(|_| BoostError::LSDSocketErr)?;
+        socket.join_multicast_v4(&LSD_GROUP, &interface).map_err(|_| BoostError::LSDSocketErr)?;
+        //time out reads so the thread can announce and notice a shut down
+        socket.set_read_timeout(Some(Duration::from_secs(1))).map_err(|_| BoostError::LSDSocketErr)?;
+        let cookie: String = rand::thread_rng().gen_ascii_chars().take(12).collect();
+        let lsd = Arc::new(LocalDiscovery {
+            socket,
+            interface,
+            listen_port,
+            cookie,
+            torrents: Mutex::new(Vec::new()),
+            last_announce: Mutex::new(None),
+            shut_down: AtomicBool::new(false)
+        });
+        let thread_lsd = lsd.clone();
+        thread::spawn(move || thread_lsd.run());
+        Ok(lsd)
+    }
+
+    ///Starts announcing a torrent, feeding the local peers found into potential_peers.
+    ///Private torrents must not be added
+    pub fn add_torrent(&self, info_hash: [u8; 20], potential_peers: Arc<RwLock<Vec<PotentialPeer>>>) {
+        self.torrents.lock().expect("The LSD torrents lock was poisoned").push(LocalTorrent { info_hash, potential_peers });
+        //let the new torrent go out with the next announcement
+        *self.last_announce.lock().expect("The LSD announce lock was poisoned") = None;
+    }
+
+    ///Stops announcing and listening
+    pub fn shut_down(&self) {
+        self.shut_down.store(true, Ordering::Relaxed);
+        let _ = self.socket.leave_multicast_v4(&LSD_GROUP, &self.interface);
+    }
+
+    ///Announces every so often and handles other peers' announcements until shut down
+    fn run(&self) {
+        let mut buf = [0u8; 1500];
+        while !self.shut_down.load(Ordering::Relaxed) {
+            let due = match *self.last_announce.lock().expect("The LSD announce lock was poisoned") {
+                Some(last) => last.elapsed() > Duration::from_secs(ANNOUNCE_INTERVAL_SECS),
+                None => true
+            };
+            if due {
+                self.announce();
+            }
+            if let Ok((len, SocketAddr::V4(from))) = self.socket.recv_from(&mut buf) {
+                self.handle_announcement(&buf[0..len], from);
+            }
+        }
+    }
+
+    ///Sends one announcement holding the info hashes of all our torrents
+    fn announce(&self) {
+        *self.last_announce.lock().expect("The LSD announce lock was poisoned") = Some(Instant::now());
+        let torrents = self.torrents.lock().expect("The LSD torrents lock was poisoned");
+        if torrents.is_empty() {
+            return
+        }
+        let mut message = format!("BT-SEARCH * HTTP/1.1\r\nHost: {}:{}\r\nPort: {}\r\n", LSD_GROUP, LSD_PORT, self.listen_port);
+        for torrent in torrents.iter() {
+            message += format!("Infohash: {}\r\n", hex_encode(&torrent.info_hash)).as_str();
+        }
+        message += format!("cookie: {}\r\n\r\n\r\n", self.cookie).as_str();
+        let _ = self.socket.send_to(message.as_bytes(), SocketAddrV4::new(LSD_GROUP, LSD_PORT));
+    }
+
+    ///Parses an announcement from another peer and adds it as a potential peer of
+    ///every torrent of ours it announced
+    fn handle_announcement(&self, packet: &[u8], from: SocketAddrV4) {
+        let text = match str::from_utf8(packet) {
+            Ok(text) => text,
+            Err(_) => return
+        };
+        let mut lines = text.split("\r\n");
+        if lines.next() != Some("BT-SEARCH * HTTP/1.1") {
+            return
+        }
+        let mut port = None;
+        let mut info_hashes = Vec::new();
+        for line in lines {
+            let (name, value) = match line.find(':') {
+                Some(idx) => (line[0..idx].trim().to_lowercase(), line[idx+1..].trim()),
+                None => continue
+            };
+            match name.as_str() {
+                "port" => port = value.parse::<u16>().ok(),
+                "infohash" => if let Some(info_hash) = hex_decode(value) {
+                    info_hashes.push(info_hash)
+                },
+                //our own announcement came back around
+                "cookie" if value == self.cookie => return,
+                _ => ()
+            }
+        }
+        let addr = match port {
+            Some(port) if port != 0 => SocketAddrV4::new(*from.ip(), port),
+            _ => return
+        };
+
+        let torrents = self.torrents.lock().expect("The LSD torrents lock was poisoned");
+        for torrent in torrents.iter().filter(|torrent| info_hashes.contains(&torrent.info_hash)) {
+            let mut potential_peers = torrent.potential_peers.write().expect("The potential peers lock was poisoned");
+            if !potential_peers.iter().any(|potential_peer| potential_peer.addr == addr) {
+                potential_peers.push(PotentialPeer { addr, id: None });
+            }
+        }
+    }
+}
+
+///Decodes a 40 character hex info hash
+fn hex_decode(hex: &str) -> Option<[u8; 20]> {
+    if hex.len() != 40 || !hex.is_ascii() {
+        return None
+    }
+    let mut info_hash = [0u8; 20];
+    for (idx, byte) in info_hash.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[idx*2..idx*2+2], 16).ok()?;
+    }
+    Some(info_hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shares_the_port_with_other_clients() {
+        let first = LocalDiscovery::start(Ipv4Addr::new(0, 0, 0, 0), 6881).ok().expect("first LSD socket");
+        let second = LocalDiscovery::start(Ipv4Addr::new(0, 0, 0, 0), 6882).ok().expect("second LSD socket");
+        first.shut_down();
+        second.shut_down();
+    }
+
+    #[test]
+    fn reads_announcements() {
+        let lsd = LocalDiscovery::start(Ipv4Addr::new(0, 0, 0, 0), 6881).ok().unwrap();
+        let potential_peers = Arc::new(RwLock::new(Vec::new()));
+        lsd.add_torrent([0xab; 20], potential_peers.clone());
+        let from = SocketAddrV4::new(Ipv4Addr::new(192, 168, 1, 5), 6771);
+        let message = format!("BT-SEARCH * HTTP/1.1\r\nHost: {}:{}\r\nPort: 7000\r\nInfohash: {}\r\ncookie: other\r\n\r\n\r\n",
+                              LSD_GROUP, LSD_PORT, hex_encode(&[0xab; 20]));
+        lsd.handle_announcement(message.as_bytes(), from);
+        //our own announcements are skipped
+        let own = message.replace("cookie: other", &format!("cookie: {}", lsd.cookie)).replace("7000", "7001");
+        lsd.handle_announcement(own.as_bytes(), from);
+        let potential_peers = potential_peers.read().unwrap();
+        assert_eq!(potential_peers.len(), 1);
+        assert_eq!(potential_peers[0].addr, SocketAddrV4::new(Ipv4Addr::new(192, 168, 1, 5), 7000));
+        lsd.shut_down();
+    }
+}
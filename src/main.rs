@@ -5,29 +5,71 @@ extern crate sha1;
 #[macro_use]
 extern crate bitflags;
 extern crate clap;
+extern crate mio;
+extern crate net2;
+extern crate libc;
 mod bencode;
 mod meta;
 mod tracker;
+mod compact;
 mod peer;
 mod bitvector;
 mod message;
 mod torrentfile;
 mod error;
 mod piece;
+mod extension;
+mod pex;
+mod fast;
+mod dht;
+mod lsd;
+mod peerid;
+mod mse;
+mod session;
+mod pipeline;
+mod cache;
+mod choker;
+mod picker;
+mod stream;
+mod priority;
+mod hashpool;
+mod resume;
+mod check;
+mod util;
 
 use meta::MetaInfo;
-use clap::{App,Arg};
-use error::BoostError;
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use error::{BoostError, BoostResult};
 use bitvector::BitVector;
-use std::sync::{Arc, RwLock, mpsc};
-use peer::{PeerFlags, Peer};
-use tracker::PotentialPeer;
-use piece::Piece;
+use std::sync::{Arc, RwLock};
+use peer::PeerFlags;
+use tracker::{Announce, PotentialPeer};
+use torrentfile::TorrentFile;
 use std::sync::atomic::{AtomicUsize, AtomicBool, Ordering};
-use std::net::{TcpListener, TcpStream, SocketAddr, Shutdown};
+use std::net::{TcpListener, SocketAddr, SocketAddrV4};
 use rand::Rng;
-use std::{thread, time};
-use message::BitTorrentMessage;
+use std::{io, thread, time};
+use std::io::BufRead;
+use std::fs::File;
+use extension::ExtensionRegistry;
+use pex::PeerExchange;
+use dht::Dht;
+use lsd::LocalDiscovery;
+use mse::EncryptionPolicy;
+use session::{Session, SessionSettings, SessionShared};
+use picker::PickMode;
+use stream::StreamReader;
+use priority::Priorities;
+use resume::ResumeData;
+use std::net::Ipv4Addr;
+
+///How long to wait between tracker requests if the tracker never told us
+const DEFAULT_TRACKER_INTERVAL: u32 = 1800;
+///How often to look for peers in the DHT and announce ourselves there
+const DHT_ANNOUNCE_INTERVAL_SECS: u64 = 15 * 60;
+
+///Set when we are asked to stop with Ctrl-C or SIGTERM
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
 
 
 fn main() {
@@ -36,6 +78,25 @@ fn main() {
         .version(env!("CARGO_PKG_VERSION"))
         .author(env!("CARGO_PKG_AUTHORS"))
         .about("A torrent client written in rust")
+        .setting(AppSettings::SubcommandsNegateReqs)
+        .subcommand(
+            SubCommand::with_name("check")
+            .about("Checks the torrent's data on disk against its piece hashes and saves which pieces are good")
+            .arg(
+                Arg::with_name("meta")
+                .required(true)
+                .short("m")
+                .long("meta")
+                .takes_value(true)
+                .help("The torrent's metafile")
+                )
+            .arg(
+                Arg::with_name("resume")
+                .long("resume")
+                .takes_value(true)
+                .help("Where to save which pieces are good, by default the torrent's info hash in hex with .resume")
+                )
+            )
         .arg(
             Arg::with_name("meta")
             .required(true)
@@ -43,9 +104,128 @@ fn main() {
             .long("meta")
             .takes_value(true)
             .help("The torrent's metafile")
+            )
+        .arg(
+            Arg::with_name("dht-state")
+            .long("dht-state")
+            .takes_value(true)
+            .default_value("boost.dht")
+            .help("Where the DHT routing table is kept between runs")
+            )
+        .arg(
+            Arg::with_name("encryption")
+            .long("encryption")
+            .takes_value(true)
+            .possible_values(&["disabled", "preferred", "required"])
+            .default_value("preferred")
+            .help("Whether to encrypt connections to peers")
+            )
+        .arg(
+            Arg::with_name("bind")
+            .long("bind")
+            .takes_value(true)
+            .default_value("0.0.0.0")
+            .help("The address of the interface to use for everything not given its own address")
+            )
+        .arg(
+            Arg::with_name("peer-bind")
+            .long("peer-bind")
+            .takes_value(true)
+            .help("The address to listen for peers on")
+            )
+        .arg(
+            Arg::with_name("tracker-bind")
+            .long("tracker-bind")
+            .takes_value(true)
+            .help("The address to send tracker requests from")
+            )
+        .arg(
+            Arg::with_name("dht-bind")
+            .long("dht-bind")
+            .takes_value(true)
+            .help("The address the DHT node listens on")
+            )
+        .arg(
+            Arg::with_name("port")
+            .short("p")
+            .long("port")
+            .takes_value(true)
+            .conflicts_with("port-range")
+            .help("The port to listen for peers on, the DHT uses the same one")
+            )
+        .arg(
+            Arg::with_name("port-range")
+            .long("port-range")
+            .takes_value(true)
+            .help("A range of ports like 6881-6889 to listen for peers on, the first free one is used")
+            )
+        .arg(
+            Arg::with_name("sequential")
+            .long("sequential")
+            .help("Download pieces in order instead of rarest first, so files can be played before they finish")
+            )
+        .arg(
+            Arg::with_name("read-ahead")
+            .long("read-ahead")
+            .takes_value(true)
+            .help("How many pieces ahead of the playhead to download strictly in order in sequential mode")
+            )
+        .arg(
+            Arg::with_name("stream-to")
+            .long("stream-to")
+            .takes_value(true)
+            .help("Copy the torrent's data to this file in order as it downloads. Implies --sequential")
+            )
+        .arg(
+            Arg::with_name("file-priority")
+            .long("file-priority")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .help("Sets the priority of a file like 2=high, counting files from 0 in metafile order. \
+                   Priorities are skip, low, normal and high. They can be changed while the torrent \
+                   runs by typing them on standard input, one per line")
+            )
+        .arg(
+            Arg::with_name("resume")
+            .long("resume")
+            .takes_value(true)
+            .help("Where what the torrent has downloaded is kept between runs, by default its info hash in hex with .resume")
             ).get_matches();
 
+    if let Some(check_args) = args.subcommand_matches("check") {
+        check_command(check_args);
+        return
+    }
+
     let file = args.value_of("meta").unwrap();
+    let dht_state = String::from(args.value_of("dht-state").unwrap());
+    let encryption = EncryptionPolicy::from_name(args.value_of("encryption").unwrap()).unwrap();
+    let bind_ip = parse_ip(args.value_of("bind").unwrap());
+    let peer_ip = args.value_of("peer-bind").map_or(bind_ip, parse_ip);
+    let tracker_ip = args.value_of("tracker-bind").map_or(bind_ip, parse_ip);
+    let dht_ip = args.value_of("dht-bind").map_or(bind_ip, parse_ip);
+    //no port means any free one
+    let (first_port, last_port) = match (args.value_of("port"), args.value_of("port-range")) {
+        (Some(port), _) => {
+            let port = parse_port(port);
+            (port, port)
+        },
+        (None, Some(range)) => parse_port_range(range),
+        (None, None) => (0, 0)
+    };
+
+    let read_ahead = args.value_of("read-ahead").map_or(picker::DEFAULT_READ_AHEAD, |read_ahead| read_ahead.parse().unwrap_or_else(|_| {
+        println!("{} is not a number of pieces", read_ahead);
+        std::process::exit(1)
+    }));
+    let stream_to = args.value_of("stream-to").map(String::from);
+    let playhead = Arc::new(AtomicUsize::new(0));
+    let pick_mode = if args.is_present("sequential") || stream_to.is_some() {
+        PickMode::Sequential { read_ahead, playhead: playhead.clone() }
+    } else {
+        PickMode::RarestFirst
+    };
 
     //parse meta file
     let meta_info = meta::MetaInfo::parse_meta(file).unwrap_or_else(|err: BoostError| {
@@ -53,19 +233,37 @@ fn main() {
         std::process::exit(1)
     });
 
+    //pick up where the last run left off, if there was one
+    let resume_path = resume_path(&args, &meta_info);
+    let resume = ResumeData::load(resume_path.as_str(), &meta_info).ok();
+
+    //every file starts at normal priority, or the priority it had last run,
+    //unless the command line says otherwise
+    let mut priorities = Priorities::new(&meta_info);
+    if let Some(ref resume) = resume {
+        for (file, &priority) in resume.priorities.iter().enumerate() {
+            priorities.set_file(file, priority);
+        }
+    }
+    for file_priority in args.values_of("file-priority").into_iter().flatten() {
+        let (file, priority) = priority::parse_file_priority(file_priority).unwrap_or_else(|| {
+            println!("{} is not a file priority", file_priority);
+            std::process::exit(1)
+        });
+        if !priorities.set_file(file, priority) {
+            println!("The torrent has no file {}", file);
+            std::process::exit(1)
+        }
+    }
+    let priorities = Arc::new(RwLock::new(priorities));
+
     //set up variables
-    let torrent_size = meta_info.file_info.total_bytes();
-    let num_pieces = (if torrent_size % meta_info.piece_len == 0 { 0 } else { 1 }
-                      + torrent_size / meta_info.piece_len) as usize;
     let peerid = gen_peer_id();
     let completed = Arc::new(RwLock::new(BitVector::new(meta_info.num_pieces())));
-    let active_peers: Arc<RwLock<Vec<Peer>>> = Arc::new(RwLock::new(Vec::new()));
     let potential_peers: Arc<RwLock<Vec<PotentialPeer>>> = Arc::new(RwLock::new(Vec::new()));
-    let working_pieces: Arc<RwLock<Vec<Piece>>> = Arc::new(RwLock::new(Vec::new()));
-    let request_queue: Arc<RwLock<Vec<(&Peer, &BitTorrentMessage)>>> = Arc::new(RwLock::new(Vec::new()));
     let total_uploaded = Arc::new(AtomicUsize::new(0));
     let total_downloaded = Arc::new(AtomicUsize::new(0));
-    let listener = TcpListener::bind("0.0.0.0:0").expect("Error creating listener socket");
+    let listener = bind_listener(peer_ip, first_port, last_port);
     let listen_port = match listener.local_addr() {
         Ok(SocketAddr::V4(sockv4)) => sockv4.port(),
         Ok(SocketAddr::V6(sockv6)) => sockv6.port(),
@@ -74,70 +272,259 @@ fn main() {
     let wrap_up = Arc::new(AtomicBool::new(false));
     let mut info_hash = Vec::new();
     info_hash.extend_from_slice(&meta_info.info_hash);
-    let (death_announcer, death_listener) = mpsc::channel();
-
-    //make first call out to tracker
-    let mut tracker_info = tracker::TrackerInfo::tracker_request(
-        meta_info.announce_url.as_str(),
-        &meta_info.info_hash,
-        peerid.as_bytes(),
-        listen_port,
-        total_uploaded.load(Ordering::Relaxed) as u64,
-        total_downloaded.load(Ordering::Relaxed) as u64,
-        meta_info.file_info.total_bytes(),
-        tracker::TrackerEvent::Started,
+    let mut extensions = ExtensionRegistry::new();
+    //private torrents may only get peers from their trackers
+    let pex = if meta_info.private {
         None
-        ).unwrap_or_else(|err: BoostError| {
-        println!("{}",err);
-        std::process::exit(2)
-    });
+    } else {
+        let pex = Arc::new(PeerExchange::new(potential_peers.clone(), SocketAddrV4::new(peer_ip, listen_port)));
+        extensions.register(pex.clone());
+        Some(pex)
+    };
+    let extensions = Arc::new(extensions);
+    let mut ext_handshake = extensions.handshake();
+    ext_handshake.version = Some(format!("Boost {}", env!("CARGO_PKG_VERSION")));
+    ext_handshake.listen_port = Some(listen_port);
+    ext_handshake.request_queue = Some(peer::MAX_QUEUED_REQUESTS);
+
+    //make first call out to tracker, if there is one. If it can't be reached we
+    //carry on and hope the DHT turns up peers
+    let tracker_info = meta_info.announce_url.as_ref().and_then(|url| tracker::TrackerInfo::tracker_request(
+        url.as_str(),
+        tracker_ip,
+        Announce {
+            info_hash: &meta_info.info_hash,
+            peer_id: peerid.as_bytes(),
+            listen_port,
+            uploaded_bytes: total_uploaded.load(Ordering::Relaxed) as u64,
+            downloaded_bytes: total_downloaded.load(Ordering::Relaxed) as u64,
+            bytes_left: meta_info.file_info.total_bytes(),
+            event: tracker::TrackerEvent::Started,
+            tracker_id: None
+        }).map_err(|err: BoostError| println!("{}",err)).ok());
 
     //write the first batch of potential peers
-    {
+    if let Some(ref tracker_info) = tracker_info {
         potential_peers.write()
             .expect("The potential peers lock was poisoned")
-            .append(&mut tracker_info.potential_peers)
+            .extend(tracker_info.potential_peers.iter().map(|peer| PotentialPeer { addr: peer.addr, id: peer.id }))
+    };
+
+    //private torrents may only get peers from their trackers, so no DHT either
+    let dht = if meta_info.private {
+        None
+    } else {
+        Dht::start(SocketAddrV4::new(dht_ip, listen_port), Some(dht_state.as_str()))
+            .map_err(|err: BoostError| println!("{}",err)).ok()
     };
 
+    //look for peers on the local network too, unless the torrent is private
+    let lsd = if meta_info.private {
+        None
+    } else {
+        LocalDiscovery::start(peer_ip, listen_port)
+            .map_err(|err: BoostError| println!("{}, local service discovery is disabled", err)).ok()
+    };
+    if let Some(ref lsd) = lsd {
+        lsd.add_torrent(meta_info.info_hash, potential_peers.clone());
+    }
+
+    //set once the download finishes during this run, so the tracker gets told
+    let finished = Arc::new(AtomicBool::new(false));
+
     //launch threads
-    let tracker_thread = start_tracker_request_thread(
-        meta_info.announce_url.clone(),
-        info_hash.clone(),
-        peerid.clone(),
-        listen_port,
-        tracker_info.interval,
-        meta_info.file_info.total_bytes(),
+    let tracker_thread = meta_info.announce_url.clone().map(|url| start_tracker_request_thread(
+        TrackerSettings {
+            url,
+            bind_ip: tracker_ip,
+            info_hash: info_hash.clone(),
+            peer_id: peerid.clone(),
+            listen_port,
+            interval: tracker_info.as_ref().map(|info| info.interval).unwrap_or(DEFAULT_TRACKER_INTERVAL),
+            file_size: meta_info.file_info.total_bytes(),
+            tracker_id: tracker_info.as_ref().and_then(|info| info.tracker_id.clone())
+        },
         total_uploaded.clone(),
         total_downloaded.clone(),
-        tracker_info.tracker_id.clone(),
         potential_peers.clone(),
+        finished.clone(),
         wrap_up.clone()
-        );
-    let manager_thread = start_peer_management_thread(
-        peerid.clone(),
-        info_hash.clone(),
-        active_peers.clone(),
+        ));
+    let dht_thread = dht.clone().map(|dht| start_dht_thread(
+        dht,
+        meta_info.nodes.clone(),
+        meta_info.info_hash,
+        listen_port,
         potential_peers.clone(),
-        death_listener,
-        num_pieces,
         wrap_up.clone()
-        );
+        ));
+    //one event loop talks to every peer
+    let mut torrent_file = TorrentFile::open(&meta_info);
+    let existing_data = torrent_file.has_data();
+    //what we had on disk can only be trusted if nothing touched the files since,
+    //otherwise any data there is checked, it may be a download finished elsewhere
+    let partial = match resume {
+        Some(ref resume) if resume.matches_data(&meta_info) => {
+            *completed.write().expect("The completed lock was poisoned") = resume.completed.clone();
+            resume.partial.clone()
+        },
+        _ if existing_data => {
+            match check_data(|offset, buffer| torrent_file.read(offset, buffer), &meta_info) {
+                Ok(checked) => *completed.write().expect("The completed lock was poisoned") = checked,
+                Err(err) => println!("{}",err)
+            }
+            Vec::new()
+        },
+        _ => Vec::new()
+    };
+    let stream_thread = stream_to.map(|stream_to| start_stream_thread(
+        StreamReader::new(&meta_info, completed.clone(), playhead.clone(), wrap_up.clone()),
+        stream_to
+        ));
+    let settings = SessionSettings {
+        bind_ip: peer_ip,
+        my_id: peerid.clone(),
+        encryption,
+        pick_mode,
+        extensions: extensions.clone(),
+        ext_handshake,
+        pex
+    };
+    let shared = SessionShared {
+        completed: completed.clone(),
+        priorities: priorities.clone(),
+        total_uploaded: total_uploaded.clone(),
+        total_downloaded: total_downloaded.clone(),
+        potential_peers: potential_peers.clone(),
+        wrap_up: wrap_up.clone()
+    };
+    let mut session = Session::new(listener, &meta_info, torrent_file, settings, shared).unwrap_or_else(|err: BoostError| {
+        println!("{}",err);
+        std::process::exit(1)
+    });
+    if let Err(err) = session.restore_partial(&partial) {
+        println!("{}",err);
+    }
+    let session_thread = thread::spawn(move || session.run());
+    start_priority_thread(priorities.clone());
+    //download, then seed once every piece we want is here, until we are asked to stop.
+    //A download that was already done when we started isn't announced as finished
+    catch_interrupts();
+    let second = time::Duration::from_secs(1);
+    let is_finished = || download_finished(&completed.read().expect("The completed lock was poisoned"),
+                                           &priorities.read().expect("The priorities lock was poisoned"));
+    let mut seeding = is_finished();
+    while !INTERRUPTED.load(Ordering::Relaxed) && !session_thread.is_finished() {
+        //a skipped file asked for while seeding starts the download again
+        let now_seeding = is_finished();
+        if now_seeding && !seeding {
+            println!("Download finished, seeding until stopped");
+            finished.store(true, Ordering::Relaxed);
+        }
+        seeding = now_seeding;
+        thread::sleep(second);
+    }
     //tell infininte looping threads to wrap up so they can be joined
     wrap_up.store(true, Ordering::Relaxed);
-    //disconnect all active peers so their reciever threads will die
-    {
-        for peer in active_peers.read().expect("The active peers lock was poisoned").iter() {
-            let _ = peer.socket.shutdown(Shutdown::Both);
-        }
-    };
 
     //join all threads
-    let _ = tracker_thread.join();
-    let _ = manager_thread.join();
+    if let Some(tracker_thread) = tracker_thread {
+        let _ = tracker_thread.join();
+    }
+    if let Some(dht_thread) = dht_thread {
+        let _ = dht_thread.join();
+    }
+    if let Some(lsd) = lsd {
+        lsd.shut_down();
+    }
+    //keep the routing table around so the next run doesn't have to bootstrap
+    if let Some(dht) = dht {
+        let _ = dht.save(dht_state.as_str());
+        dht.shut_down();
+    }
+    //the session disconnects every peer when it ends
+    let partial = session_thread.join().unwrap_or_default();
+    //the files are closed now, so their modification times are final
+    let (file_len, file_mtime) = resume::data_stamp(&meta_info).unwrap_or((0, 0));
+    let resume = ResumeData {
+        info_hash: meta_info.info_hash,
+        completed: completed.read().expect("The completed lock was poisoned").clone(),
+        partial,
+        priorities: priorities.read().expect("The priorities lock was poisoned").files().to_vec(),
+        //the totals count every run, the tracker is only told about this one
+        uploaded: resume.as_ref().map_or(0, |resume| resume.uploaded) + total_uploaded.load(Ordering::Relaxed) as u64,
+        downloaded: resume.as_ref().map_or(0, |resume| resume.downloaded) + total_downloaded.load(Ordering::Relaxed) as u64,
+        file_len,
+        file_mtime
+    };
+    if let Err(err) = resume.save(resume_path.as_str()) {
+        println!("{}",err);
+    }
+    if let Some(stream_thread) = stream_thread {
+        let _ = stream_thread.join();
+    }
 
     println!("{:#?}",potential_peers)
 }
 
+///parses an IPv4 address from the command line, exiting if it isn't one
+fn parse_ip(ip: &str) -> Ipv4Addr {
+    ip.parse().unwrap_or_else(|_| {
+        println!("{} is not an IPv4 address", ip);
+        std::process::exit(1)
+    })
+}
+
+///tells whether every piece of the files we want has been downloaded
+fn download_finished(completed: &BitVector, priorities: &Priorities) -> bool {
+    completed.or(&priorities.skipped_pieces()).is_complete()
+}
+
+extern "C" fn on_interrupt(_: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::Relaxed);
+}
+
+///Catches Ctrl-C and SIGTERM, so we stop cleanly and the resume data gets saved
+fn catch_interrupts() {
+    let handler: extern "C" fn(libc::c_int) = on_interrupt;
+    unsafe {
+        libc::signal(libc::SIGINT, handler as libc::sighandler_t);
+        libc::signal(libc::SIGTERM, handler as libc::sighandler_t);
+    }
+}
+
+///parses a port from the command line, exiting if it isn't one
+fn parse_port(port: &str) -> u16 {
+    port.parse().unwrap_or_else(|_| {
+        println!("{} is not a port", port);
+        std::process::exit(1)
+    })
+}
+
+///parses a port range like 6881-6889 from the command line, exiting if it isn't one
+fn parse_port_range(range: &str) -> (u16, u16) {
+    let mut ports = range.splitn(2, '-');
+    let first = parse_port(ports.next().unwrap_or(""));
+    let last = ports.next().map_or(first, parse_port);
+    if first > last {
+        println!("{} is not a port range", range);
+        std::process::exit(1)
+    }
+    (first, last)
+}
+
+///binds the peer listener to the first free port in the range. If every port in
+///it is taken, falls back on any free port so we can still make outgoing connections
+fn bind_listener(ip: Ipv4Addr, first_port: u16, last_port: u16) -> TcpListener {
+    for port in first_port..=last_port {
+        if let Ok(listener) = TcpListener::bind(SocketAddrV4::new(ip, port)) {
+            return listener
+        }
+    }
+    println!("Could not listen on {} ports {}-{}, using any free port", ip, first_port, last_port);
+    TcpListener::bind(SocketAddrV4::new(ip, 0)).expect("Error creating listener socket")
+}
+
 fn gen_peer_id() -> String {
     let mut rng = rand::thread_rng();
     let chargen = rng.gen_ascii_chars();
@@ -148,144 +535,207 @@ fn gen_peer_id() -> String {
     result
 }
 
-fn start_tracker_request_thread(url: String,
-                                info_hash: Vec<u8>,
-                                peer_id: String,
-                                listen_port: u16,
-                                interval: u32,
-                                file_size: u64,
+///Who we announce ourselves as to the tracker, and how often
+struct TrackerSettings {
+    url: String,
+    bind_ip: Ipv4Addr,
+    info_hash: Vec<u8>,
+    peer_id: String,
+    listen_port: u16,
+    interval: u32,
+    file_size: u64,
+    tracker_id: Option<String>
+}
+
+fn start_tracker_request_thread(settings: TrackerSettings,
                                 uploaded_bytes: Arc<AtomicUsize>,
                                 downloaded_bytes: Arc<AtomicUsize>,
-                                tracker_id: Option<String>,
                                 potential_peers: Arc<RwLock<Vec<PotentialPeer>>>,
+                                finished: Arc<AtomicBool>,
                                 wrap_up: Arc<AtomicBool>) -> thread::JoinHandle<()> {
     thread::spawn(move || {
         let second = time::Duration::from_secs(1);
+        let mut announced_finish = false;
         while !wrap_up.load(Ordering::Relaxed) {
             //every interval, requery tracker and add result to potential peers
-            //wait out the interval in 1 second chunks, so can check if need to stop
-            for _ in 0 .. interval {
+            //wait out the interval in 1 second chunks, so can check if need to stop,
+            //or tell the tracker straight away that the download finished
+            for _ in 0 .. settings.interval {
                 if wrap_up.load(Ordering::Relaxed) {
                     return
                 }
+                if !announced_finish && finished.load(Ordering::Relaxed) {
+                    break
+                }
                 thread::sleep(second);
             }
-            let mut tracker_info = tracker::TrackerInfo::tracker_request(
-                url.as_str(),
-                info_hash.as_slice(),
-                peer_id.as_bytes(),
-                listen_port,
-                uploaded_bytes.load(Ordering::Relaxed) as u64,
-                downloaded_bytes.load(Ordering::Relaxed) as u64,
-                file_size,
-                tracker::TrackerEvent::None,
-                tracker_id.clone()
-                ).unwrap_or_else(|err: BoostError| {
-                println!("{}",err);
-                std::process::exit(2)
-            });
-
-            potential_peers.write()
-                .expect("The potential peers lock was poisoned")
-                .append(&mut tracker_info.potential_peers)
+            let event = if !announced_finish && finished.load(Ordering::Relaxed) {
+                announced_finish = true;
+                tracker::TrackerEvent::Completed
+            } else {
+                tracker::TrackerEvent::None
+            };
+            //a tracker that is down may come back, so just try again next interval
+            match tracker::TrackerInfo::tracker_request(
+                settings.url.as_str(),
+                settings.bind_ip,
+                Announce {
+                    info_hash: settings.info_hash.as_slice(),
+                    peer_id: settings.peer_id.as_bytes(),
+                    listen_port: settings.listen_port,
+                    uploaded_bytes: uploaded_bytes.load(Ordering::Relaxed) as u64,
+                    downloaded_bytes: downloaded_bytes.load(Ordering::Relaxed) as u64,
+                    bytes_left: if announced_finish { 0 } else { settings.file_size },
+                    event,
+                    tracker_id: settings.tracker_id.clone()
+                }) {
+                Ok(mut tracker_info) => potential_peers.write()
+                    .expect("The potential peers lock was poisoned")
+                    .append(&mut tracker_info.potential_peers),
+                Err(err) => println!("{}",err)
+            }
 
         }
     })
 
 }
 
+///Runs the check command: checks the data of a torrent already on disk and
+///saves the good pieces as its resume data, so the next run seeds or finishes them
+fn check_command(args: &ArgMatches) {
+    let file = args.value_of("meta").unwrap();
+    let meta_info = meta::MetaInfo::parse_meta(file).unwrap_or_else(|err: BoostError| {
+        println!("{}",err);
+        std::process::exit(1)
+    });
+    //the data is read from where the files end up, so missing or short files
+    //only fail their own pieces
+    let mut torrent_file = TorrentFile::open(&meta_info);
+    if !torrent_file.has_data() {
+        println!("There is no data for the torrent");
+        std::process::exit(1)
+    }
+    for problem in torrent_file.problems() {
+        println!("{}", problem);
+    }
+    let completed = check_data(|offset, buffer| torrent_file.read(offset, buffer), &meta_info).unwrap_or_else(|err: BoostError| {
+        println!("{}",err);
+        std::process::exit(1)
+    });
+    println!("{} of {} pieces are good", completed.count_ones(), meta_info.num_pieces());
 
-fn start_peer_management_thread(
-    peer_id: String,
-    info_hash: Vec<u8>,
-    active_peers: Arc<RwLock<Vec<Peer>>>,
-    potential_peers: Arc<RwLock<Vec<PotentialPeer>>>,
-    death_listener: mpsc::Receiver<[u8;20]>,
-    num_pieces: usize,
-    wrap_up: Arc<AtomicBool>) -> thread::JoinHandle<()> {
-    let mut outgoing_count = 0;
-    //start a bunch of connections to the potential peers
-    {
-        //drain the potential peers so we don't try to connect to the same one again later
-        let mut potential_peers = potential_peers.write().expect("The potential peers lock was poisoned");
-        while let Some(potential_peer) = potential_peers.pop() {
-            if outgoing_count < 30 {
-                if let Ok(stream) = TcpStream::connect(potential_peer.addr) {
-                    if let Ok(peer) = Peer::start_session(stream, peer_id.as_bytes(), info_hash.as_slice(), num_pieces, false) {
-                        //connected to peer, add to active peers!
-                        active_peers.write().expect("The active peers lock was poisoned").push(peer);
-                        outgoing_count += 1;
-                    }
-                }
-            }
-        }
+    //keep the priorities and totals of the last run
+    let resume_path = resume_path(args, &meta_info);
+    let previous = ResumeData::load(resume_path.as_str(), &meta_info).ok();
+    let (file_len, file_mtime) = resume::data_stamp(&meta_info).unwrap_or((0, 0));
+    let resume = ResumeData {
+        info_hash: meta_info.info_hash,
+        completed,
+        partial: Vec::new(),
+        priorities: previous.as_ref().map_or_else(|| Priorities::new(&meta_info).files().to_vec(), |previous| previous.priorities.clone()),
+        uploaded: previous.as_ref().map_or(0, |previous| previous.uploaded),
+        downloaded: previous.as_ref().map_or(0, |previous| previous.downloaded),
+        file_len,
+        file_mtime
     };
-    //spawn a thread that listens for dead peers, cleans them out and adds new peers
-    thread::spawn(move || {
-        //wait for a death message from some peer
-        while let Ok(peer_id) = death_listener.recv() {
-            //if time to wrap up, for get about the peer and just end
-            //when time to wrap up, all reciever threads will send out death
-            //signals, so the blocking death_listener will not prevent wrap_up
-            //from occuring
-            if wrap_up.load(Ordering::Relaxed) {
-                return
-            } else {
-                //remove peer from active peers
-                let ref mut active_peers = *active_peers.write().expect("The active peers lock was poisoned");
-                //find peer by id and remove
-                if let Some(peer_idx) = active_peers.iter().position(|peer| peer.id == peer_id) {
-                    let dead_peer = active_peers.remove(peer_idx);
-                    if !dead_peer.is_incoming() {
-                        outgoing_count -= 1;
-                    }
-                }
+    if let Err(err) = resume.save(resume_path.as_str()) {
+        println!("{}",err);
+        std::process::exit(1)
+    }
+}
+
+///checks every piece of the torrent's data, printing the progress as it goes
+fn check_data<R: FnMut(u64, &mut [u8]) -> BoostResult<usize>>(read: R, meta_info: &MetaInfo) -> BoostResult<BitVector> {
+    let mut last_percent = None;
+    check::check_pieces(read, meta_info, |checked, total| {
+        let percent = checked * 100 / total;
+        if last_percent != Some(percent) {
+            println!("Checked {}% of the pieces", percent);
+            last_percent = Some(percent);
+        }
+    })
+}
 
-                //if there is space, add a new connection from the potential peers list
-                if outgoing_count < 30 {
-                    let ref mut potential_peers = *potential_peers.write().expect("The potential peers lock was poisoned");
-                    //try to get a new peer until you run out of peers to try
-                    while let Some(potential_peer) = potential_peers.pop() {
-                        if let Ok(stream) = TcpStream::connect(potential_peer.addr) {
-                            if let Ok(peer) = Peer::start_session(stream, &peer_id, info_hash.as_slice(), num_pieces, false) {
-                                //connected to peer, add to active peers!
-                                active_peers.push(peer);
-                                outgoing_count += 1;
-                                break
-                            }
-                        }
+///where the torrent's resume data is kept, by default named after its info hash
+fn resume_path(args: &ArgMatches, meta_info: &MetaInfo) -> String {
+    args.value_of("resume").map_or_else(|| format!("{}.resume", util::hex_encode(&meta_info.info_hash)), String::from)
+}
 
+///spawns a thread that reads file priorities like 2=high from standard input
+///and applies them, so files can be skipped or fetched first while the torrent
+///runs. The session notices the change on its next tick. The thread is left to
+///end with the process, it can't be woken from waiting on input
+fn start_priority_thread(priorities: Arc<RwLock<Priorities>>) {
+    thread::spawn(move || {
+        let stdin = io::stdin();
+        for line in stdin.lock().lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(_) => return
+            };
+            if line.trim().is_empty() {
+                continue
+            }
+            match priority::parse_file_priority(&line) {
+                Some((file, priority)) => {
+                    if priorities.write().expect("The priorities lock was poisoned").set_file(file, priority) {
+                        println!("File {} is now {} priority", file, priority.name());
+                    } else {
+                        println!("The torrent has no file {}", file);
                     }
-                }
+                },
+                None => println!("{} is not a file priority", line.trim())
             }
         }
-    })
+    });
 }
 
-fn start_peer_recv_thread(
-                          death_announcer: mpsc::Sender<[u8;20]>,
-                          request_queue: Arc<RwLock<Vec<(&Peer, &BitTorrentMessage)>>>,
-                          active_peers: Arc<RwLock<Vec<Peer>>>,
-                          peer_idx: usize,
-                          total_uploaded: Arc<AtomicUsize>,
-                          total_downloaded: Arc<AtomicUsize>,
-                          wrap_up: Arc<AtomicBool>
-                          ) -> thread::JoinHandle<()> {
+///spawns a thread that copies the torrent's data to the given file in order,
+///as fast as the pieces arrive
+fn start_stream_thread(mut reader: StreamReader, stream_to: String) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        let result = File::create(&stream_to).map_err(|_| BoostError::FileOpenErr(stream_to.clone())).and_then(|mut out|
+            io::copy(&mut reader, &mut out).map_err(|_| BoostError::FileWriteErr(stream_to.clone())));
+        if let Err(err) = result {
+            println!("{}", err);
+        }
+    })
+}
 
+///spawns a thread that bootstraps the DHT if needed, then periodically looks up
+///peers for the torrent and announces that we are downloading it
+fn start_dht_thread(dht: Arc<Dht>,
+                    nodes: Vec<(String, u16)>,
+                    info_hash: [u8; 20],
+                    listen_port: u16,
+                    potential_peers: Arc<RwLock<Vec<PotentialPeer>>>,
+                    wrap_up: Arc<AtomicBool>) -> thread::JoinHandle<()> {
     thread::spawn(move || {
-        //doing this instead of passing peer directly so we can garuntee to the compiler
-        //that the peer will live longer than the reference we are taking to it
-        let ref mut peer = active_peers.write().expect("The active peers lock was poisoned")[peer_idx];
-        while let Ok(message) = peer.recv_message() {
-            if wrap_up.load(Ordering::Relaxed) {
-                break
-            }
-            match message {
-                _ => "not done yet"
+        //prefer the nodes from the metafile, fall back on the well known routers
+        if dht.num_nodes() == 0 {
+            dht.bootstrap(&nodes);
+        }
+        if dht.num_nodes() == 0 {
+            dht.bootstrap(&dht::DEFAULT_ROUTERS);
+        }
+        let second = time::Duration::from_secs(1);
+        while !wrap_up.load(Ordering::Relaxed) {
+            let found = dht.announce(&info_hash, listen_port);
+            {
+                let mut potential_peers = potential_peers.write().expect("The potential peers lock was poisoned");
+                for addr in found {
+                    if !potential_peers.iter().any(|potential_peer| potential_peer.addr == addr) {
+                        potential_peers.push(PotentialPeer { addr, id: None });
+                    }
+                }
             };
+            //wait out the interval in 1 second chunks, so can check if need to stop
+            for _ in 0 .. DHT_ANNOUNCE_INTERVAL_SECS {
+                if wrap_up.load(Ordering::Relaxed) {
+                    return
+                }
+                thread::sleep(second);
+            }
         }
-        //if we get here, there was an error or we are supposed to wrap up.
-        //tell the manager to clean this peer up
-        let _ = death_announcer.send(peer.id);
     })
 }
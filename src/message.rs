@@ -1,11 +1,15 @@
-use std::net::TcpStream;
 use byteorder::{NetworkEndian, ByteOrder};
-use std::io::{Read, Write};
 use bitvector::BitVector;
 use error::{BoostError, BoostResult};
 use std::fmt;
 
+///The largest message (length prefix excluded) we will accept from a peer.
+///A piece message is a 16KiB block plus 9 bytes of header, so this only has to
+///be generous enough for the bitfields of torrents with very many pieces
+pub const MAX_MESSAGE_LEN: u32 = 1 << 20;
+
 ///An enum that represents the possible messages BitTorrent can send
+#[derive(PartialEq)]
 pub enum BitTorrentMessage {
     KeepAlive,
     Choke,
@@ -17,11 +21,17 @@ pub enum BitTorrentMessage {
     Request { piece_index: u32, begin: u32, length: u32 },
     Piece { piece_index: u32, begin: u32, block: Vec<u8>},
     Cancel { piece_index: u32, begin: u32, length: u32 },
+    SuggestPiece(u32),
+    HaveAll,
+    HaveNone,
+    RejectRequest { piece_index: u32, begin: u32, length: u32 },
+    AllowedFast(u32),
+    Extended { id: u8, payload: Vec<u8> },
 }
 
 impl BitTorrentMessage {
-    ///Encodes self as the on-the-wire form and sends the result to the given dest
-    pub fn send(&self, dest: &mut TcpStream) -> BoostResult<()> {
+    ///Encodes self as the on-the-wire form, length prefix included
+    pub fn encode(&self) -> Vec<u8> {
         let mut msg = Vec::new(); //holds the data
         let mut u32bytebuf = [0u8; 4]; //used to convert u32 to network byte order byte arrays
         let mut send_buf = Vec::new(); //will be concat of msglen and msg
@@ -72,57 +82,163 @@ impl BitTorrentMessage {
                 NetworkEndian::write_u32(&mut u32bytebuf, length);
                 msg.extend_from_slice(&u32bytebuf);
 
+            },
+            //fast extension messages
+            &BitTorrentMessage::SuggestPiece(piece) => {
+                msg.push(13);
+                NetworkEndian::write_u32(&mut u32bytebuf, piece);
+                msg.extend_from_slice(&u32bytebuf);
+            },
+            &BitTorrentMessage::HaveAll => msg.push(14),
+            &BitTorrentMessage::HaveNone => msg.push(15),
+            //same as request but with id 16 instead of id 6
+            &BitTorrentMessage::RejectRequest { piece_index, begin, length } => {
+                msg.push(16);
+                NetworkEndian::write_u32(&mut u32bytebuf, piece_index);
+                msg.extend_from_slice(&u32bytebuf);
+                NetworkEndian::write_u32(&mut u32bytebuf, begin);
+                msg.extend_from_slice(&u32bytebuf);
+                NetworkEndian::write_u32(&mut u32bytebuf, length);
+                msg.extend_from_slice(&u32bytebuf);
+            },
+            &BitTorrentMessage::AllowedFast(piece) => {
+                msg.push(17);
+                NetworkEndian::write_u32(&mut u32bytebuf, piece);
+                msg.extend_from_slice(&u32bytebuf);
+            },
+            //send id, the extended message id and the extension's payload
+            &BitTorrentMessage::Extended { id, ref payload } => {
+                msg.push(20);
+                msg.push(id);
+                msg.extend_from_slice(payload.as_slice());
             }
         }
         NetworkEndian::write_u32(&mut u32bytebuf, msg.len() as u32);
         send_buf.extend_from_slice(&u32bytebuf);
         send_buf.append(&mut msg);
-        dest.write(send_buf.as_slice()).map(|_| ()).map_err(|_| BoostError::BitTorrentTCPSendErr)
+        send_buf
     }
 
-    ///Recieves a message from the src and decodes it to self
-    pub fn recv(src: &mut TcpStream) -> BoostResult<Self> {
-        let mut u32bytebuf = [0u8;4];
-        src.read(&mut u32bytebuf).map_err(|_| BoostError::BitTorrentTCPRecvErr)?;
-        let msglen = NetworkEndian::read_u32(&u32bytebuf);
-
-        if msglen == 0 {
-            Ok(BitTorrentMessage::KeepAlive)
+    ///Decodes the first message in buf. Returns the message and how many bytes
+    ///of buf it used up, or None if buf does not hold a whole message yet
+    pub fn decode(buf: &[u8]) -> BoostResult<Option<(Self, usize)>> {
+        if buf.len() < 4 {
+            return Ok(None)
+        }
+        let msglen = NetworkEndian::read_u32(&buf[0..4]);
+        if msglen > MAX_MESSAGE_LEN {
+            return Err(BoostError::BitTorrentProtocolErr(format!("Message length {} exceeds the maximum of {}", msglen, MAX_MESSAGE_LEN)))
+        }
+        let end = 4 + msglen as usize;
+        if buf.len() < end {
+            Ok(None)
+        } else if msglen == 0 {
+            Ok(Some((BitTorrentMessage::KeepAlive, end)))
         } else {
-            let mut data = Vec::with_capacity(msglen as usize);
-            src.read(data.as_mut_slice()).map_err(|_| BoostError::BitTorrentTCPRecvErr)?;
-            let msgid = data[0]; //get message id
-            match msgid {
-                1 => Ok(BitTorrentMessage::Choke),
-                2 => Ok(BitTorrentMessage::Unchoke),
-                3 => Ok(BitTorrentMessage::Interested),
-                4 => Ok(BitTorrentMessage::NotInterested),
-                5 => {
-                    Ok(BitTorrentMessage::Have(NetworkEndian::read_u32(&data[1..5])))
-                },
-                6 => {
-                    let piece_index = NetworkEndian::read_u32(&data[1..5]);
-                    let begin = NetworkEndian::read_u32(&data[5..9]);
-                    let length = NetworkEndian::read_u32(&data[9..13]);
-                    Ok(BitTorrentMessage::Request {piece_index, begin, length})
-                },
-                7 => {
-                    let piece_index = NetworkEndian::read_u32(&data[1..5]);
-                    let begin = NetworkEndian::read_u32(&data[5..9]);
-                    let mut block = Vec::new();
-                    block.extend_from_slice(&data[9..data.len()]);
-                    Ok(BitTorrentMessage::Piece {piece_index, begin, block})
-                },
-                8 => {
-                    let piece_index = NetworkEndian::read_u32(&data[1..5]);
-                    let begin = NetworkEndian::read_u32(&data[5..9]);
-                    let length = NetworkEndian::read_u32(&data[9..13]);
-                    Ok(BitTorrentMessage::Cancel {piece_index, begin, length})
+            BitTorrentMessage::decode_body(&buf[4..end]).map(|msg| Some((msg, end)))
+        }
+    }
+
+    ///tells whether this message is part of the fast extension, and so may only
+    ///be sent by peers that negotiated it
+    pub fn is_fast_extension(&self) -> bool {
+        matches!(*self, BitTorrentMessage::SuggestPiece(_) | BitTorrentMessage::HaveAll | BitTorrentMessage::HaveNone |
+            BitTorrentMessage::RejectRequest {..} | BitTorrentMessage::AllowedFast(_))
+    }
+
+    ///Decodes a message body (the message id followed by the payload, without the
+    ///length prefix), checking that the payload is the right size for the id
+    fn decode_body(data: &[u8]) -> BoostResult<Self> {
+        let msgid = data[0]; //get message id
+        let payload = &data[1..];
+        match msgid {
+            0 => {
+                check_payload_len("Choke", payload, 0)?;
+                Ok(BitTorrentMessage::Choke)
+            },
+            1 => {
+                check_payload_len("Unchoke", payload, 0)?;
+                Ok(BitTorrentMessage::Unchoke)
+            },
+            2 => {
+                check_payload_len("Interested", payload, 0)?;
+                Ok(BitTorrentMessage::Interested)
+            },
+            3 => {
+                check_payload_len("Not Interested", payload, 0)?;
+                Ok(BitTorrentMessage::NotInterested)
+            },
+            4 => {
+                check_payload_len("Have", payload, 4)?;
+                Ok(BitTorrentMessage::Have(NetworkEndian::read_u32(&payload[0..4])))
+            },
+            //the piece count isn't known here, so the peer checks the length and spare bits
+            5 => Ok(BitTorrentMessage::Bitfield(BitVector::from_bytes(payload, payload.len() * 8)?)),
+            6 => {
+                check_payload_len("Request", payload, 12)?;
+                let piece_index = NetworkEndian::read_u32(&payload[0..4]);
+                let begin = NetworkEndian::read_u32(&payload[4..8]);
+                let length = NetworkEndian::read_u32(&payload[8..12]);
+                Ok(BitTorrentMessage::Request {piece_index, begin, length})
+            },
+            7 => {
+                //a piece must at least have its index and offset
+                if payload.len() < 8 {
+                    return Err(BoostError::BitTorrentProtocolErr(format!("Piece payload is {} bytes, expected at least 8", payload.len())))
                 }
-                i => Err(BoostError::BitTorrentProtocolErr(format!("Message Id '{}' is not recognized", i)))
-            }
+                let piece_index = NetworkEndian::read_u32(&payload[0..4]);
+                let begin = NetworkEndian::read_u32(&payload[4..8]);
+                let block = payload[8..].to_vec();
+                Ok(BitTorrentMessage::Piece {piece_index, begin, block})
+            },
+            8 => {
+                check_payload_len("Cancel", payload, 12)?;
+                let piece_index = NetworkEndian::read_u32(&payload[0..4]);
+                let begin = NetworkEndian::read_u32(&payload[4..8]);
+                let length = NetworkEndian::read_u32(&payload[8..12]);
+                Ok(BitTorrentMessage::Cancel {piece_index, begin, length})
+            },
+            13 => {
+                check_payload_len("Suggest Piece", payload, 4)?;
+                Ok(BitTorrentMessage::SuggestPiece(NetworkEndian::read_u32(&payload[0..4])))
+            },
+            14 => {
+                check_payload_len("Have All", payload, 0)?;
+                Ok(BitTorrentMessage::HaveAll)
+            },
+            15 => {
+                check_payload_len("Have None", payload, 0)?;
+                Ok(BitTorrentMessage::HaveNone)
+            },
+            16 => {
+                check_payload_len("Reject Request", payload, 12)?;
+                let piece_index = NetworkEndian::read_u32(&payload[0..4]);
+                let begin = NetworkEndian::read_u32(&payload[4..8]);
+                let length = NetworkEndian::read_u32(&payload[8..12]);
+                Ok(BitTorrentMessage::RejectRequest {piece_index, begin, length})
+            },
+            17 => {
+                check_payload_len("Allowed Fast", payload, 4)?;
+                Ok(BitTorrentMessage::AllowedFast(NetworkEndian::read_u32(&payload[0..4])))
+            },
+            20 => {
+                //extended messages need at least their extended message id
+                if payload.is_empty() {
+                    return Err(BoostError::BitTorrentProtocolErr(String::from("Extended message is missing its id")))
+                }
+                Ok(BitTorrentMessage::Extended {id: payload[0], payload: payload[1..].to_vec()})
+            },
+            i => Err(BoostError::BitTorrentProtocolErr(format!("Message Id '{}' is not recognized", i)))
         }
+    }
+}
 
+///Makes sure a fixed size message has exactly the expected payload length
+fn check_payload_len(name: &str, payload: &[u8], expected: usize) -> BoostResult<()> {
+    if payload.len() == expected {
+        Ok(())
+    } else {
+        Err(BoostError::BitTorrentProtocolErr(format!("{} payload is {} bytes, expected {}", name, payload.len(), expected)))
     }
 }
 
@@ -138,7 +254,104 @@ impl fmt::Display for BitTorrentMessage {
             BitTorrentMessage::Bitfield(ref bitvector) => write!(f, "Have bitvector {}", bitvector),
             BitTorrentMessage::Request { piece_index, begin, length} => write!(f, "Request piece number {}, starting at {} and going for {} bytes", piece_index, begin, length),
             BitTorrentMessage::Piece { piece_index, begin, ref block} => write!(f, "Recieved piece number {}, starting at {} and going for {} bytes", piece_index, begin, block.len()),
-            BitTorrentMessage::Cancel { piece_index, begin, length } => write!(f, "canceled Request for piece number {}, starting at {} and going for {} bytes", piece_index, begin, length)
+            BitTorrentMessage::Cancel { piece_index, begin, length } => write!(f, "canceled Request for piece number {}, starting at {} and going for {} bytes", piece_index, begin, length),
+            BitTorrentMessage::SuggestPiece(idx) => write!(f, "Suggest piece number {}", idx),
+            BitTorrentMessage::HaveAll => write!(f, "Have All"),
+            BitTorrentMessage::HaveNone => write!(f, "Have None"),
+            BitTorrentMessage::RejectRequest { piece_index, begin, length } => write!(f, "Rejected Request for piece number {}, starting at {} and going for {} bytes", piece_index, begin, length),
+            BitTorrentMessage::AllowedFast(idx) => write!(f, "Allowed to request piece number {} while choked", idx),
+            BitTorrentMessage::Extended { id, ref payload } => write!(f, "Extended message {} with {} bytes of payload", id, payload.len())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(msg: BitTorrentMessage) {
+        let encoded = msg.encode();
+        match BitTorrentMessage::decode(&encoded) {
+            Ok(Some((decoded, used))) => {
+                assert_eq!(used, encoded.len());
+                assert!(decoded == msg, "{} did not survive a round trip", msg);
+            },
+            _ => panic!("{} could not be decoded", msg)
         }
     }
+
+    fn body(id: u8, payload: &[u8]) -> Vec<u8> {
+        let mut buf = vec![0u8; 4];
+        NetworkEndian::write_u32(&mut buf, payload.len() as u32 + 1);
+        buf.push(id);
+        buf.extend_from_slice(payload);
+        buf
+    }
+
+    #[test]
+    fn every_variant_round_trips() {
+        let mut bitfield = BitVector::new(16);
+        bitfield.set_index(0);
+        bitfield.set_index(9);
+        round_trip(BitTorrentMessage::KeepAlive);
+        round_trip(BitTorrentMessage::Choke);
+        round_trip(BitTorrentMessage::Unchoke);
+        round_trip(BitTorrentMessage::Interested);
+        round_trip(BitTorrentMessage::NotInterested);
+        round_trip(BitTorrentMessage::Have(0xdead_beef));
+        round_trip(BitTorrentMessage::Bitfield(bitfield));
+        round_trip(BitTorrentMessage::Request { piece_index: 1, begin: 16384, length: 16384 });
+        round_trip(BitTorrentMessage::Piece { piece_index: 2, begin: 32768, block: vec![1, 2, 3, 4, 5] });
+        round_trip(BitTorrentMessage::Piece { piece_index: 2, begin: 0, block: Vec::new() });
+        round_trip(BitTorrentMessage::Cancel { piece_index: 3, begin: 0, length: 100 });
+        round_trip(BitTorrentMessage::SuggestPiece(7));
+        round_trip(BitTorrentMessage::HaveAll);
+        round_trip(BitTorrentMessage::HaveNone);
+        round_trip(BitTorrentMessage::RejectRequest { piece_index: 4, begin: 16384, length: 9 });
+        round_trip(BitTorrentMessage::AllowedFast(1059));
+        round_trip(BitTorrentMessage::Extended { id: 0, payload: b"d1:pi6881ee".to_vec() });
+    }
+
+    #[test]
+    fn ids_match_the_wire() {
+        assert_eq!(BitTorrentMessage::Choke.encode(), vec![0, 0, 0, 1, 0]);
+        assert_eq!(BitTorrentMessage::Cancel { piece_index: 0, begin: 0, length: 0 }.encode()[4], 8);
+        assert_eq!(BitTorrentMessage::KeepAlive.encode(), vec![0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn waits_for_a_whole_message() {
+        let encoded = BitTorrentMessage::Have(5).encode();
+        assert!(BitTorrentMessage::decode(&encoded[..3]).ok().unwrap().is_none());
+        assert!(BitTorrentMessage::decode(&encoded[..encoded.len() - 1]).ok().unwrap().is_none());
+    }
+
+    #[test]
+    fn rejects_messages_over_the_maximum_length() {
+        let mut buf = vec![0u8; 4];
+        NetworkEndian::write_u32(&mut buf, MAX_MESSAGE_LEN + 1);
+        assert!(BitTorrentMessage::decode(&buf).is_err());
+        NetworkEndian::write_u32(&mut buf, MAX_MESSAGE_LEN);
+        assert!(BitTorrentMessage::decode(&buf).ok().unwrap().is_none());
+    }
+
+    #[test]
+    fn rejects_unknown_ids() {
+        for &id in &[9, 12, 18, 21, 255] {
+            assert!(BitTorrentMessage::decode(&body(id, &[])).is_err());
+        }
+    }
+
+    #[test]
+    fn rejects_wrong_payload_sizes() {
+        assert!(BitTorrentMessage::decode(&body(0, &[1])).is_err());
+        assert!(BitTorrentMessage::decode(&body(4, &[0, 0, 1])).is_err());
+        assert!(BitTorrentMessage::decode(&body(4, &[0, 0, 0, 0, 1])).is_err());
+        assert!(BitTorrentMessage::decode(&body(6, &[0; 11])).is_err());
+        assert!(BitTorrentMessage::decode(&body(7, &[0; 7])).is_err());
+        assert!(BitTorrentMessage::decode(&body(8, &[0; 13])).is_err());
+        assert!(BitTorrentMessage::decode(&body(16, &[0; 4])).is_err());
+        assert!(BitTorrentMessage::decode(&body(17, &[])).is_err());
+        assert!(BitTorrentMessage::decode(&body(20, &[])).is_err());
+    }
 }
@@ -5,16 +5,18 @@ use sha1::Sha1;
 use std::str;
 use error::{BoostError, BoostResult};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct MetaInfo {
-    pub announce_url : String,
+    pub announce_url : Option<String>,
     pub piece_len : u64,
     pub info_hash : [u8; 20],
     pub piece_hashes : Vec<[u8; 20]>,
-    pub file_info : FileInfo
+    pub file_info : FileInfo,
+    pub private : bool,
+    pub nodes : Vec<(String, u16)>
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum FileInfo {
     Single { filename : String, filelength : u64 },
     Multi { rootdir : String, files : Vec<FileInfo> }
@@ -35,7 +37,9 @@ impl MetaInfo {
                 let (piece_len, piece_hashes) = parse_pieces(&dict)?;
                 let file_info = parse_fileinfo(&dict)?;
                 let info_hash = make_info_hash(&dict)?;
-                Ok(MetaInfo { announce_url, piece_len, info_hash, piece_hashes, file_info })
+                let private = parse_private(&dict);
+                let nodes = parse_nodes(&dict);
+                Ok(MetaInfo { announce_url, piece_len, info_hash, piece_hashes, file_info, private, nodes })
             } else {
                 Err(BoostError::BencodeValueErr(String::from("Metafile bencode Toplevel not a dict")))
             }
@@ -49,6 +53,24 @@ impl MetaInfo {
             (bytes / self.piece_len + extra) as usize
         }
 
+        ///Gets the first and last piece of every file in the torrent, in the order
+        ///the files are laid out. Empty files have no pieces and are left out
+        pub fn file_pieces(&self) -> Vec<(u32, u32)> {
+            let lengths = match self.file_info {
+                FileInfo::Single { filelength, .. } => vec![filelength],
+                FileInfo::Multi { ref files, .. } => files.iter().map(|file| file.total_bytes()).collect()
+            };
+            let mut offset = 0;
+            let mut ranges = Vec::new();
+            for length in lengths {
+                if length > 0 {
+                    ranges.push(((offset / self.piece_len) as u32, ((offset + length - 1) / self.piece_len) as u32));
+                }
+                offset += length;
+            }
+            ranges
+        }
+
 }
 
 impl FileInfo {
@@ -76,21 +98,51 @@ fn make_info_hash(val: &BencodeValue) -> BoostResult<[u8;20]> {
     }
 }
 
-///gets the announce url from the metafile bdecoded values
-fn parse_announce(val: &BencodeValue) -> BoostResult<String> {
+///gets the announce url from the metafile bdecoded values.
+///Trackerless torrents have no announce url
+fn parse_announce(val: &BencodeValue) -> BoostResult<Option<String>> {
     //get dict from val
-    if let &BencodeValue::Dict(ref d) = val {
+    if let &BencodeValue::Dict(_) = val {
         //get value associated with announce key
-        let announce_result = d.iter().find(|&r| r.0 == "announce".as_bytes());
-        announce_result.map(|&(_,ref v)|  match v {
-            &BencodeValue::Str(ref s) => String::from(str::from_utf8(s).unwrap()),
-            _ => String::from("")
-        }).ok_or(BoostError::BencodeValueErr(String::from("Could not find Announce")))
+        match val.dict_get("announce") {
+            Some(&BencodeValue::Str(s)) => str::from_utf8(s)
+                .map(|url| Some(String::from(url)))
+                .map_err(|_| BoostError::BencodeValueErr(String::from("Announce is not a valid string"))),
+            Some(_) => Err(BoostError::BencodeValueErr(String::from("Announce is not a string"))),
+            None => Ok(None)
+        }
     } else {
         Err(BoostError::BencodeValueErr(String::from("Value not a dictionary")))
     }
 }
 
+///gets the DHT nodes from the metafile bdecoded values, a list of host and port
+///pairs for trackerless torrents. Entries that don't make sense are skipped
+fn parse_nodes(val: &BencodeValue) -> Vec<(String, u16)> {
+    let mut nodes = Vec::new();
+    if let Some(BencodeValue::List(list)) = val.dict_get("nodes") {
+        for node in list {
+            if let &BencodeValue::List(ref pair) = node {
+                if let (Some(&BencodeValue::Str(host)), Some(&BencodeValue::Integer(port))) = (pair.first(), pair.get(1)) {
+                    if let (Ok(host), true) = (str::from_utf8(host), port > 0 && port <= 65535) {
+                        nodes.push((String::from(host), port as u16));
+                    }
+                }
+            }
+        }
+    }
+    nodes
+}
+
+///checks the private flag of the info dict. Private torrents must only get peers
+///from their trackers, so peer exchange and the like are turned off for them
+fn parse_private(val: &BencodeValue) -> bool {
+    match val.dict_get("info").and_then(|info| info.dict_get("private")) {
+        Some(&BencodeValue::Integer(1)) => true,
+        _ => false
+    }
+}
+
 ///gets the piece length and the piece hashes from the metafile bdecoded values
 fn parse_pieces(val: &BencodeValue) -> BoostResult<(u64, Vec<[u8;20]>)> {
     //get dict from val
@@ -103,17 +155,15 @@ fn parse_pieces(val: &BencodeValue) -> BoostResult<(u64, Vec<[u8;20]>)> {
             let &(_, ref pieces) = info.iter().find(|&r| r.0 == "pieces".as_bytes()).ok_or(BoostError::BencodeValueErr(String::from("Could not find piece hashes")))?;
             //ensure they are the correct types
             if let (&BencodeValue::Integer(len), &BencodeValue::Str(ref pieces)) = (piece_len,pieces) {
-                let mut pos = 0;
-                let mut piece_vec = Vec::new();
-                //create fixed length array and copy 20 bytes from pieces string into it
-                while pieces.get(pos) != None {
-                    let mut hash : [u8;20] = [0;20];
-                    for idx in 0..20 {
-                        hash[idx] = pieces[idx];
-                    }
-                    piece_vec.push(hash);
-                    pos+=20;
+                if pieces.len() % 20 != 0 {
+                    return Err(BoostError::BencodeValueErr(String::from("Pieces is not a whole number of hashes")))
                 }
+                //create fixed length array and copy each 20 bytes from pieces string into it
+                let piece_vec = pieces.chunks(20).map(|chunk| {
+                    let mut hash : [u8;20] = [0;20];
+                    hash.copy_from_slice(chunk);
+                    hash
+                }).collect();
                 Ok((len as u64,  piece_vec))
 
             } else {
@@ -0,0 +1,657 @@
+use std::io::{self, Read, Write};
+use rand::{self, Rng};
+use sha1::Sha1;
+use error::{BoostError, BoostResult};
+
+///The 768 bit prime the Diffie-Hellman key exchange is done in. The generator is 2
+const PRIME: [u8; KEY_LEN] = [
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xC9, 0x0F, 0xDA, 0xA2,
+    0x21, 0x68, 0xC2, 0x34, 0xC4, 0xC6, 0x62, 0x8B, 0x80, 0xDC, 0x1C, 0xD1,
+    0x29, 0x02, 0x4E, 0x08, 0x8A, 0x67, 0xCC, 0x74, 0x02, 0x0B, 0xBE, 0xA6,
+    0x3B, 0x13, 0x9B, 0x22, 0x51, 0x4A, 0x08, 0x79, 0x8E, 0x34, 0x04, 0xDD,
+    0xEF, 0x95, 0x19, 0xB3, 0xCD, 0x3A, 0x43, 0x1B, 0x30, 0x2B, 0x0A, 0x6D,
+    0xF2, 0x5F, 0x14, 0x37, 0x4F, 0xE1, 0x35, 0x6D, 0x6D, 0x51, 0xC2, 0x45,
+    0xE4, 0x85, 0xB5, 0x76, 0x62, 0x5E, 0x7E, 0xC6, 0xF4, 0x4C, 0x42, 0xE9,
+    0xA6, 0x3A, 0x36, 0x21, 0x00, 0x00, 0x00, 0x00, 0x00, 0x09, 0x05, 0x63
+];
+///Length in bytes of public keys and the shared secret
+const KEY_LEN: usize = 96;
+///Length in bytes of our private keys
+const PRIVATE_KEY_LEN: usize = 20;
+///Most random padding either side may send in one go
+const MAX_PAD_LEN: usize = 512;
+///The verification constant, 8 zero bytes sent encrypted so the other side can find the
+///start of the encrypted stream behind the padding
+const VC: [u8; 8] = [0; 8];
+///How much of the RC4 keystream is thrown away before use
+const RC4_DISCARD: usize = 1024;
+
+///The crypto_provide and crypto_select bits
+const CRYPTO_PLAINTEXT: u32 = 0x01;
+const CRYPTO_RC4: u32 = 0x02;
+
+///The first 20 bytes of a plaintext BitTorrent handshake
+const PLAINTEXT_HEADER: &[u8] = b"\x13BitTorrent protocol";
+
+///Whether peer connections are obfuscated with message stream encryption
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum EncryptionPolicy {
+    ///Only plaintext connections
+    Disabled,
+    ///Encrypt when the peer can, fall back on plaintext when it can't
+    Preferred,
+    ///Only RC4 encrypted connections
+    Required
+}
+
+impl EncryptionPolicy {
+    ///Parses a policy from its lowercase name, as given on the command line
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "disabled" => Some(EncryptionPolicy::Disabled),
+            "preferred" => Some(EncryptionPolicy::Preferred),
+            "required" => Some(EncryptionPolicy::Required),
+            _ => None
+        }
+    }
+
+    ///The crypto methods we are willing to use under this policy
+    fn crypto_methods(self) -> u32 {
+        match self {
+            EncryptionPolicy::Disabled => CRYPTO_PLAINTEXT,
+            EncryptionPolicy::Preferred => CRYPTO_PLAINTEXT | CRYPTO_RC4,
+            EncryptionPolicy::Required => CRYPTO_RC4
+        }
+    }
+}
+
+///A peer connection that may be RC4 encrypted. Everything read and written goes
+///through the ciphers negotiated in the handshake, if any
+pub struct MseStream<S> {
+    inner: S,
+    read_cipher: Option<Rc4>,
+    write_cipher: Option<Rc4>,
+    ///already decrypted bytes that arrived during the handshake, read out before the socket
+    pending: Vec<u8>
+}
+
+impl<S> MseStream<S> {
+    ///Wraps a socket without any encryption
+    pub fn plaintext(inner: S) -> Self {
+        MseStream { inner, read_cipher: None, write_cipher: None, pending: Vec::new() }
+    }
+
+    ///the wrapped socket
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    ///Swaps the wrapped socket for another one made from it, keeping the
+    ///ciphers where they are in their keystreams
+    pub fn map_inner<T, F>(self, f: F) -> io::Result<MseStream<T>>
+        where F: FnOnce(S) -> io::Result<T> {
+        Ok(MseStream { inner: f(self.inner)?, read_cipher: self.read_cipher, write_cipher: self.write_cipher, pending: self.pending })
+    }
+
+    ///tells whether this connection is RC4 encrypted
+    pub fn is_encrypted(&self) -> bool {
+        self.read_cipher.is_some()
+    }
+}
+
+impl<S: Read> Read for MseStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.pending.is_empty() {
+            let len = ::std::cmp::min(buf.len(), self.pending.len());
+            buf[0..len].copy_from_slice(&self.pending[0..len]);
+            self.pending.drain(0..len);
+            return Ok(len)
+        }
+        let len = self.inner.read(buf)?;
+        if let Some(ref mut cipher) = self.read_cipher {
+            cipher.apply(&mut buf[0..len]);
+        }
+        Ok(len)
+    }
+}
+
+impl<S: Write> Write for MseStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.write_cipher {
+            //a non-blocking socket may take only part of the buffer, so the data is
+            //encrypted with a copy of the cipher and the keystream only moves on by
+            //what the socket took. The rest is encrypted again on the next write
+            Some(ref mut cipher) => {
+                let mut encrypted = buf.to_vec();
+                let mut trial = cipher.clone();
+                trial.apply(&mut encrypted);
+                let len = self.inner.write(&encrypted)?;
+                if len == encrypted.len() {
+                    *cipher = trial;
+                } else {
+                    cipher.apply(&mut encrypted[0..len]);
+                }
+                Ok(len)
+            },
+            None => self.inner.write(buf)
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+///Sets up an outgoing connection according to the policy. With encryption
+///enabled, the key exchange is done with the torrent's info hash as the shared key.
+///A peer that doesn't understand it will just hang up, so with the preferred policy
+///the caller should reconnect in plaintext if this fails
+pub fn connect<S: Read + Write>(mut sock: S, info_hash: &[u8], policy: EncryptionPolicy) -> BoostResult<MseStream<S>> {
+    if policy == EncryptionPolicy::Disabled {
+        return Ok(MseStream::plaintext(sock))
+    }
+    //send our public key
+    let (private_key, public_key) = key_pair();
+    let mut message = public_key.to_vec();
+    message.extend_from_slice(&random_pad());
+    write(&mut sock, &message)?;
+
+    //get theirs, and work out the shared secret
+    let their_key = read(&mut sock, KEY_LEN)?;
+    let secret = shared_secret(&their_key, &private_key)?;
+    let mut encryptor = Rc4::new(&hash(&[b"keyA", &secret, info_hash]));
+    let mut decryptor = Rc4::new(&hash(&[b"keyB", &secret, info_hash]));
+
+    //tell them which torrent we want and which methods we can do. We don't
+    //send an initial payload, the BitTorrent handshake follows afterwards
+    let mut message = hash(&[b"req1", &secret]).to_vec();
+    let req2 = hash(&[b"req2", info_hash]);
+    let req3 = hash(&[b"req3", &secret]);
+    message.extend(req2.iter().zip(req3.iter()).map(|(a, b)| a ^ b));
+    let mut encrypted = VC.to_vec();
+    encrypted.extend_from_slice(&policy.crypto_methods().to_be_bytes());
+    //no padding and no initial payload
+    encrypted.extend_from_slice(&[0, 0, 0, 0]);
+    encryptor.apply(&mut encrypted);
+    message.extend(encrypted);
+    write(&mut sock, &message)?;
+
+    //their answer starts with the encrypted verification constant, somewhere after their padding
+    let mut encrypted_vc = VC;
+    decryptor.apply(&mut encrypted_vc);
+    sync(&mut sock, &encrypted_vc, MAX_PAD_LEN)?;
+    let mut answer = read(&mut sock, 6)?;
+    decryptor.apply(&mut answer);
+    let selected = u32::from_be_bytes([answer[0], answer[1], answer[2], answer[3]]);
+    let pad_len = u16::from_be_bytes([answer[4], answer[5]]) as usize;
+    if pad_len > MAX_PAD_LEN {
+        return Err(BoostError::MSEProtocolErr(format!("Padding was too long: {} > {}", pad_len, MAX_PAD_LEN)))
+    }
+    let mut pad = read(&mut sock, pad_len)?;
+    decryptor.apply(&mut pad);
+
+    match selected {
+        CRYPTO_RC4 if policy.crypto_methods() & CRYPTO_RC4 != 0 =>
+            Ok(MseStream { inner: sock, read_cipher: Some(decryptor), write_cipher: Some(encryptor), pending: Vec::new() }),
+        CRYPTO_PLAINTEXT if policy.crypto_methods() & CRYPTO_PLAINTEXT != 0 => Ok(MseStream::plaintext(sock)),
+        _ => Err(BoostError::MSEProtocolErr(format!("Peer selected a crypto method we didn't offer: {}", selected)))
+    }
+}
+
+///Sets up an incoming connection according to the policy. The peer may start
+///with a plaintext handshake, or with a key exchange for any one of the given
+///info hashes. Returns the connection along with the info hash the peer asked
+///for in the key exchange, if it did one
+pub fn accept<S: Read + Write>(mut sock: S, info_hashes: &[[u8; 20]], policy: EncryptionPolicy) -> BoostResult<(MseStream<S>, Option<[u8; 20]>)> {
+    let start = read(&mut sock, PLAINTEXT_HEADER.len())?;
+    if start == PLAINTEXT_HEADER {
+        if policy == EncryptionPolicy::Required {
+            return Err(BoostError::MSEProtocolErr(String::from("Peer sent a plaintext handshake but encryption is required")))
+        }
+        //hand the start of the handshake back for the BitTorrent handshake to read
+        let mut stream = MseStream::plaintext(sock);
+        stream.pending = start;
+        return Ok((stream, None))
+    }
+    if policy == EncryptionPolicy::Disabled {
+        return Err(BoostError::MSEProtocolErr(String::from("Peer tried to encrypt but encryption is disabled")))
+    }
+
+    //the rest of their public key
+    let mut their_key = start;
+    their_key.extend(read(&mut sock, KEY_LEN - PLAINTEXT_HEADER.len())?);
+    let (private_key, public_key) = key_pair();
+    let mut message = public_key.to_vec();
+    message.extend_from_slice(&random_pad());
+    write(&mut sock, &message)?;
+    let secret = shared_secret(&their_key, &private_key)?;
+
+    //skip their padding, then find out which torrent they want
+    sync(&mut sock, &hash(&[b"req1", &secret]), MAX_PAD_LEN)?;
+    let req2_xor_req3 = read(&mut sock, 20)?;
+    let req3 = hash(&[b"req3", &secret]);
+    let req2: Vec<u8> = req2_xor_req3.iter().zip(req3.iter()).map(|(a, b)| a ^ b).collect();
+    let info_hash = *info_hashes.iter()
+        .find(|info_hash| hash(&[b"req2", &info_hash[..]])[..] == req2[..])
+        .ok_or_else(|| BoostError::MSEProtocolErr(String::from("Peer asked for a torrent we don't have")))?;
+    let mut decryptor = Rc4::new(&hash(&[b"keyA", &secret, &info_hash]));
+    let mut encryptor = Rc4::new(&hash(&[b"keyB", &secret, &info_hash]));
+
+    //verification constant, the methods they provide and their padding
+    let mut request = read(&mut sock, 14)?;
+    decryptor.apply(&mut request);
+    if request[0..8] != VC {
+        return Err(BoostError::MSEProtocolErr(String::from("Verification constant was not correct")))
+    }
+    let provided = u32::from_be_bytes([request[8], request[9], request[10], request[11]]);
+    let pad_len = u16::from_be_bytes([request[12], request[13]]) as usize;
+    if pad_len > MAX_PAD_LEN {
+        return Err(BoostError::MSEProtocolErr(format!("Padding was too long: {} > {}", pad_len, MAX_PAD_LEN)))
+    }
+    //the padding, then the length of the initial payload
+    let mut pad = read(&mut sock, pad_len + 2)?;
+    decryptor.apply(&mut pad);
+    let payload_len = u16::from_be_bytes([pad[pad_len], pad[pad_len + 1]]) as usize;
+    let mut payload = read(&mut sock, payload_len)?;
+    decryptor.apply(&mut payload);
+
+    //pick RC4 whenever we can
+    let acceptable = provided & policy.crypto_methods();
+    let selected = if acceptable & CRYPTO_RC4 != 0 {
+        CRYPTO_RC4
+    } else if acceptable & CRYPTO_PLAINTEXT != 0 {
+        CRYPTO_PLAINTEXT
+    } else {
+        return Err(BoostError::MSEProtocolErr(format!("Peer provided no crypto method we allow: {}", provided)))
+    };
+    let mut answer = VC.to_vec();
+    answer.extend_from_slice(&selected.to_be_bytes());
+    //no padding
+    answer.extend_from_slice(&[0, 0]);
+    encryptor.apply(&mut answer);
+    write(&mut sock, &answer)?;
+
+    let mut stream = if selected == CRYPTO_RC4 {
+        MseStream { inner: sock, read_cipher: Some(decryptor), write_cipher: Some(encryptor), pending: Vec::new() }
+    } else {
+        MseStream::plaintext(sock)
+    };
+    //the initial payload is usually the start of the BitTorrent handshake
+    stream.pending = payload;
+    Ok((stream, Some(info_hash)))
+}
+
+///Reads until the given pattern turns up, giving up if it isn't found after
+///skipping max_skip bytes
+fn sync<R: Read>(src: &mut R, pattern: &[u8], max_skip: usize) -> BoostResult<()> {
+    let mut window = read(src, pattern.len())?;
+    for _ in 0..max_skip {
+        if window == pattern {
+            return Ok(())
+        }
+        window.remove(0);
+        window.extend(read(src, 1)?);
+    }
+    if window == pattern {
+        Ok(())
+    } else {
+        Err(BoostError::MSEProtocolErr(String::from("Could not find the start of the encrypted stream")))
+    }
+}
+
+///Reads exactly len bytes
+fn read<R: Read>(src: &mut R, len: usize) -> BoostResult<Vec<u8>> {
+    let mut buf = vec![0u8; len];
+    src.read_exact(&mut buf).map_err(|_| BoostError::BitTorrentTCPRecvErr)?;
+    Ok(buf)
+}
+
+///Writes all of buf
+fn write<W: Write>(dest: &mut W, buf: &[u8]) -> BoostResult<()> {
+    dest.write_all(buf).map_err(|_| BoostError::BitTorrentTCPSendErr)
+}
+
+///Between 0 and the maximum bytes of random padding
+fn random_pad() -> Vec<u8> {
+    let mut rng = rand::thread_rng();
+    let len = rng.gen_range(0, MAX_PAD_LEN + 1);
+    rng.gen_iter::<u8>().take(len).collect()
+}
+
+///SHA1 of all the parts one after the other
+fn hash(parts: &[&[u8]]) -> [u8; 20] {
+    let mut hasher = Sha1::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.digest().bytes()
+}
+
+///Generates a random private key and the public key that goes with it
+fn key_pair() -> ([u8; PRIVATE_KEY_LEN], [u8; KEY_LEN]) {
+    let private_key = rand::random::<[u8; PRIVATE_KEY_LEN]>();
+    let public_key = to_bytes(&pow_mod(&from_bytes(&[2]), &private_key, &from_bytes(&PRIME)));
+    (private_key, public_key)
+}
+
+///Works out the shared secret from the other side's public key and our private key
+fn shared_secret(their_key: &[u8], private_key: &[u8]) -> BoostResult<[u8; KEY_LEN]> {
+    let prime = from_bytes(&PRIME);
+    let their_key = from_bytes(their_key);
+    //keys outside 2..p-2 would give away the secret
+    let mut upper = prime;
+    sub(&mut upper, &from_bytes(&[1]));
+    if !less_than(&from_bytes(&[1]), &their_key) || !less_than(&their_key, &upper) {
+        return Err(BoostError::MSEProtocolErr(String::from("Peer's public key was out of range")))
+    }
+    Ok(to_bytes(&pow_mod(&their_key, private_key, &prime)))
+}
+
+///The 32 bit limbs of a 768 bit number, least significant first
+const LIMBS: usize = KEY_LEN / 4;
+type BigNum = [u32; LIMBS];
+
+///Reads a big endian number of at most 96 bytes
+fn from_bytes(bytes: &[u8]) -> BigNum {
+    let mut num = [0u32; LIMBS];
+    for (idx, &byte) in bytes.iter().rev().enumerate() {
+        num[idx / 4] |= (byte as u32) << (8 * (idx % 4));
+    }
+    num
+}
+
+///Writes a number out as 96 big endian bytes
+fn to_bytes(num: &BigNum) -> [u8; KEY_LEN] {
+    let mut bytes = [0u8; KEY_LEN];
+    for (idx, byte) in bytes.iter_mut().rev().enumerate() {
+        *byte = (num[idx / 4] >> (8 * (idx % 4))) as u8;
+    }
+    bytes
+}
+
+///tells whether a < b. b may have fewer limbs than a
+fn less_than(a: &[u32], b: &[u32]) -> bool {
+    for idx in (0..a.len()).rev() {
+        let b_limb = if idx < b.len() { b[idx] } else { 0 };
+        if a[idx] != b_limb {
+            return a[idx] < b_limb
+        }
+    }
+    false
+}
+
+///a -= b, where b <= a. b may have fewer limbs than a
+fn sub(a: &mut [u32], b: &[u32]) {
+    let mut borrow = 0i64;
+    for idx in 0..a.len() {
+        let b_limb = if idx < b.len() { b[idx] as i64 } else { 0 };
+        let diff = a[idx] as i64 - b_limb - borrow;
+        borrow = if diff < 0 { 1 } else { 0 };
+        a[idx] = diff as u32;
+    }
+}
+
+///(a * b) mod m
+fn mul_mod(a: &BigNum, b: &BigNum, m: &BigNum) -> BigNum {
+    let mut product = [0u32; LIMBS * 2];
+    for i in 0..LIMBS {
+        let mut carry = 0u64;
+        for j in 0..LIMBS {
+            let limb = product[i + j] as u64 + a[i] as u64 * b[j] as u64 + carry;
+            product[i + j] = limb as u32;
+            carry = limb >> 32;
+        }
+        product[i + LIMBS] = carry as u32;
+    }
+    //long division one bit at a time. The remainder stays below 2m, so one extra limb is enough
+    let mut rem = [0u32; LIMBS + 1];
+    for bit in (0..LIMBS * 2 * 32).rev() {
+        let mut carry = (product[bit / 32] >> (bit % 32)) & 1;
+        for limb in rem.iter_mut() {
+            let next = *limb >> 31;
+            *limb = (*limb << 1) | carry;
+            carry = next;
+        }
+        if !less_than(&rem, m) {
+            sub(&mut rem, m);
+        }
+    }
+    let mut result = [0u32; LIMBS];
+    result.copy_from_slice(&rem[0..LIMBS]);
+    result
+}
+
+///(base ^ exp) mod m, exp given as big endian bytes
+fn pow_mod(base: &BigNum, exp: &[u8], m: &BigNum) -> BigNum {
+    let mut result = from_bytes(&[1]);
+    for byte in exp {
+        for shift in (0..8).rev() {
+            result = mul_mod(&result, &result, m);
+            if byte >> shift & 1 == 1 {
+                result = mul_mod(&result, base, m);
+            }
+        }
+    }
+    result
+}
+
+///The RC4 stream cipher, with the start of the keystream thrown away
+#[derive(Clone)]
+struct Rc4 {
+    state: [u8; 256],
+    i: u8,
+    j: u8
+}
+
+impl Rc4 {
+    fn new(key: &[u8]) -> Self {
+        let mut state = [0u8; 256];
+        for (idx, byte) in state.iter_mut().enumerate() {
+            *byte = idx as u8;
+        }
+        let mut j = 0u8;
+        for i in 0..256 {
+            j = j.wrapping_add(state[i]).wrapping_add(key[i % key.len()]);
+            state.swap(i, j as usize);
+        }
+        let mut rc4 = Rc4 { state, i: 0, j: 0 };
+        rc4.apply(&mut [0u8; RC4_DISCARD]);
+        rc4
+    }
+
+    ///encrypts or decrypts data in place
+    fn apply(&mut self, data: &mut [u8]) {
+        for byte in data.iter_mut() {
+            self.i = self.i.wrapping_add(1);
+            self.j = self.j.wrapping_add(self.state[self.i as usize]);
+            self.state.swap(self.i as usize, self.j as usize);
+            let idx = self.state[self.i as usize].wrapping_add(self.state[self.j as usize]);
+            *byte ^= self.state[idx as usize];
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::thread;
+
+    ///One end of an in-memory connection. Reads block until the other end writes
+    struct Pipe {
+        sender: mpsc::Sender<Vec<u8>>,
+        receiver: mpsc::Receiver<Vec<u8>>,
+        buf: Vec<u8>
+    }
+
+    fn pipe() -> (Pipe, Pipe) {
+        let (a_sender, b_receiver) = mpsc::channel();
+        let (b_sender, a_receiver) = mpsc::channel();
+        (Pipe { sender: a_sender, receiver: a_receiver, buf: Vec::new() },
+         Pipe { sender: b_sender, receiver: b_receiver, buf: Vec::new() })
+    }
+
+    impl Read for Pipe {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.buf.is_empty() {
+                match self.receiver.recv() {
+                    Ok(bytes) => self.buf = bytes,
+                    //the other end hung up
+                    Err(_) => return Ok(0)
+                }
+            }
+            let len = ::std::cmp::min(buf.len(), self.buf.len());
+            buf[0..len].copy_from_slice(&self.buf[0..len]);
+            self.buf.drain(0..len);
+            Ok(len)
+        }
+    }
+
+    impl Write for Pipe {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.sender.send(buf.to_vec()).map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "other end hung up"))?;
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn both_sides_get_the_same_secret() {
+        let (a_private, a_public) = key_pair();
+        let (b_private, b_public) = key_pair();
+        assert!(a_public[..] != b_public[..]);
+        let a_secret = shared_secret(&b_public, &a_private).ok().unwrap();
+        let b_secret = shared_secret(&a_public, &b_private).ok().unwrap();
+        assert_eq!(&a_secret[..], &b_secret[..]);
+
+        //keys that would give the secret away are refused
+        assert!(shared_secret(&to_bytes(&from_bytes(&[1])), &a_private).is_err());
+        let mut upper = from_bytes(&PRIME);
+        sub(&mut upper, &from_bytes(&[1]));
+        assert!(shared_secret(&to_bytes(&upper), &a_private).is_err());
+    }
+
+    #[test]
+    fn modular_arithmetic() {
+        let prime = from_bytes(&PRIME);
+        assert_eq!(&to_bytes(&pow_mod(&from_bytes(&[2]), &[10], &prime))[KEY_LEN - 2..], &[0x04, 0x00]);
+        //Fermat: 2^(p-1) = 1 mod p
+        let mut exp = prime;
+        sub(&mut exp, &from_bytes(&[1]));
+        assert!(pow_mod(&from_bytes(&[2]), &to_bytes(&exp), &prime) == from_bytes(&[1]));
+    }
+
+    #[test]
+    fn rc4_skips_the_start_of_the_keystream() {
+        //RFC 6229, 40 bit key, the keystream from offset 1024
+        let mut rc4 = Rc4::new(&[0x01, 0x02, 0x03, 0x04, 0x05]);
+        let mut keystream = [0u8; 16];
+        rc4.apply(&mut keystream);
+        assert_eq!(keystream, [0x30, 0xab, 0xbc, 0xc7, 0xc2, 0x0b, 0x01, 0x60, 0x9f, 0x23, 0xee, 0x2d, 0x5f, 0x6b, 0xb7, 0xdf]);
+
+        //the same key undoes it
+        let mut data = *b"BitTorrent protocol";
+        Rc4::new(b"key").apply(&mut data);
+        assert!(&data[..] != b"BitTorrent protocol");
+        Rc4::new(b"key").apply(&mut data);
+        assert_eq!(&data[..], b"BitTorrent protocol");
+    }
+
+    #[test]
+    fn encrypted_handshake() {
+        let info_hash = [0xaa; 20];
+        let (initiator, acceptor) = pipe();
+        let accepting = thread::spawn(move || {
+            let (mut stream, asked_for) = accept(acceptor, &[[0xbb; 20], info_hash], EncryptionPolicy::Preferred).ok().unwrap();
+            assert!(asked_for == Some(info_hash));
+            assert!(stream.is_encrypted());
+            let mut hello = [0u8; 5];
+            stream.read_exact(&mut hello).unwrap();
+            assert_eq!(&hello, b"hello");
+            stream.write_all(b"world").unwrap();
+        });
+        let mut stream = connect(initiator, &info_hash, EncryptionPolicy::Required).ok().unwrap();
+        assert!(stream.is_encrypted());
+        stream.write_all(b"hello").unwrap();
+        let mut world = [0u8; 5];
+        stream.read_exact(&mut world).unwrap();
+        assert_eq!(&world, b"world");
+        accepting.join().unwrap();
+    }
+
+    #[test]
+    fn plaintext_handshakes() {
+        let (initiator, acceptor) = pipe();
+        let accepting = thread::spawn(move || {
+            let (mut stream, asked_for) = accept(acceptor, &[[0xaa; 20]], EncryptionPolicy::Preferred).ok().unwrap();
+            assert!(asked_for.is_none());
+            assert!(!stream.is_encrypted());
+            //the start of the handshake is handed back
+            let mut start = vec![0u8; PLAINTEXT_HEADER.len()];
+            stream.read_exact(&mut start).unwrap();
+            assert_eq!(&start[..], PLAINTEXT_HEADER);
+        });
+        let mut stream = connect(initiator, &[0xaa; 20], EncryptionPolicy::Disabled).ok().unwrap();
+        assert!(!stream.is_encrypted());
+        stream.write_all(PLAINTEXT_HEADER).unwrap();
+        accepting.join().unwrap();
+
+        //plaintext isn't good enough when encryption is required
+        let (mut initiator, acceptor) = pipe();
+        initiator.write_all(PLAINTEXT_HEADER).unwrap();
+        assert!(accept(acceptor, &[[0xaa; 20]], EncryptionPolicy::Required).is_err());
+    }
+
+    #[test]
+    fn refuses_torrents_we_do_not_have() {
+        let (initiator, acceptor) = pipe();
+        let accepting = thread::spawn(move || accept(acceptor, &[[0xbb; 20]], EncryptionPolicy::Preferred).is_err());
+        //the acceptor hangs up, so the initiator fails too
+        assert!(connect(initiator, &[0xaa; 20], EncryptionPolicy::Required).is_err());
+        assert!(accepting.join().unwrap());
+    }
+
+    ///A busy non-blocking socket: takes a few bytes at a time and refuses every other write
+    struct Trickle {
+        taken: Vec<u8>,
+        refuse: bool
+    }
+
+    impl Write for Trickle {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.refuse = !self.refuse;
+            if !self.refuse {
+                return Err(io::Error::new(io::ErrorKind::WouldBlock, "socket is full"))
+            }
+            let len = ::std::cmp::min(7, buf.len());
+            self.taken.extend_from_slice(&buf[0..len]);
+            Ok(len)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn partial_writes_keep_the_keystream_in_step() {
+        let inner = Trickle { taken: Vec::new(), refuse: false };
+        let mut stream = MseStream { inner, read_cipher: Some(Rc4::new(b"keyB")), write_cipher: Some(Rc4::new(b"keyA")), pending: Vec::new() };
+        let data: Vec<u8> = (0..100u8).collect();
+        let mut written = 0;
+        while written < data.len() {
+            match stream.write(&data[written..]) {
+                Ok(len) => written += len,
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => (),
+                Err(err) => panic!("{}", err)
+            }
+        }
+        let mut received = stream.inner.taken.clone();
+        assert_eq!(received.len(), data.len());
+        Rc4::new(b"keyA").apply(&mut received);
+        assert_eq!(received, data);
+    }
+}
@@ -1,10 +1,15 @@
 use bitvector::BitVector;
-use std::net::TcpStream;
-use byteorder::{NetworkEndian, ByteOrder};
-use std::io::{Read, Write};
-use std::str;
+use std::net::{TcpStream, SocketAddrV4};
+use std::io::{ErrorKind, Read, Write};
 use error::{BoostError, BoostResult};
 use message::BitTorrentMessage;
+use extension::{self, ExtensionIds, ExtensionRegistry, ExtendedHandshake};
+use fast;
+use peerid;
+use mse::MseStream;
+use pipeline::RequestPipeline;
+use std::time::{Duration, Instant};
+use std::fmt;
 
 bitflags! {
     pub struct PeerFlags: u32 {
@@ -16,87 +21,694 @@ bitflags! {
     }
 }
 
+bitflags! {
+    ///The optional protocol features a peer advertised in its handshake's reserved bytes
+    pub struct Capabilities: u32 {
+        const EXTENSION_PROTOCOL = 0b00000001;
+        const FAST_EXTENSION     = 0b00000010;
+        const DHT                = 0b00000100;
+    }
+}
+
+///The byte and bit of the reserved handshake bytes that advertise a DHT node
+const DHT_RESERVED_BYTE: usize = 7;
+const DHT_RESERVED_BIT: u8 = 0x01;
 
-///A struct that represents a connected peer
-pub struct Peer {
+
+///A struct that represents a connected peer. The socket can be anything that
+///can be read from and written to, normally a TcpStream that may be encrypted
+pub struct Peer<S = MseStream<TcpStream>> {
     pub id: [u8; 20],
-    pub socket: TcpStream,
+    pub socket: S,
+    ///the address we connected to, or the address the peer connected from
+    pub addr: Option<SocketAddrV4>,
     bytes_sent: u32,
     bytes_received: u32,
     bit_vector: BitVector,
     flags: PeerFlags,
-    pending_requests: u32
+    ///the blocks we asked this peer for that haven't arrived yet
+    requests: RequestPipeline,
+    ///requests we took back, whose blocks may already have been on their way
+    withdrawn: Vec<(u32, u32, u32)>,
+    ///how many blocks this peer sent that we never asked for
+    unrequested_blocks: u32,
+    capabilities: Capabilities,
+    extension_ids: ExtensionIds,
+    listen_port: Option<u16>,
+    ///requests this peer sent us that have not been served yet
+    requests_from_peer: Vec<(u32, u32, u32)>,
+    ///pieces this peer may request while we choke it
+    allowed_fast: Vec<u32>,
+    ///pieces we may request while this peer chokes us
+    allowed_fast_for_us: Vec<u32>,
+    ///pieces this peer suggested we download
+    suggested: Vec<u32>,
+    ///bytes read from the socket that don't make up a whole message yet
+    read_buf: Vec<u8>,
+    ///bytes of messages the socket wasn't ready to take yet
+    write_buf: Vec<u8>,
+    ///block bytes we got from and sent to this peer since the choker last looked
+    downloaded_since_choke: usize,
+    uploaded_since_choke: usize,
+    ///when this peer last sent us a block, or connected if it never has
+    last_block_at: Instant,
+    connected_at: Instant
 }
 
-impl Peer {
-    ///Takes a freshly created tcp socket, as well as this client's id, the info hash, the
-    ///number of pieces and whether this connection is incoming or outgoing,
-    ///and performs the handshake, and starts the connection!
-    pub fn start_session(mut sock: TcpStream, my_id: &[u8], info_hash: &[u8], num_pieces: usize, incoming: bool) -> BoostResult<Self> {
-        let mut handshake_buf = Vec::new();
-
-        //proto string len
-        handshake_buf.push(19);
-        //proto string
-        handshake_buf.extend_from_slice("BitTorrent protocol".as_bytes());
-        //reserved bytes (all zeros)
-        handshake_buf.extend_from_slice(&[0;8]);
-        //info hash
-        if info_hash.len() == 20 {
-            handshake_buf.extend_from_slice(info_hash);
-        } else {
+///How long a peer we want pieces from can go without sending a block before it is snubbing us
+pub const SNUB_SECS: u64 = 60;
+///How long a peer counts as newly connected
+pub const NEW_PEER_SECS: u64 = 60;
+///How long to wait for a TCP connection to a peer to open
+pub const CONNECT_TIMEOUT_SECS: u64 = 10;
+///How long to wait for a peer's handshake before giving up on it
+pub const HANDSHAKE_TIMEOUT_SECS: u64 = 20;
+
+///The largest block a peer may request. The spec says peers asking for more
+///than 128 KiB should be disconnected
+pub const MAX_REQUEST_LEN: u32 = 128 * 1024;
+///How many requests a peer may have queued with us, advertised as reqq in our extended handshake
+pub const MAX_QUEUED_REQUESTS: u32 = 250;
+///How many blocks a peer may send us without being asked before it is disconnected
+pub const MAX_UNREQUESTED_BLOCKS: u32 = 16;
+///How many withdrawn requests are remembered, so their blocks still count as requested
+const MAX_WITHDRAWN: usize = 250;
+
+const PROTOCOL_STRING: &[u8] = b"BitTorrent protocol";
+const HANDSHAKE_LEN: usize = 68;
+
+
+///The fields of a BitTorrent handshake
+pub struct Handshake {
+    pub capabilities: Capabilities,
+    pub reserved: [u8; 8],
+    pub info_hash: [u8; 20],
+    pub peer_id: [u8; 20]
+}
+
+impl Handshake {
+    ///Creates our handshake for the given torrent
+    pub fn ours(info_hash: &[u8], my_id: &[u8]) -> BoostResult<Self> {
+        if info_hash.len() != 20 {
             return Err(BoostError::BitTorrentProtocolErr(format!("Info hash has incorrect length: {} != 20", info_hash.len())))
         }
-        //my id
-        if my_id.len() == 20 {
-            handshake_buf.extend_from_slice(my_id);
-        } else {
+        if my_id.len() != 20 {
             return Err(BoostError::BitTorrentProtocolErr(format!("Id has incorrect length: {} != 20", my_id.len())))
         }
+        //the capabilities we advertise
+        let capabilities = EXTENSION_PROTOCOL | FAST_EXTENSION;
+        let mut handshake = Handshake { capabilities, reserved: capabilities.reserved_bytes(), info_hash: [0; 20], peer_id: [0; 20] };
+        handshake.info_hash.copy_from_slice(info_hash);
+        handshake.peer_id.copy_from_slice(my_id);
+        Ok(handshake)
+    }
+
+    ///Sends this handshake
+    pub fn send<W: Write>(&self, dest: &mut W) -> BoostResult<()> {
+        let mut handshake_buf = Vec::with_capacity(HANDSHAKE_LEN);
+        //proto string len and proto string
+        handshake_buf.push(PROTOCOL_STRING.len() as u8);
+        handshake_buf.extend_from_slice(PROTOCOL_STRING);
+        handshake_buf.extend_from_slice(&self.reserved);
+        handshake_buf.extend_from_slice(&self.info_hash);
+        handshake_buf.extend_from_slice(&self.peer_id);
+        dest.write_all(handshake_buf.as_slice()).map_err(|_| BoostError::BitTorrentTCPSendErr)
+    }
 
+    ///Reads a whole handshake and checks the protocol string
+    pub fn recv<R: Read>(src: &mut R) -> BoostResult<Self> {
+        let mut handshake_buf = [0u8; HANDSHAKE_LEN];
+        src.read_exact(&mut handshake_buf).map_err(|_| BoostError::BitTorrentTCPRecvErr)?;
+        if handshake_buf[0] as usize != PROTOCOL_STRING.len() {
+            return Err(BoostError::BitTorrentProtocolErr(format!("Recieved handshake protocol string length was {} != 19", handshake_buf[0])))
+        }
+        if &handshake_buf[1..20] != PROTOCOL_STRING {
+            return Err(BoostError::BitTorrentProtocolErr(String::from("Proto string was not correct")))
+        }
+        let mut handshake = Handshake { capabilities: Capabilities::empty(), reserved: [0; 8], info_hash: [0; 20], peer_id: [0; 20] };
+        handshake.reserved.copy_from_slice(&handshake_buf[20..28]);
+        handshake.capabilities = Capabilities::from_reserved(&handshake.reserved);
+        handshake.info_hash.copy_from_slice(&handshake_buf[28..48]);
+        handshake.peer_id.copy_from_slice(&handshake_buf[48..68]);
+        Ok(handshake)
+    }
+}
+
+impl Capabilities {
+    ///Works out the capabilities from the reserved bytes of a handshake
+    pub fn from_reserved(reserved: &[u8; 8]) -> Self {
+        let mut capabilities = Capabilities::empty();
+        capabilities.set(EXTENSION_PROTOCOL, reserved[extension::RESERVED_BYTE] & extension::RESERVED_BIT != 0);
+        capabilities.set(FAST_EXTENSION, reserved[fast::RESERVED_BYTE] & fast::RESERVED_BIT != 0);
+        capabilities.set(DHT, reserved[DHT_RESERVED_BYTE] & DHT_RESERVED_BIT != 0);
+        capabilities
+    }
+
+    ///Sets the reserved bits for these capabilities
+    pub fn reserved_bytes(&self) -> [u8; 8] {
+        let mut reserved = [0u8; 8];
+        if self.contains(EXTENSION_PROTOCOL) {
+            reserved[extension::RESERVED_BYTE] |= extension::RESERVED_BIT;
+        }
+        if self.contains(FAST_EXTENSION) {
+            reserved[fast::RESERVED_BYTE] |= fast::RESERVED_BIT;
+        }
+        if self.contains(DHT) {
+            reserved[DHT_RESERVED_BYTE] |= DHT_RESERVED_BIT;
+        }
+        reserved
+    }
+}
+
+impl<S: Read + Write> Peer<S> {
+    ///Takes a freshly created socket, as well as this client's id, the info hash, the
+    ///number of pieces, the id the tracker said the peer has (if any) and whether this
+    ///connection is incoming or outgoing, and performs the handshake, and starts the connection!
+    ///Any timeouts should be set on the socket beforehand
+    pub fn start_session(mut sock: S, my_id: &[u8], info_hash: &[u8], num_pieces: usize, expected_id: Option<[u8; 20]>, incoming: bool) -> BoostResult<Self> {
         //send handshake
-        let _ = sock.write(handshake_buf.as_slice()).map_err(|_| BoostError::BitTorrentTCPSendErr)?;
+        Handshake::ours(info_hash, my_id)?.send(&mut sock)?;
 
         //recieve handshake
-        let _ = sock.read(handshake_buf.as_mut_slice()).map_err(|_| BoostError::BitTorrentTCPRecvErr)?;
-
-        //check protocol string
-        if handshake_buf[0] == 19 {
-            if "BitTorrent protocol" == str::from_utf8(&handshake_buf[1..20]).map_err(|_| BoostError::BitTorrentProtocolErr(String::from("Protocol string was not a string")))? {
-                //check info hash
-                if info_hash == &handshake_buf[28..48] {
-                    let mut id = [0u8; 20];
-                    let mut flags = PeerFlags::empty();
-                    flags.set(INCOMING, incoming);
-                    //get peer id
-                    for idx in 0..20 {
-                       id[idx] = handshake_buf[idx+48];
-                    }
-                    Ok(Peer {id, socket: sock, bytes_sent: 0, bytes_received: 0, bit_vector: BitVector::new(num_pieces), flags: flags, pending_requests: 0})
+        let theirs = Handshake::recv(&mut sock)?;
+        //check info hash
+        if info_hash != theirs.info_hash {
+            return Err(BoostError::BitTorrentProtocolErr(String::from("Info hash was not correct")))
+        }
+        Peer::from_handshake(sock, my_id, theirs, num_pieces, expected_id, incoming)
+    }
+
+    ///Takes the socket of a peer that connected to us and reads its handshake before
+    ///sending ours, so we know which torrent it wants. torrent_pieces gives the
+    ///number of pieces in the torrent with the given info hash, or None if we
+    ///don't have it. Returns the peer along with the info hash it asked for
+    pub fn accept_session<F>(mut sock: S, my_id: &[u8], torrent_pieces: F) -> BoostResult<(Self, [u8; 20])>
+        where F: Fn(&[u8; 20]) -> Option<usize> {
+        //recieve handshake
+        let theirs = Handshake::recv(&mut sock)?;
+        let info_hash = theirs.info_hash;
+        let num_pieces = torrent_pieces(&info_hash)
+            .ok_or_else(|| BoostError::BitTorrentProtocolErr(String::from("Peer asked for a torrent we don't have")))?;
+
+        //send handshake
+        Handshake::ours(&info_hash, my_id)?.send(&mut sock)?;
+        let peer = Peer::from_handshake(sock, my_id, theirs, num_pieces, None, true)?;
+        Ok((peer, info_hash))
+    }
+
+    ///Creates the peer once handshakes have been swapped, making sure we didn't
+    ///connect to ourselves or to someone other than who we expected
+    fn from_handshake(sock: S, my_id: &[u8], theirs: Handshake, num_pieces: usize, expected_id: Option<[u8; 20]>, incoming: bool) -> BoostResult<Self> {
+        if my_id == theirs.peer_id {
+            return Err(BoostError::BitTorrentProtocolErr(String::from("Connected to ourselves")))
+        }
+        if let Some(expected_id) = expected_id {
+            if expected_id != theirs.peer_id {
+                return Err(BoostError::BitTorrentProtocolErr(String::from("Peer id was not the one the tracker gave")))
+            }
+        }
+        //both sides start out choked
+        let mut flags = CHOKED | CHOKING;
+        flags.set(INCOMING, incoming);
+        Ok(Peer {id: theirs.peer_id, socket: sock, addr: None, bytes_sent: 0, bytes_received: 0, bit_vector: BitVector::new(num_pieces), flags,
+                 requests: RequestPipeline::new(), withdrawn: Vec::new(), unrequested_blocks: 0, capabilities: theirs.capabilities, extension_ids: ExtensionIds::default(), listen_port: None,
+                 requests_from_peer: Vec::new(), allowed_fast: Vec::new(), allowed_fast_for_us: Vec::new(), suggested: Vec::new(),
+                 read_buf: Vec::new(), write_buf: Vec::new(), downloaded_since_choke: 0, uploaded_since_choke: 0,
+                 last_block_at: Instant::now(), connected_at: Instant::now()})
+    }
+
+    ///Reads everything the socket has for us and returns the whole messages in it,
+    ///keeping any partial message for next time. The socket must be non-blocking.
+    ///If there is some kind of IO error, this peer should be removed from the active peers
+    pub fn read_messages(&mut self) -> BoostResult<Vec<BitTorrentMessage>> {
+        let mut buf = [0u8; 16 * 1024];
+        loop {
+            match self.socket.read(&mut buf) {
+                //the peer hung up
+                Ok(0) => return Err(BoostError::BitTorrentTCPRecvErr),
+                Ok(len) => {
+                    self.read_buf.extend_from_slice(&buf[0..len]);
+                    self.bytes_received = self.bytes_received.wrapping_add(len as u32);
+                },
+                Err(ref err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(ref err) if err.kind() == ErrorKind::Interrupted => (),
+                Err(_) => return Err(BoostError::BitTorrentTCPRecvErr)
+            }
+        }
+
+        let mut messages = Vec::new();
+        let mut start = 0;
+        while let Some((message, len)) = BitTorrentMessage::decode(&self.read_buf[start..])? {
+            if message.is_fast_extension() && !self.supports_fast() {
+                return Err(BoostError::BitTorrentProtocolErr(format!("Got fast extension message '{}' without negotiating it", message)))
+            }
+            messages.push(message);
+            start += len;
+        }
+        self.read_buf.drain(0..start);
+        Ok(messages)
+    }
+
+    ///Sends the given message to this peer, or as much of it as the socket will
+    ///take right now. The rest goes out with flush. If there is some kind of IO
+    ///error, this peer should be removed from the active peers
+    pub fn send_message(&mut self, message: BitTorrentMessage) -> BoostResult<()> {
+        self.write_buf.extend(message.encode());
+        self.flush()
+    }
+
+    ///Writes out as much of the messages waiting to be sent as the socket will take.
+    ///Should be called whenever the socket becomes writable again
+    pub fn flush(&mut self) -> BoostResult<()> {
+        let mut written = 0;
+        while written < self.write_buf.len() {
+            match self.socket.write(&self.write_buf[written..]) {
+                Ok(0) => return Err(BoostError::BitTorrentTCPSendErr),
+                Ok(len) => written += len,
+                Err(ref err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(ref err) if err.kind() == ErrorKind::Interrupted => (),
+                Err(_) => return Err(BoostError::BitTorrentTCPSendErr)
+            }
+        }
+        self.write_buf.drain(0..written);
+        self.bytes_sent = self.bytes_sent.wrapping_add(written as u32);
+        Ok(())
+    }
+
+    ///Tells this peer which pieces we have. With the fast extension, the special
+    ///cases of everything and nothing get their own messages
+    pub fn send_have_set(&mut self, completed: &BitVector) -> BoostResult<()> {
+        if self.supports_fast() && completed.is_complete() {
+            self.send_message(BitTorrentMessage::HaveAll)
+        } else if self.supports_fast() && completed.is_empty() {
+            self.send_message(BitTorrentMessage::HaveNone)
+        } else {
+            self.send_message(BitTorrentMessage::Bitfield(completed.clone()))
+        }
+    }
+
+    ///Works out which pieces this peer may request while choked and tells it.
+    ///Does nothing if the peer doesn't support the fast extension or we don't know its address
+    pub fn send_allowed_fast(&mut self, info_hash: &[u8], num_pieces: usize) -> BoostResult<()> {
+        if let (true, Some(addr)) = (self.supports_fast(), self.addr) {
+            self.allowed_fast = fast::allowed_fast_set(*addr.ip(), info_hash, num_pieces, fast::ALLOWED_FAST_COUNT);
+            for idx in 0..self.allowed_fast.len() {
+                let piece = self.allowed_fast[idx];
+                self.send_message(BitTorrentMessage::AllowedFast(piece))?;
+            }
+        }
+        Ok(())
+    }
+
+    ///Chokes this peer. Requests it already sent us will never be served, so
+    ///with the fast extension they are explicitly rejected, except for pieces in
+    ///its allowed fast set. Without it, the peer knows they are dropped
+    pub fn choke(&mut self) -> BoostResult<()> {
+        self.flags.insert(CHOKING);
+        self.send_message(BitTorrentMessage::Choke)?;
+        let requests = ::std::mem::take(&mut self.requests_from_peer);
+        for (piece_index, begin, length) in requests {
+            if self.supports_fast() {
+                if self.allowed_fast.contains(&piece_index) {
+                    self.requests_from_peer.push((piece_index, begin, length));
                 } else {
-                    Err(BoostError::BitTorrentProtocolErr(String::from("Info hash was not
-                    correct")))
+                    self.send_message(BitTorrentMessage::RejectRequest { piece_index, begin, length })?;
                 }
-            } else {
-                Err(BoostError::BitTorrentProtocolErr(String::from("Proto string was not
-                                                                   correct")))
             }
+        }
+        Ok(())
+    }
+
+    ///Unchokes this peer so it may request pieces
+    pub fn unchoke(&mut self) -> BoostResult<()> {
+        self.flags.remove(CHOKING);
+        self.send_message(BitTorrentMessage::Unchoke)
+    }
+
+    ///Records a request this peer sent us. Requests while choked are rejected
+    ///with the fast extension, unless the piece is in its allowed fast set,
+    ///and are ignored without it. So are requests past our advertised queue length
+    pub fn queue_request(&mut self, piece_index: u32, begin: u32, length: u32) -> BoostResult<()> {
+        if (self.flags.contains(CHOKING) && !self.allowed_fast.contains(&piece_index))
+            || self.requests_from_peer.len() >= MAX_QUEUED_REQUESTS as usize {
+            self.reject_request(piece_index, begin, length)?;
+        } else if !self.requests_from_peer.contains(&(piece_index, begin, length)) {
+            self.requests_from_peer.push((piece_index, begin, length));
+        }
+        Ok(())
+    }
+
+    ///Tells this peer a request won't be served. Only the fast extension has a
+    ///message for that, without it the request is silently dropped
+    pub fn reject_request(&mut self, piece_index: u32, begin: u32, length: u32) -> BoostResult<()> {
+        if self.supports_fast() {
+            self.send_message(BitTorrentMessage::RejectRequest { piece_index, begin, length })?;
+        }
+        Ok(())
+    }
+
+    ///Sends a block this peer requested
+    pub fn send_block(&mut self, piece_index: u32, begin: u32, block: Vec<u8>) -> BoostResult<()> {
+        self.uploaded_since_choke += block.len();
+        self.send_message(BitTorrentMessage::Piece { piece_index, begin, block })
+    }
+
+    ///Drops a request this peer cancelled. The fast extension requires every
+    ///request to be answered, so cancelled ones get rejected
+    pub fn cancel_request(&mut self, piece_index: u32, begin: u32, length: u32) -> BoostResult<()> {
+        if let Some(idx) = self.requests_from_peer.iter().position(|&req| req == (piece_index, begin, length)) {
+            self.requests_from_peer.remove(idx);
+            if self.supports_fast() {
+                self.send_message(BitTorrentMessage::RejectRequest { piece_index, begin, length })?;
+            }
+        }
+        Ok(())
+    }
+
+    ///Works out whether this peer has any of the pieces we want, and tells it
+    ///if that changed since last time
+    pub fn update_interest(&mut self, wanted: &BitVector) -> BoostResult<()> {
+        let interested = self.bit_vector.intersects(wanted);
+        if interested && !self.flags.contains(INTERESTED_IN_THEM) {
+            self.flags.insert(INTERESTED_IN_THEM);
+            self.send_message(BitTorrentMessage::Interested)
+        } else if !interested && self.flags.contains(INTERESTED_IN_THEM) {
+            self.flags.remove(INTERESTED_IN_THEM);
+            self.send_message(BitTorrentMessage::NotInterested)
         } else {
-            Err(BoostError::BitTorrentProtocolErr(format!("Recieved handshake protocol string length was {} != 19", handshake_buf[0])))
+            Ok(())
         }
     }
 
+    ///Sends a request for a block, counting it as outstanding until the block arrives
+    pub fn request_block(&mut self, piece_index: u32, begin: u32, length: u32) -> BoostResult<()> {
+        self.requests.push(piece_index, begin, length);
+        self.send_message(BitTorrentMessage::Request { piece_index, begin, length })
+    }
 
-    ///blocks listening for a message. If there is some kind of IO error,
-    ///this peer should be removed from the active peers
-    pub fn recv_message(&mut self) -> BoostResult<BitTorrentMessage> {
-        BitTorrentMessage::recv(&mut self.socket)
+    ///Cancels a request we made of this peer, for when the block came from
+    ///someone else. Returns whether we had made it
+    pub fn withdraw_request(&mut self, piece_index: u32, begin: u32, length: u32) -> BoostResult<bool> {
+        if self.requests.remove(piece_index, begin, length) {
+            self.remember_withdrawn(&[(piece_index, begin, length)]);
+            self.send_message(BitTorrentMessage::Cancel { piece_index, begin, length })?;
+            Ok(true)
+        } else {
+            Ok(false)
+        }
     }
 
-    ///Sends the given message to this peer.  If there is some kind of IO error,
-    ///this peer should be removed from the active peers
-    pub fn send_message(&mut self, message: BitTorrentMessage) -> BoostResult<()> {
-        message.send(&mut self.socket)
+    ///Cancels every request we have outstanding with this peer for blocks of the
+    ///given piece, such as once it is skipped
+    pub fn withdraw_piece(&mut self, piece_index: u32) -> BoostResult<()> {
+        let withdrawn = self.requests.remove_piece(piece_index);
+        self.remember_withdrawn(&withdrawn);
+        for &(piece_index, begin, length) in &withdrawn {
+            self.send_message(BitTorrentMessage::Cancel { piece_index, begin, length })?;
+        }
+        Ok(())
+    }
+
+    ///Takes out the requests this peer has taken too long to answer and cancels
+    ///them, returning them so their blocks can be requested again
+    pub fn cancel_timed_out(&mut self) -> BoostResult<Vec<(u32, u32, u32)>> {
+        let expired = self.requests.timed_out();
+        self.remember_withdrawn(&expired);
+        for &(piece_index, begin, length) in &expired {
+            self.send_message(BitTorrentMessage::Cancel { piece_index, begin, length })?;
+        }
+        Ok(expired)
+    }
+
+    ///tells whether this peer set the extension protocol bit in its handshake
+    pub fn supports_extensions(&self) -> bool {
+        self.capabilities.contains(EXTENSION_PROTOCOL)
+    }
+
+    ///Sends our extended handshake. Should only be sent if the peer supports extensions
+    pub fn send_extended_handshake(&mut self, handshake: &ExtendedHandshake) -> BoostResult<()> {
+        self.send_message(BitTorrentMessage::Extended { id: extension::HANDSHAKE_ID, payload: handshake.encode() })
+    }
+
+    ///Sends a message for the named extension, using the id this peer asked for
+    ///in its extended handshake
+    pub fn send_extension_message(&mut self, name: &str, payload: Vec<u8>) -> BoostResult<()> {
+        let id = self.extension_ids.get(name)
+            .ok_or_else(|| BoostError::BitTorrentProtocolErr(format!("Peer does not support the {} extension", name)))?;
+        self.send_message(BitTorrentMessage::Extended { id, payload })
+    }
+
+    ///tells whether this peer can receive messages for the named extension
+    pub fn supports_extension(&self, name: &str) -> bool {
+        self.extension_ids.get(name).is_some()
+    }
+
+    ///Handles an extended message from this peer. Handshakes update the ids this
+    ///peer wants its extension messages sent with, everything else is handed to
+    ///the registered extension with that id
+    pub fn handle_extended(&mut self, id: u8, payload: &[u8], registry: &ExtensionRegistry) -> BoostResult<()> {
+        if id == extension::HANDSHAKE_ID {
+            let handshake = ExtendedHandshake::decode(payload)?;
+            self.extension_ids.update(&handshake);
+            if handshake.listen_port.is_some() {
+                self.listen_port = handshake.listen_port;
+            }
+            if let Some(reqq) = handshake.request_queue {
+                self.requests.set_peer_limit(reqq);
+            }
+            for name in handshake.extensions.keys() {
+                if let Some(ext) = registry.by_name(name) {
+                    if self.supports_extension(name) {
+                        ext.on_handshake(&self.id, &handshake);
+                    }
+                }
+            }
+            Ok(())
+        } else {
+            match registry.by_id(id) {
+                Some(ext) => ext.on_message(&self.id, payload),
+                None => Err(BoostError::BitTorrentProtocolErr(format!("Extended message id {} is not one we gave out", id)))
+            }
+        }
+    }
+
+}
+
+impl<S> Peer<S> {
+    ///Swaps this peer's socket for another one made from it, such as a
+    ///non-blocking version of the same connection
+    pub fn map_socket<T, F>(self, f: F) -> BoostResult<Peer<T>>
+        where F: FnOnce(S) -> BoostResult<T> {
+        Ok(Peer {id: self.id, socket: f(self.socket)?, addr: self.addr, bytes_sent: self.bytes_sent, bytes_received: self.bytes_received,
+                 bit_vector: self.bit_vector, flags: self.flags, requests: self.requests, withdrawn: self.withdrawn,
+                 unrequested_blocks: self.unrequested_blocks, capabilities: self.capabilities,
+                 extension_ids: self.extension_ids, listen_port: self.listen_port, requests_from_peer: self.requests_from_peer,
+                 allowed_fast: self.allowed_fast, allowed_fast_for_us: self.allowed_fast_for_us, suggested: self.suggested,
+                 read_buf: self.read_buf, write_buf: self.write_buf, downloaded_since_choke: self.downloaded_since_choke,
+                 uploaded_since_choke: self.uploaded_since_choke, last_block_at: self.last_block_at, connected_at: self.connected_at})
+    }
+
+    ///how many bytes of messages are still waiting for the socket to take them
+    pub fn send_buffer_len(&self) -> usize {
+        self.write_buf.len()
+    }
+
+    ///Takes the oldest request this peer sent us that is still to be served
+    pub fn next_queued_request(&mut self) -> Option<(u32, u32, u32)> {
+        if self.requests_from_peer.is_empty() {
+            None
+        } else {
+            Some(self.requests_from_peer.remove(0))
+        }
+    }
+
+    ///tells whether this peer set the fast extension bit in its handshake
+    pub fn supports_fast(&self) -> bool {
+        self.capabilities.contains(FAST_EXTENSION)
+    }
+
+    ///Called when this peer chokes us. Without the fast extension, every request we
+    ///had outstanding is dropped, and they are returned so they can be requested
+    ///again. With it, each one will be explicitly rejected
+    pub fn choked_by_peer(&mut self) -> Vec<(u32, u32, u32)> {
+        self.flags.insert(CHOKED);
+        if self.supports_fast() {
+            Vec::new()
+        } else {
+            let dropped = self.requests.clear();
+            self.remember_withdrawn(&dropped);
+            dropped
+        }
+    }
+
+    ///Called when this peer unchokes us, so we may request anything it has
+    pub fn unchoked_by_peer(&mut self) {
+        self.flags.remove(CHOKED);
+    }
+
+    ///Called when this peer tells us whether it wants pieces from us
+    pub fn set_interested_in_me(&mut self, interested: bool) {
+        self.flags.set(INTERESTED_IN_ME, interested);
+    }
+
+    ///Records a piece this peer announced it has
+    pub fn handle_have(&mut self, piece: u32) -> BoostResult<()> {
+        if piece as usize >= self.bit_vector.bit_len() {
+            return Err(BoostError::BitTorrentProtocolErr(format!("Peer has piece {} of only {}", piece, self.bit_vector.bit_len())))
+        }
+        self.bit_vector.set_index(piece as usize);
+        Ok(())
+    }
+
+    ///Takes every piece this peer has from its bitfield. The bitfield must have
+    ///exactly one bit per piece, rounded up to whole bytes, with the spare bits 0
+    pub fn handle_bitfield(&mut self, bitfield: &BitVector) -> BoostResult<()> {
+        self.bit_vector = BitVector::from_bytes(bitfield.as_bytes(), self.bit_vector.bit_len())?;
+        Ok(())
+    }
+
+    ///Called when a block arrives from this peer. Returns whether we asked this
+    ///peer for it, including requests we since cancelled because the block came
+    ///from someone else or took too long. Other blocks are counted against the peer
+    pub fn block_received(&mut self, piece_index: u32, begin: u32, length: u32) -> bool {
+        let requested = if self.requests.block_received(piece_index, begin, length) {
+            true
+        } else if let Some(idx) = self.withdrawn.iter().position(|&req| req == (piece_index, begin, length)) {
+            self.withdrawn.remove(idx);
+            true
+        } else {
+            false
+        };
+        if requested {
+            self.downloaded_since_choke += length as usize;
+            self.last_block_at = Instant::now();
+        } else {
+            self.unrequested_blocks += 1;
+        }
+        requested
+    }
+
+    ///how many blocks this peer sent that we never asked for
+    pub fn unrequested_blocks(&self) -> u32 {
+        self.unrequested_blocks
+    }
+
+    ///Keeps track of requests we took back, forgetting the oldest ones once there are too many
+    fn remember_withdrawn(&mut self, requests: &[(u32, u32, u32)]) {
+        self.withdrawn.extend_from_slice(requests);
+        if self.withdrawn.len() > MAX_WITHDRAWN {
+            let excess = self.withdrawn.len() - MAX_WITHDRAWN;
+            self.withdrawn.drain(0..excess);
+        }
+    }
+
+    ///Called when this peer rejects one of our requests. Returns whether we had made it
+    pub fn request_rejected(&mut self, piece_index: u32, begin: u32, length: u32) -> bool {
+        self.requests.remove(piece_index, begin, length)
+    }
+
+    ///tells whether we asked this peer for the given block and are still waiting for it
+    pub fn has_requested(&self, piece_index: u32, begin: u32, length: u32) -> bool {
+        self.requests.contains(piece_index, begin, length)
+    }
+
+    ///Forgets every request we have outstanding with this peer and returns them,
+    ///for when it is going away
+    pub fn take_requests(&mut self) -> Vec<(u32, u32, u32)> {
+        self.requests.clear()
+    }
+
+    ///tells whether this peer's request queue has room for another request,
+    ///going by how fast it sends and how long it takes to answer
+    pub fn wants_requests(&self) -> bool {
+        self.requests.has_room()
+    }
+
+    ///the pieces this peer has
+    pub fn pieces(&self) -> &BitVector {
+        &self.bit_vector
+    }
+
+    ///tells whether this peer has the given piece
+    pub fn has_piece(&self, piece: u32) -> bool {
+        self.bit_vector.index_isset(piece as usize)
+    }
+
+    ///tells whether we are choking this peer
+    pub fn is_choking(&self) -> bool {
+        self.flags.contains(CHOKING)
+    }
+
+    ///tells whether this peer wants pieces from us
+    pub fn is_interested_in_me(&self) -> bool {
+        self.flags.contains(INTERESTED_IN_ME)
+    }
+
+    ///tells whether this peer has pieces we want but hasn't sent us a block in a long time
+    pub fn is_snubbing(&self) -> bool {
+        self.is_interesting() && self.last_block_at.elapsed() > Duration::from_secs(SNUB_SECS)
+    }
+
+    ///tells whether this peer connected recently
+    pub fn is_new(&self) -> bool {
+        self.connected_at.elapsed() < Duration::from_secs(NEW_PEER_SECS)
+    }
+
+    ///Returns the block bytes we got from and sent to this peer since the last
+    ///call, for the choker to work out rates from
+    pub fn take_transfer_counts(&mut self) -> (usize, usize) {
+        let counts = (self.downloaded_since_choke, self.uploaded_since_choke);
+        self.downloaded_since_choke = 0;
+        self.uploaded_since_choke = 0;
+        counts
+    }
+
+    ///tells whether this peer has pieces we want
+    pub fn is_interesting(&self) -> bool {
+        self.flags.contains(INTERESTED_IN_THEM)
+    }
+
+    ///Updates this peer from one of the fast extension messages
+    pub fn handle_fast_message(&mut self, message: &BitTorrentMessage) {
+        match *message {
+            BitTorrentMessage::HaveAll => self.bit_vector.set_all(),
+            BitTorrentMessage::HaveNone => self.bit_vector.clear(),
+            BitTorrentMessage::SuggestPiece(piece)
+                if (piece as usize) < self.bit_vector.bit_len() && !self.suggested.contains(&piece) => {
+                self.suggested.push(piece);
+            },
+            BitTorrentMessage::AllowedFast(piece)
+                if (piece as usize) < self.bit_vector.bit_len() && !self.allowed_fast_for_us.contains(&piece) => {
+                self.allowed_fast_for_us.push(piece);
+            },
+            _ => ()
+        }
+    }
+
+    ///tells whether we may request the given piece from this peer right now
+    pub fn can_request(&self, piece: u32) -> bool {
+        !self.flags.contains(CHOKED) || self.allowed_fast_for_us.contains(&piece)
+    }
+
+    ///the address other peers can connect to this peer at, if we know it.
+    ///Incoming peers connect from some random port, so we need the listen port
+    ///from their extended handshake
+    pub fn listen_addr(&self) -> Option<SocketAddrV4> {
+        match (self.addr, self.listen_port) {
+            (Some(addr), Some(port)) => Some(SocketAddrV4::new(*addr.ip(), port)),
+            (Some(addr), None) if !self.is_incoming() => Some(addr),
+            _ => None
+        }
+    }
+
+    ///works out which client this peer is running from its peer id
+    pub fn client_name(&self) -> Option<String> {
+        peerid::identify_client(&self.id)
+    }
+
+    ///tells whether this peer has every piece
+    pub fn is_seed(&self) -> bool {
+        self.bit_vector.is_complete()
     }
 
     ///tells whether this peer was created by connecting to it (outgoing)
@@ -104,5 +716,336 @@ impl Peer {
     pub fn is_incoming(&self) -> bool {
         return !(self.flags & INCOMING).is_empty()
     }
+}
 
+///Shows the peer's address, and the client it runs if its id tells us
+impl<S> fmt::Display for Peer<S> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.addr {
+            Some(addr) => write!(f, "{}", addr)?,
+            None => write!(f, "peer at an unknown address")?
+        }
+        match self.client_name() {
+            Some(name) => write!(f, " ({})", name),
+            None => Ok(())
+        }
+    }
+}
+
+impl<S> Peer<MseStream<S>> {
+    ///tells whether the connection to this peer is encrypted
+    pub fn is_encrypted(&self) -> bool {
+        self.socket.is_encrypted()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{self, Cursor};
+
+    const INFO_HASH: [u8; 20] = [0xaa; 20];
+    const MY_ID: [u8; 20] = [1; 20];
+    const THEIR_ID: [u8; 20] = [2; 20];
+
+    ///An in-memory connection. Reads come from what the other side sent and
+    ///would block once it runs out, writes are kept to look at afterwards
+    struct Pipe {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>
+    }
+
+    impl Read for Pipe {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.input.read(buf)? {
+                0 if !buf.is_empty() => Err(io::Error::new(ErrorKind::WouldBlock, "no more input")),
+                len => Ok(len)
+            }
+        }
+    }
+
+    impl Write for Pipe {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    ///the handshake the other side sends, with the fast extension if asked for
+    fn their_handshake(info_hash: [u8; 20], peer_id: [u8; 20], fast: bool) -> Vec<u8> {
+        let capabilities = if fast { FAST_EXTENSION } else { Capabilities::empty() };
+        let handshake = Handshake { capabilities, reserved: capabilities.reserved_bytes(), info_hash, peer_id };
+        let mut buf = Vec::new();
+        handshake.send(&mut buf).ok().unwrap();
+        buf
+    }
+
+    ///a peer for a 16 piece torrent that has already swapped handshakes
+    fn connected(fast: bool) -> Peer<Pipe> {
+        let pipe = Pipe { input: Cursor::new(their_handshake(INFO_HASH, THEIR_ID, fast)), output: Vec::new() };
+        let mut peer = Peer::start_session(pipe, &MY_ID, &INFO_HASH, 16, None, false).ok().unwrap();
+        peer.socket.output.clear();
+        peer
+    }
+
+    ///gives the peer more bytes to read, as if the other side sent them
+    fn receive(peer: &mut Peer<Pipe>, messages: &[BitTorrentMessage]) -> Vec<BitTorrentMessage> {
+        let bytes: Vec<u8> = messages.iter().flat_map(|message| message.encode()).collect();
+        peer.socket.input = Cursor::new(bytes);
+        peer.read_messages().ok().unwrap()
+    }
+
+    ///takes out the messages the peer sent so far
+    fn sent(peer: &mut Peer<Pipe>) -> Vec<BitTorrentMessage> {
+        let output = ::std::mem::take(&mut peer.socket.output);
+        let mut messages = Vec::new();
+        let mut start = 0;
+        while let Some((message, len)) = BitTorrentMessage::decode(&output[start..]).ok().unwrap() {
+            messages.push(message);
+            start += len;
+        }
+        assert_eq!(start, output.len());
+        messages
+    }
+
+    #[test]
+    fn outgoing_handshake() {
+        let pipe = Pipe { input: Cursor::new(their_handshake(INFO_HASH, THEIR_ID, true)), output: Vec::new() };
+        let peer = Peer::start_session(pipe, &MY_ID, &INFO_HASH, 16, Some(THEIR_ID), false).ok().unwrap();
+        let ours = Handshake::recv(&mut Cursor::new(peer.socket.output.clone())).ok().unwrap();
+        assert_eq!(ours.info_hash, INFO_HASH);
+        assert_eq!(ours.peer_id, MY_ID);
+        assert!(ours.capabilities.contains(EXTENSION_PROTOCOL | FAST_EXTENSION));
+        assert_eq!(peer.id, THEIR_ID);
+        assert!(peer.supports_fast());
+        assert!(!peer.supports_extensions());
+        assert!(!peer.is_incoming());
+        assert!(peer.is_choking());
+        assert!(!peer.can_request(0));
+    }
+
+    #[test]
+    fn handshake_rejections() {
+        let wrong_torrent = Pipe { input: Cursor::new(their_handshake([0xbb; 20], THEIR_ID, false)), output: Vec::new() };
+        assert!(Peer::start_session(wrong_torrent, &MY_ID, &INFO_HASH, 16, None, false).is_err());
+        let ourselves = Pipe { input: Cursor::new(their_handshake(INFO_HASH, MY_ID, false)), output: Vec::new() };
+        assert!(Peer::start_session(ourselves, &MY_ID, &INFO_HASH, 16, None, false).is_err());
+        let someone_else = Pipe { input: Cursor::new(their_handshake(INFO_HASH, THEIR_ID, false)), output: Vec::new() };
+        assert!(Peer::start_session(someone_else, &MY_ID, &INFO_HASH, 16, Some([3; 20]), false).is_err());
+        let mut bad_protocol = their_handshake(INFO_HASH, THEIR_ID, false);
+        bad_protocol[1] = b'b';
+        let bad_protocol = Pipe { input: Cursor::new(bad_protocol), output: Vec::new() };
+        assert!(Peer::start_session(bad_protocol, &MY_ID, &INFO_HASH, 16, None, false).is_err());
+    }
+
+    #[test]
+    fn incoming_handshake() {
+        let pipe = Pipe { input: Cursor::new(their_handshake(INFO_HASH, THEIR_ID, false)), output: Vec::new() };
+        let (peer, info_hash) = Peer::accept_session(pipe, &MY_ID, |info_hash| if *info_hash == INFO_HASH { Some(16) } else { None })
+            .ok().unwrap();
+        assert_eq!(info_hash, INFO_HASH);
+        assert!(peer.is_incoming());
+        assert_eq!(peer.pieces().bit_len(), 16);
+        let ours = Handshake::recv(&mut Cursor::new(peer.socket.output.clone())).ok().unwrap();
+        assert_eq!(ours.peer_id, MY_ID);
+
+        //we don't answer for torrents we don't have
+        let pipe = Pipe { input: Cursor::new(their_handshake([0xbb; 20], THEIR_ID, false)), output: Vec::new() };
+        let accepted = Peer::accept_session(pipe, &MY_ID, |info_hash| if *info_hash == INFO_HASH { Some(16) } else { None });
+        assert!(accepted.is_err());
+    }
+
+    #[test]
+    fn reads_whole_messages_only() {
+        let mut peer = connected(false);
+        let mut bytes = BitTorrentMessage::Have(3).encode();
+        let unchoke = BitTorrentMessage::Unchoke.encode();
+        bytes.extend_from_slice(&unchoke[0..2]);
+        peer.socket.input = Cursor::new(bytes);
+        let messages = peer.read_messages().ok().unwrap();
+        assert!(messages == vec![BitTorrentMessage::Have(3)]);
+        peer.socket.input = Cursor::new(unchoke[2..].to_vec());
+        let messages = peer.read_messages().ok().unwrap();
+        assert!(messages == vec![BitTorrentMessage::Unchoke]);
+
+        //without the fast extension its messages are a protocol error
+        peer.socket.input = Cursor::new(BitTorrentMessage::HaveAll.encode());
+        assert!(peer.read_messages().is_err());
+    }
+
+    #[test]
+    fn bitfield_and_have() {
+        let mut peer = connected(false);
+        let mut bitfield = BitVector::new(16);
+        bitfield.set_index(0);
+        bitfield.set_index(15);
+        let messages = receive(&mut peer, &[BitTorrentMessage::Bitfield(bitfield), BitTorrentMessage::Have(7)]);
+        for message in messages {
+            match message {
+                BitTorrentMessage::Bitfield(ref bitfield) => peer.handle_bitfield(bitfield).ok().unwrap(),
+                BitTorrentMessage::Have(piece) => peer.handle_have(piece).ok().unwrap(),
+                _ => panic!("unexpected message {}", message)
+            }
+        }
+        assert!(peer.has_piece(0) && peer.has_piece(7) && peer.has_piece(15));
+        assert!(!peer.has_piece(1));
+        assert_eq!(peer.pieces().count_ones(), 3);
+        assert!(peer.handle_have(16).is_err());
+
+        //a bitfield for another number of pieces
+        assert!(peer.handle_bitfield(&BitVector::new(24)).is_err());
+
+        let mut wanted = BitVector::new(16);
+        wanted.set_index(1);
+        peer.update_interest(&wanted).ok().unwrap();
+        assert!(!peer.is_interesting());
+        wanted.set_index(7);
+        peer.update_interest(&wanted).ok().unwrap();
+        assert!(peer.is_interesting());
+        assert!(sent(&mut peer) == vec![BitTorrentMessage::Interested]);
+    }
+
+    #[test]
+    fn requests_while_choking() {
+        let mut peer = connected(true);
+        //choked peers get rejected
+        peer.queue_request(1, 0, 16384).ok().unwrap();
+        assert!(peer.next_queued_request().is_none());
+        assert!(sent(&mut peer) == vec![BitTorrentMessage::RejectRequest { piece_index: 1, begin: 0, length: 16384 }]);
+
+        peer.unchoke().ok().unwrap();
+        peer.queue_request(1, 0, 16384).ok().unwrap();
+        peer.queue_request(1, 0, 16384).ok().unwrap();
+        peer.queue_request(2, 0, 16384).ok().unwrap();
+        assert!(sent(&mut peer) == vec![BitTorrentMessage::Unchoke]);
+        assert_eq!(peer.next_queued_request(), Some((1, 0, 16384)));
+        assert_eq!(peer.next_queued_request(), Some((2, 0, 16384)));
+        assert!(peer.next_queued_request().is_none());
+    }
+
+    #[test]
+    fn our_requests() {
+        let mut peer = connected(false);
+        peer.unchoked_by_peer();
+        assert!(peer.can_request(3));
+        peer.request_block(3, 0, 16384).ok().unwrap();
+        peer.request_block(3, 16384, 16384).ok().unwrap();
+        assert!(peer.has_requested(3, 0, 16384));
+        assert!(peer.block_received(3, 0, 16384));
+        assert!(!peer.has_requested(3, 0, 16384));
+        //a block we never asked for
+        assert!(!peer.block_received(4, 0, 16384));
+        assert_eq!(peer.unrequested_blocks(), 1);
+        assert_eq!(peer.take_transfer_counts(), (16384, 0));
+
+        //without the fast extension a choke drops what we had outstanding
+        assert!(peer.choked_by_peer() == vec![(3, 16384, 16384)]);
+        assert!(!peer.can_request(3));
+        assert!(!peer.has_requested(3, 16384, 16384));
+        //but the block may have been on its way already
+        assert!(peer.block_received(3, 16384, 16384));
+        assert!(!peer.block_received(3, 16384, 16384));
+        assert_eq!(peer.unrequested_blocks(), 2);
+        assert!(sent(&mut peer) == vec![
+            BitTorrentMessage::Request { piece_index: 3, begin: 0, length: 16384 },
+            BitTorrentMessage::Request { piece_index: 3, begin: 16384, length: 16384 }
+        ]);
+    }
+
+    #[test]
+    fn withdrawing_a_piece_cancels_its_requests() {
+        let mut peer = connected(false);
+        peer.unchoked_by_peer();
+        peer.request_block(3, 0, 16384).ok().unwrap();
+        peer.request_block(5, 0, 16384).ok().unwrap();
+        peer.request_block(3, 16384, 16384).ok().unwrap();
+        let _ = sent(&mut peer);
+        peer.withdraw_piece(3).ok().unwrap();
+        assert!(!peer.has_requested(3, 0, 16384) && !peer.has_requested(3, 16384, 16384));
+        assert!(peer.has_requested(5, 0, 16384));
+        assert!(sent(&mut peer) == vec![
+            BitTorrentMessage::Cancel { piece_index: 3, begin: 0, length: 16384 },
+            BitTorrentMessage::Cancel { piece_index: 3, begin: 16384, length: 16384 }
+        ]);
+        //blocks already on their way aren't held against the peer
+        assert!(peer.block_received(3, 0, 16384));
+        assert_eq!(peer.unrequested_blocks(), 0);
+    }
+
+    #[test]
+    fn choke_rejects_all_but_allowed_fast() {
+        let mut peer = connected(true);
+        peer.addr = Some(SocketAddrV4::new(::std::net::Ipv4Addr::new(80, 4, 4, 200), 6881));
+        peer.send_allowed_fast(&INFO_HASH, 16).ok().unwrap();
+        let allowed: Vec<u32> = sent(&mut peer).into_iter().map(|message| match message {
+            BitTorrentMessage::AllowedFast(piece) => piece,
+            _ => panic!("unexpected message {}", message)
+        }).collect();
+        assert_eq!(allowed.len(), fast::ALLOWED_FAST_COUNT);
+        let not_allowed = (0..16).find(|piece| !allowed.contains(piece)).unwrap();
+
+        peer.unchoke().ok().unwrap();
+        peer.queue_request(allowed[0], 0, 16384).ok().unwrap();
+        peer.queue_request(not_allowed, 0, 16384).ok().unwrap();
+        peer.queue_request(not_allowed, 16384, 16384).ok().unwrap();
+        sent(&mut peer);
+
+        peer.choke().ok().unwrap();
+        assert!(sent(&mut peer) == vec![
+            BitTorrentMessage::Choke,
+            BitTorrentMessage::RejectRequest { piece_index: not_allowed, begin: 0, length: 16384 },
+            BitTorrentMessage::RejectRequest { piece_index: not_allowed, begin: 16384, length: 16384 }
+        ]);
+        assert_eq!(peer.next_queued_request(), Some((allowed[0], 0, 16384)));
+        assert!(peer.next_queued_request().is_none());
+
+        //allowed fast pieces may still be requested while choked
+        peer.queue_request(allowed[1], 0, 16384).ok().unwrap();
+        peer.queue_request(not_allowed, 0, 16384).ok().unwrap();
+        assert!(sent(&mut peer) == vec![BitTorrentMessage::RejectRequest { piece_index: not_allowed, begin: 0, length: 16384 }]);
+        assert_eq!(peer.next_queued_request(), Some((allowed[1], 0, 16384)));
+    }
+
+    #[test]
+    fn cancel_is_answered_with_reject() {
+        let mut peer = connected(true);
+        peer.unchoke().ok().unwrap();
+        peer.queue_request(1, 0, 16384).ok().unwrap();
+        sent(&mut peer);
+        peer.cancel_request(1, 0, 16384).ok().unwrap();
+        assert!(sent(&mut peer) == vec![BitTorrentMessage::RejectRequest { piece_index: 1, begin: 0, length: 16384 }]);
+        assert!(peer.next_queued_request().is_none());
+        //nothing to answer for a request we never had
+        peer.cancel_request(2, 0, 16384).ok().unwrap();
+        assert!(sent(&mut peer).is_empty());
+
+        //without the fast extension the request just goes away
+        let mut peer = connected(false);
+        peer.unchoke().ok().unwrap();
+        peer.queue_request(1, 0, 16384).ok().unwrap();
+        sent(&mut peer);
+        peer.cancel_request(1, 0, 16384).ok().unwrap();
+        assert!(sent(&mut peer).is_empty());
+        assert!(peer.next_queued_request().is_none());
+    }
+
+    #[test]
+    fn withdrawn_requests_still_count_as_requested() {
+        let mut peer = connected(true);
+        peer.unchoked_by_peer();
+        peer.request_block(5, 0, 16384).ok().unwrap();
+        //the block came from someone else first
+        assert!(peer.withdraw_request(5, 0, 16384).ok().unwrap());
+        assert!(!peer.withdraw_request(5, 0, 16384).ok().unwrap());
+        assert!(sent(&mut peer) == vec![
+            BitTorrentMessage::Request { piece_index: 5, begin: 0, length: 16384 },
+            BitTorrentMessage::Cancel { piece_index: 5, begin: 0, length: 16384 }
+        ]);
+        assert!(peer.block_received(5, 0, 16384));
+        assert_eq!(peer.unrequested_blocks(), 0);
+    }
 }
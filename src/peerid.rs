@@ -0,0 +1,144 @@
+use std::str;
+
+///Two letter client codes used in Azureus style peer ids, like -AZ2060-
+const AZUREUS_CLIENTS: [(&str, &str); 24] = [
+    ("AG", "Ares"),
+    ("AZ", "Azureus"),
+    ("BC", "BitComet"),
+    ("BI", "BiglyBT"),
+    ("BO", "Boost"),
+    ("BT", "BitTorrent"),
+    ("DE", "Deluge"),
+    ("FG", "FlashGet"),
+    ("KT", "KTorrent"),
+    ("LT", "libtorrent"),
+    ("lt", "libTorrent"),
+    ("LW", "LimeWire"),
+    ("qB", "qBittorrent"),
+    ("SD", "Thunder"),
+    ("SZ", "Shareaza"),
+    ("TL", "Tribler"),
+    ("TR", "Transmission"),
+    ("TX", "Tixati"),
+    ("UM", "uTorrent Mac"),
+    ("UT", "uTorrent"),
+    ("UW", "uTorrent Web"),
+    ("VG", "Vagaa"),
+    ("WW", "WebTorrent"),
+    ("XL", "Xunlei")
+];
+
+///One letter client codes used in Shadow style peer ids, like S58B-----
+const SHADOW_CLIENTS: [(u8, &str); 7] = [
+    (b'A', "ABC"),
+    (b'O', "Osprey Permaseed"),
+    (b'Q', "BTQueue"),
+    (b'R', "Tribler"),
+    (b'S', "Shadow"),
+    (b'T', "BitTornado"),
+    (b'U', "UPnP NAT Bit Torrent")
+];
+
+///Works out the client and its version from a peer id, for the common
+///Azureus, Shadow and Mainline conventions. Returns None if the id follows none of them
+pub fn identify_client(id: &[u8; 20]) -> Option<String> {
+    azureus_style(id).or_else(|| mainline_style(id)).or_else(|| shadow_style(id))
+}
+
+///-XXvvvv- where XX is the client and vvvv the version, digits separated by dots
+fn azureus_style(id: &[u8; 20]) -> Option<String> {
+    if id[0] != b'-' || id[7] != b'-' {
+        return None
+    }
+    let code = str::from_utf8(&id[1..3]).ok()?;
+    let version = str::from_utf8(&id[3..7]).ok()?;
+    if !version.chars().all(|chr| chr.is_ascii_alphanumeric()) {
+        return None
+    }
+    let name = AZUREUS_CLIENTS.iter().find(|&&(client_code, _)| client_code == code)
+        .map(|&(_, name)| String::from(name))
+        .unwrap_or_else(|| format!("Unknown ({})", code));
+    let version: Vec<String> = version.chars().map(|chr| chr.to_string()).collect();
+    Some(format!("{} {}", name, version.join(".")))
+}
+
+///Mx-y-z-- where x, y and z are the major, minor and patch versions
+fn mainline_style(id: &[u8; 20]) -> Option<String> {
+    if id[0] != b'M' {
+        return None
+    }
+    let text = str::from_utf8(&id[1..8]).ok()?;
+    let parts: Vec<&str> = text.trim_end_matches('-').split('-').collect();
+    if parts.len() != 3 || !parts.iter().all(|part| !part.is_empty() && part.chars().all(|chr| chr.is_ascii_digit())) {
+        return None
+    }
+    Some(format!("Mainline {}", parts.join(".")))
+}
+
+///Xvvvvv--- where X is the client and each v is one version number encoded as a
+///single character, padded with dashes. The three dashes after the version are
+///what tells these ids apart from random ones that happen to start with a client letter
+fn shadow_style(id: &[u8; 20]) -> Option<String> {
+    let name = SHADOW_CLIENTS.iter().find(|&&(code, _)| code == id[0]).map(|&(_, name)| name)?;
+    if &id[6..9] != b"---" {
+        return None
+    }
+    let mut version = Vec::new();
+    for &chr in id[1..6].iter().take_while(|&&chr| chr != b'-') {
+        version.push(shadow_digit(chr)?.to_string());
+    }
+    //the version must be followed by dashes
+    if version.is_empty() || id[1 + version.len()..6].iter().any(|&chr| chr != b'-') {
+        return None
+    }
+    Some(format!("{} {}", name, version.join(".")))
+}
+
+///Shadow style version characters count 0-9, then A-Z, then a-z, then . and -
+fn shadow_digit(chr: u8) -> Option<u8> {
+    match chr {
+        b'0'..=b'9' => Some(chr - b'0'),
+        b'A'..=b'Z' => Some(chr - b'A' + 10),
+        b'a'..=b'z' => Some(chr - b'a' + 36),
+        b'.' => Some(62),
+        _ => None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(start: &[u8]) -> [u8; 20] {
+        let mut id = *b"x7Fq2LmZ0pW9cR4tY8uB";
+        id[0..start.len()].copy_from_slice(start);
+        id
+    }
+
+    #[test]
+    fn azureus() {
+        assert_eq!(identify_client(&id(b"-qB4250-")), Some(String::from("qBittorrent 4.2.5.0")));
+        assert_eq!(identify_client(&id(b"-ZZ1000-")), Some(String::from("Unknown (ZZ) 1.0.0.0")));
+    }
+
+    #[test]
+    fn mainline() {
+        assert_eq!(identify_client(&id(b"M7-2-2--")), Some(String::from("Mainline 7.2.2")));
+        assert_eq!(identify_client(&id(b"M4-20-8-")), Some(String::from("Mainline 4.20.8")));
+    }
+
+    #[test]
+    fn shadow() {
+        assert_eq!(identify_client(&id(b"S58B-----")), Some(String::from("Shadow 5.8.11")));
+        assert_eq!(identify_client(&id(b"T03I-----")), Some(String::from("BitTornado 0.3.18")));
+    }
+
+    #[test]
+    fn random_ids_do_not_match() {
+        //starts with a Shadow client letter and five version characters
+        assert_eq!(identify_client(b"S58BxQ7kd9w3m1z0p2r8"), None);
+        assert_eq!(identify_client(b"M7-2-2xyz0123456789a"), None);
+        assert_eq!(identify_client(&[0xff; 20]), None);
+        assert_eq!(identify_client(&id(b"")), None);
+    }
+}
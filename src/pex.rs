@@ -0,0 +1,240 @@
+use bencode::BencodeValue;
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddrV4;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use error::{BoostError, BoostResult};
+use extension::Extension;
+use tracker::PotentialPeer;
+use peer::Peer;
+use mse::MseStream;
+use compact::{self, compact_addr, COMPACT_ADDR_LEN};
+
+///The name ut_pex is advertised under in the extended handshake
+pub const PEX_NAME: &str = "ut_pex";
+
+///How often the session sends pex messages to its peers
+pub const PEX_INTERVAL_SECS: u64 = 60;
+
+///Peers are supposed to send at most one message a minute. Messages from a peer
+///that arrive faster than this are ignored
+const MIN_RECV_INTERVAL_SECS: u64 = 45;
+
+///The most peers added or dropped in a single message, both ways
+const MAX_PEERS_PER_MESSAGE: usize = 50;
+
+///Flags sent along with each added peer
+pub const PEX_PREFERS_ENCRYPTION: u8 = 0x01;
+pub const PEX_SEED: u8 = 0x02;
+pub const PEX_REACHABLE: u8 = 0x10;
+
+///What we remember about each peer we exchange peers with
+struct PexState {
+    ///the peers this peer has been told about and not told were dropped
+    sent: HashSet<SocketAddrV4>,
+    last_received: Option<Instant>
+}
+
+///The ut_pex extension. Tells connected peers which peers we are connected to,
+///and adds the peers they tell us about to the potential peers
+pub struct PeerExchange {
+    potential_peers: Arc<RwLock<Vec<PotentialPeer>>>,
+    ///the address we listen for peers on, which other peers will tell us about too
+    listen_addr: SocketAddrV4,
+    state: Mutex<HashMap<[u8; 20], PexState>>
+}
+
+impl PeerExchange {
+    ///Creates the extension, feeding the peers it learns about into potential_peers.
+    ///This should never be registered for private torrents
+    pub fn new(potential_peers: Arc<RwLock<Vec<PotentialPeer>>>, listen_addr: SocketAddrV4) -> Self {
+        PeerExchange { potential_peers, listen_addr, state: Mutex::new(HashMap::new()) }
+    }
+
+    ///Builds the next message for the given peer from the peers we are connected to
+    ///(not including the peer itself) and their flags. The session only calls this
+    ///every PEX_INTERVAL_SECS, so that is the only rate limit. Returns None if
+    ///nothing changed since the last message
+    pub fn prepare_message(&self, peer_id: &[u8; 20], connected: &[(SocketAddrV4, u8)]) -> Option<Vec<u8>> {
+        let mut state = self.state.lock().expect("The pex state lock was poisoned");
+        let peer_state = state.entry(*peer_id).or_insert_with(PexState::new);
+
+        let added: Vec<&(SocketAddrV4, u8)> = connected.iter()
+            .filter(|&&(addr, _)| !peer_state.sent.contains(&addr))
+            .take(MAX_PEERS_PER_MESSAGE)
+            .collect();
+        let dropped: Vec<SocketAddrV4> = peer_state.sent.iter()
+            .filter(|addr| !connected.iter().any(|&(connected_addr, _)| connected_addr == **addr))
+            .take(MAX_PEERS_PER_MESSAGE)
+            .cloned()
+            .collect();
+        if added.is_empty() && dropped.is_empty() {
+            return None
+        }
+
+        let mut added_bytes = Vec::new();
+        let mut added_flags = Vec::new();
+        for &&(addr, flags) in &added {
+            added_bytes.extend_from_slice(&compact_addr(addr));
+            added_flags.push(flags);
+            peer_state.sent.insert(addr);
+        }
+        let mut dropped_bytes = Vec::new();
+        for addr in &dropped {
+            dropped_bytes.extend_from_slice(&compact_addr(*addr));
+            peer_state.sent.remove(addr);
+        }
+
+        let dict = vec![
+            (&b"added"[..], BencodeValue::Str(added_bytes.as_slice())),
+            (b"added.f", BencodeValue::Str(added_flags.as_slice())),
+            (b"dropped", BencodeValue::Str(dropped_bytes.as_slice()))
+        ];
+        Some(BencodeValue::Dict(dict).bencode())
+    }
+
+    ///tells whether a peer we were told about is really us. When we listen on
+    ///every interface we only know our port, so a loopback address on it counts too
+    fn is_own_addr(&self, addr: SocketAddrV4) -> bool {
+        addr == self.listen_addr
+            || (self.listen_addr.ip().is_unspecified() && addr.port() == self.listen_addr.port() && addr.ip().is_loopback())
+    }
+}
+
+impl PexState {
+    fn new() -> Self {
+        PexState { sent: HashSet::new(), last_received: None }
+    }
+}
+
+impl Extension for PeerExchange {
+    fn name(&self) -> &'static str {
+        PEX_NAME
+    }
+
+    fn on_message(&self, peer_id: &[u8; 20], payload: &[u8]) -> BoostResult<()> {
+        //rate limit each peer
+        {
+            let mut state = self.state.lock().expect("The pex state lock was poisoned");
+            let peer_state = state.entry(*peer_id).or_insert_with(PexState::new);
+            if let Some(last_received) = peer_state.last_received {
+                if last_received.elapsed() < Duration::from_secs(MIN_RECV_INTERVAL_SECS) {
+                    return Ok(())
+                }
+            }
+            peer_state.last_received = Some(Instant::now());
+        };
+
+        let dict = BencodeValue::bdecode(payload)?;
+        let added = match dict.dict_get("added") {
+            Some(&BencodeValue::Str(added)) => added,
+            Some(_) => return Err(BoostError::BencodeValueErr(String::from("Pex added peers is not a string"))),
+            None => return Ok(())
+        };
+        if !added.len().is_multiple_of(COMPACT_ADDR_LEN) {
+            return Err(BoostError::BitTorrentProtocolErr(format!("Pex added peers has length {}, not a multiple of 6", added.len())))
+        }
+
+        let mut potential_peers = self.potential_peers.write().expect("The potential peers lock was poisoned");
+        for addr in compact::parse_compact_peers(added).into_iter().take(MAX_PEERS_PER_MESSAGE) {
+            //skip obviously bad addresses, and ourselves
+            if addr.port() == 0 || addr.ip().is_unspecified() || self.is_own_addr(addr) {
+                continue
+            }
+            //skip peers we already know about
+            if !potential_peers.iter().any(|potential_peer| potential_peer.addr == addr) {
+                potential_peers.push(PotentialPeer { addr, id: None });
+            }
+        }
+        Ok(())
+    }
+
+    fn on_disconnect(&self, peer_id: &[u8; 20]) {
+        self.state.lock().expect("The pex state lock was poisoned").remove(peer_id);
+    }
+}
+
+///Works out the flags to advertise a connected peer with
+pub fn peer_flags<S>(peer: &Peer<MseStream<S>>) -> u8 {
+    let mut flags = 0;
+    if peer.is_encrypted() {
+        flags |= PEX_PREFERS_ENCRYPTION;
+    }
+    if peer.is_seed() {
+        flags |= PEX_SEED;
+    }
+    //we managed to connect to it, so others should be able to as well
+    if !peer.is_incoming() {
+        flags |= PEX_REACHABLE;
+    }
+    flags
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    const PEER_ID: [u8; 20] = [7; 20];
+
+    fn addr(last: u8, port: u16) -> SocketAddrV4 {
+        SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, last), port)
+    }
+
+    fn string<'a>(dict: &BencodeValue<'a>, key: &str) -> &'a [u8] {
+        match dict.dict_get(key) {
+            Some(&BencodeValue::Str(value)) => value,
+            _ => panic!("no string {} in the message", key)
+        }
+    }
+
+    fn exchange(listen_addr: SocketAddrV4) -> (PeerExchange, Arc<RwLock<Vec<PotentialPeer>>>) {
+        let potential_peers = Arc::new(RwLock::new(Vec::new()));
+        (PeerExchange::new(potential_peers.clone(), listen_addr), potential_peers)
+    }
+
+    #[test]
+    fn sends_every_round_with_changes() {
+        let (pex, _) = exchange(addr(1, 6881));
+        let first = pex.prepare_message(&PEER_ID, &[(addr(2, 6881), PEX_SEED)]).expect("first message");
+        let dict = BencodeValue::bdecode(&first).ok().unwrap();
+        assert_eq!(string(&dict, "added"), &compact_addr(addr(2, 6881))[..]);
+        assert_eq!(string(&dict, "added.f"), &[PEX_SEED][..]);
+
+        //nothing changed, nothing to say
+        assert!(pex.prepare_message(&PEER_ID, &[(addr(2, 6881), PEX_SEED)]).is_none());
+
+        //the very next round goes out, the session's tick is the only limit
+        let second = pex.prepare_message(&PEER_ID, &[(addr(3, 6881), 0)]).expect("second message");
+        let dict = BencodeValue::bdecode(&second).ok().unwrap();
+        assert_eq!(string(&dict, "added"), &compact_addr(addr(3, 6881))[..]);
+        assert_eq!(string(&dict, "dropped"), &compact_addr(addr(2, 6881))[..]);
+    }
+
+    #[test]
+    fn skips_ourselves_and_bad_addresses() {
+        let (pex, potential_peers) = exchange(SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), 6881));
+        let mut added = Vec::new();
+        added.extend_from_slice(&compact_addr(addr(2, 6881)));
+        added.extend_from_slice(&compact_addr(SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 6881)));
+        added.extend_from_slice(&compact_addr(addr(3, 0)));
+        added.extend_from_slice(&compact_addr(addr(2, 6881)));
+        let payload = BencodeValue::Dict(vec![(&b"added"[..], BencodeValue::Str(&added))]).bencode();
+        pex.on_message(&PEER_ID, &payload).ok().unwrap();
+        let potential_peers = potential_peers.read().unwrap();
+        assert_eq!(potential_peers.len(), 1);
+        assert_eq!(potential_peers[0].addr, addr(2, 6881));
+
+        let (pex, potential_peers) = exchange(addr(1, 6881));
+        let payload = BencodeValue::Dict(vec![(&b"added"[..], BencodeValue::Str(&compact_addr(addr(1, 6881))))]).bencode();
+        pex.on_message(&PEER_ID, &payload).ok().unwrap();
+        assert!(potential_peers.read().unwrap().is_empty());
+    }
+
+    #[test]
+    fn rejects_partial_addresses() {
+        let (pex, _) = exchange(addr(1, 6881));
+        let payload = BencodeValue::Dict(vec![(&b"added"[..], BencodeValue::Str(&[1, 2, 3, 4, 5]))]).bencode();
+        assert!(pex.on_message(&PEER_ID, &payload).is_err());
+    }
+}
@@ -0,0 +1,258 @@
+use bitvector::BitVector;
+use priority::Priorities;
+use rand::Rng;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+///How many pieces are picked at random before switching to rarest first. Until
+///we have a few pieces we have nothing to trade, and any whole piece is worth
+///more than a rare piece that takes longer to get
+pub const RANDOM_FIRST_PIECES: usize = 4;
+
+///How many pieces past the playhead are downloaded strictly in order by default
+pub const DEFAULT_READ_AHEAD: usize = 8;
+
+///How the picker orders pieces
+#[derive(Clone)]
+pub enum PickMode {
+    ///the rarest pieces first, best for the swarm and for finishing quickly
+    RarestFirst,
+    ///in order, for playing files before they finish. The read_ahead pieces from
+    ///the playhead come first, then the first and last pieces of each file, where
+    ///containers keep their headers and indexes, then everything else in order.
+    ///The playhead is the piece a reader is waiting on, see stream::StreamReader
+    Sequential { read_ahead: usize, playhead: Arc<AtomicUsize> }
+}
+
+///Picks which piece to start downloading next. It keeps count of how many
+///connected peers have each piece, from their bitfields and have messages, so
+///the rarest pieces can be fetched first and no piece is left with only one source
+pub struct PiecePicker {
+    ///how many connected peers have each piece
+    availability: Vec<u32>,
+    mode: PickMode,
+    ///the first and last pieces of each file, wanted early in sequential mode
+    edge_pieces: Vec<u32>
+}
+
+impl PiecePicker {
+    ///Creates a picker for a torrent with the given number of pieces. file_pieces
+    ///has the first and last piece of each file, see MetaInfo::file_pieces
+    pub fn new(num_pieces: usize, mode: PickMode, file_pieces: &[(u32, u32)]) -> Self {
+        let mut edge_pieces = Vec::new();
+        for &(first, last) in file_pieces {
+            for &idx in &[first, last] {
+                if !edge_pieces.contains(&idx) {
+                    edge_pieces.push(idx);
+                }
+            }
+        }
+        PiecePicker { availability: vec![0; num_pieces], mode, edge_pieces }
+    }
+
+    ///Counts a piece a peer announced it has
+    pub fn peer_has(&mut self, index: u32) {
+        if let Some(count) = self.availability.get_mut(index as usize) {
+            *count += 1;
+        }
+    }
+
+    ///Counts every piece in a peer's bitfield, such as when it sends one
+    pub fn add_peer(&mut self, pieces: &BitVector) {
+        for idx in pieces.iter_set() {
+            if let Some(count) = self.availability.get_mut(idx) {
+                *count += 1;
+            }
+        }
+    }
+
+    ///Stops counting every piece in a peer's bitfield, such as when it leaves
+    pub fn remove_peer(&mut self, pieces: &BitVector) {
+        for idx in pieces.iter_set() {
+            if let Some(count) = self.availability.get_mut(idx) {
+                *count = count.saturating_sub(1);
+            }
+        }
+    }
+
+    ///Picks a piece to start, out of the ones we don't have, aren't already
+    ///working on, aren't skipped and can_request allows. How it chooses depends on the mode
+    pub fn pick<F, R>(&self, completed: &BitVector, working: &[u32], priorities: &Priorities, can_request: F, rng: &mut R) -> Option<u32>
+        where F: Fn(u32) -> bool, R: Rng {
+        let wanted = |idx: u32| !completed.index_isset(idx as usize) && !working.contains(&idx)
+            && priorities.is_wanted(idx as usize) && can_request(idx);
+        match self.mode {
+            PickMode::RarestFirst => self.pick_rarest(completed, priorities, wanted, rng),
+            PickMode::Sequential { read_ahead, ref playhead } => self.pick_sequential(read_ahead, playhead.load(Ordering::Relaxed), wanted)
+        }
+    }
+
+    ///Picks the piece closest after the playhead, unless one of the edge pieces
+    ///is wanted and the read ahead window is already covered
+    fn pick_sequential<F: Fn(u32) -> bool>(&self, read_ahead: usize, playhead: usize, wanted: F) -> Option<u32> {
+        let num_pieces = self.availability.len();
+        let playhead = if playhead < num_pieces { playhead } else { 0 };
+        let window_end = num_pieces.min(playhead + read_ahead) as u32;
+        (playhead as u32..window_end).find(|&idx| wanted(idx))
+            .or_else(|| self.edge_pieces.iter().cloned().find(|&idx| wanted(idx)))
+            .or_else(|| (window_end..num_pieces as u32).chain(0..playhead as u32).find(|&idx| wanted(idx)))
+    }
+
+    ///Only pieces of the highest priority there is one of are considered. The first
+    ///few pieces are picked at random, after that the rarest piece is picked, with
+    ///ties broken at random
+    fn pick_rarest<F: Fn(u32) -> bool, R: Rng>(&self, completed: &BitVector, priorities: &Priorities, wanted: F, rng: &mut R) -> Option<u32> {
+        let top_priority = (0..self.availability.len() as u32).filter(|&idx| wanted(idx))
+            .map(|idx| priorities.piece(idx as usize))
+            .max()?;
+        let candidates = (0..self.availability.len() as u32)
+            .filter(|&idx| priorities.piece(idx as usize) == top_priority && wanted(idx));
+        let choices: Vec<u32> = if completed.count_ones() < RANDOM_FIRST_PIECES {
+            candidates.collect()
+        } else {
+            let mut rarest = Vec::new();
+            let mut rarest_count = u32::MAX;
+            for idx in candidates {
+                let count = self.availability[idx as usize];
+                if count < rarest_count {
+                    rarest.clear();
+                    rarest_count = count;
+                }
+                if count == rarest_count {
+                    rarest.push(idx);
+                }
+            }
+            rarest
+        };
+        if choices.is_empty() {
+            None
+        } else {
+            Some(choices[rng.gen_range(0, choices.len())])
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use meta::{FileInfo, MetaInfo};
+    use priority::FilePriority;
+    use rand::{SeedableRng, StdRng};
+
+    ///a torrent of 4 byte pieces made of files of the given lengths
+    fn meta_info(lens: &[u64]) -> MetaInfo {
+        let files: Vec<FileInfo> = lens.iter().enumerate()
+            .map(|(idx, &len)| FileInfo::Single { filename: format!("{}", idx), filelength: len })
+            .collect();
+        let num_pieces = files.iter().map(|file| file.total_bytes()).sum::<u64>().div_ceil(4) as usize;
+        MetaInfo {
+            announce_url: None,
+            piece_len: 4,
+            info_hash: [0; 20],
+            piece_hashes: vec![[0; 20]; num_pieces],
+            file_info: FileInfo::Multi { rootdir: String::from("picker"), files },
+            private: false,
+            nodes: Vec::new()
+        }
+    }
+
+    ///a picker with each piece announced by as many peers as availability says
+    fn rarest_picker(availability: &[u32]) -> PiecePicker {
+        let mut picker = PiecePicker::new(availability.len(), PickMode::RarestFirst, &[]);
+        for (idx, &count) in availability.iter().enumerate() {
+            for _ in 0..count {
+                picker.peer_has(idx as u32);
+            }
+        }
+        picker
+    }
+
+    fn completed(num_pieces: usize, pieces: &[usize]) -> BitVector {
+        let mut completed = BitVector::new(num_pieces);
+        for &idx in pieces {
+            completed.set_index(idx);
+        }
+        completed
+    }
+
+    #[test]
+    fn first_pieces_are_random() {
+        let priorities = Priorities::new(&meta_info(&[40]));
+        let picker = rarest_picker(&[1, 5, 5, 5, 5, 5, 5, 5, 5, 5]);
+        let mut rng = StdRng::from_seed(&[1usize][..]);
+        let mut picked = Vec::new();
+        for _ in 0..100 {
+            let idx = picker.pick(&completed(10, &[2]), &[3], &priorities, |idx| idx != 4, &mut rng).unwrap();
+            if !picked.contains(&idx) {
+                picked.push(idx);
+            }
+        }
+        picked.sort();
+        assert_eq!(picked, vec![0, 1, 5, 6, 7, 8, 9]);
+    }
+
+    #[test]
+    fn rarest_pieces_come_first() {
+        let priorities = Priorities::new(&meta_info(&[40]));
+        let mut picker = rarest_picker(&[1, 1, 1, 1, 4, 2, 3, 2, 5, 6]);
+        let mut rng = StdRng::from_seed(&[2usize][..]);
+        let done = completed(10, &[0, 1, 2, 3]);
+        for _ in 0..20 {
+            let idx = picker.pick(&done, &[], &priorities, |_| true, &mut rng).unwrap();
+            assert!(idx == 5 || idx == 7);
+        }
+        assert_eq!(picker.pick(&done, &[5], &priorities, |_| true, &mut rng), Some(7));
+        assert_eq!(picker.pick(&done, &[5], &priorities, |idx| idx != 7, &mut rng), Some(6));
+        //once a peer with piece 5 and 7 leaves, they are the rarest
+        let mut leaving = BitVector::new(10);
+        leaving.set_index(5);
+        leaving.set_index(7);
+        picker.remove_peer(&leaving);
+        picker.add_peer(&completed(10, &[6]));
+        assert_eq!(picker.pick(&done, &[5], &priorities, |_| true, &mut rng), Some(7));
+        assert_eq!(picker.pick(&completed(10, &(0..10).collect::<Vec<_>>()), &[], &priorities, |_| true, &mut rng), None);
+    }
+
+    #[test]
+    fn higher_priorities_come_first() {
+        //pieces 0-4 are in file 0, 5-9 in file 1 and 10-14 in file 2
+        let mut priorities = Priorities::new(&meta_info(&[20, 20, 20]));
+        priorities.set_file(0, FilePriority::Low);
+        priorities.set_file(1, FilePriority::High);
+        priorities.set_file(2, FilePriority::Skip);
+        let picker = rarest_picker(&[1; 15]);
+        let mut rng = StdRng::from_seed(&[3usize][..]);
+        let done = completed(15, &[0, 1, 2, 3]);
+        for _ in 0..20 {
+            let idx = picker.pick(&done, &[], &priorities, |_| true, &mut rng).unwrap();
+            assert!((5..10).contains(&idx));
+        }
+        //the high priority pieces the peer lacks don't hold up the low ones
+        assert_eq!(picker.pick(&done, &[], &priorities, |idx| !(5..10).contains(&idx), &mut rng), Some(4));
+        assert_eq!(picker.pick(&completed(15, &(0..10).collect::<Vec<_>>()), &[], &priorities, |_| true, &mut rng), None);
+    }
+
+    #[test]
+    fn sequential_reads_ahead_then_edges_then_the_rest() {
+        //two files of 10 pieces, so the edge pieces are 0, 9, 10 and 19
+        let meta_info = meta_info(&[40, 40]);
+        let priorities = Priorities::new(&meta_info);
+        let playhead = Arc::new(AtomicUsize::new(5));
+        let picker = PiecePicker::new(20, PickMode::Sequential { read_ahead: 3, playhead: playhead.clone() }, &meta_info.file_pieces());
+        let mut rng = StdRng::from_seed(&[4usize][..]);
+        let mut pick = |done: &[usize]| picker.pick(&completed(20, done), &[], &priorities, |_| true, &mut rng);
+        assert_eq!(pick(&[]), Some(5));
+        assert_eq!(pick(&[5]), Some(6));
+        assert_eq!(pick(&[5, 6, 7]), Some(0));
+        assert_eq!(pick(&[0, 5, 6, 7]), Some(9));
+        assert_eq!(pick(&[0, 5, 6, 7, 9, 10, 19]), Some(8));
+        //after the end it wraps around to the pieces before the playhead
+        let mut done: Vec<usize> = (5..20).collect();
+        done.push(0);
+        assert_eq!(pick(&done), Some(1));
+        //a playhead past the end starts again from the first piece
+        playhead.store(100, Ordering::Relaxed);
+        assert_eq!(pick(&[]), Some(0));
+        assert_eq!(pick(&[0, 1, 2]), Some(9));
+    }
+}
@@ -1,10 +1,11 @@
+use std::net::Ipv4Addr;
 use bitvector::BitVector;
-use std::time::{Instant, Duration};
-use sha1::Sha1;
+use error::{BoostError, BoostResult};
 use message::BitTorrentMessage;
 
 const BLOCK_SIZE: u32 = 16384; //2^14
-const REQUESTS_STALE_AFTER_MILLIS: u64 = 500; //.5 seconds
+///Most peers a block is requested from at once in endgame mode
+const MAX_ENDGAME_REQUESTS: u8 = 3;
 
 ///A struct for holding piece data while its being worked on
 pub struct Piece {
@@ -12,9 +13,11 @@ pub struct Piece {
     piece_size: u32,
     obtained_blocks: BitVector,
     requested_blocks: BitVector,
-    last_updated: Instant,
-    piece: Vec<u8>,
-    hash: [u8; 20]
+    ///how many peers each block is requested from right now, more than one only in endgame mode
+    block_requests: Vec<u8>,
+    ///the address of the peer that sent each block we have
+    suppliers: Vec<Option<Ipv4Addr>>,
+    piece: Vec<u8>
 }
 
 impl Piece {
@@ -23,18 +26,17 @@ impl Piece {
     ///in the completed pieces bitvector. piece_size should be the total size
     ///of this whole piece in bytes. This should be the same for all pieces
     ///except for the last piece which may or may not be shorter than the rest.
-    ///hash is what the piece will be verified against when it is completed.
-    pub fn new(index: u32, piece_size: u32, hash: [u8;20]) -> Self {
-        let extra = if piece_size % BLOCK_SIZE == 0 { 0 } else { 1 };
-        let num_blocks = piece_size / BLOCK_SIZE + extra;
+    ///Once complete the piece is checked against its hash by a HashPool
+    pub fn new(index: u32, piece_size: u32) -> Self {
+        let num_blocks = piece_size.div_ceil(BLOCK_SIZE);
         Piece {
             index,
             piece_size,
             obtained_blocks: BitVector::new(num_blocks as usize),
             requested_blocks: BitVector::new(num_blocks as usize),
-            last_updated: Instant::now(),
-            piece: Vec::with_capacity(piece_size as usize),
-            hash: hash
+            block_requests: vec![0; num_blocks as usize],
+            suppliers: vec![None; num_blocks as usize],
+            piece: vec![0; piece_size as usize]
         }
     }
 
@@ -43,56 +45,241 @@ impl Piece {
         self.obtained_blocks.is_complete()
     }
 
-    ///returns whether this piece is valid or not
-    pub fn is_correct(&self) -> bool {
-        let mut hasher = Sha1::new();
-        hasher.update(self.piece.as_slice());
-        hasher.digest().bytes() == self.hash
-    }
-
     ///returns a request message for the next desired piece
     pub fn next_request(&mut self) -> Option<BitTorrentMessage> {
         if self.is_complete() {
             None
         } else {
-            //if this piece hasn't been updated in a while, reset all pending requests
-            if self.is_requests_stale() {
-                self.requested_blocks.clear();
-                for idx in 0..self.requested_blocks.bit_len() {
-                    if self.obtained_blocks.index_isset(idx) {
-                        self.requested_blocks.set_index(idx)
-                    }
-                }
-            };
-            //get the first unrequested block
+            //get the first unrequested block, if every block is out there is nothing to request
             let block_idx = self.requested_blocks.first_unset_index() as u32;
-            let block_begin = block_idx * BLOCK_SIZE;
-            //get block size, either the predetermined size or the last block size, which may be
-            //smaller
-            let length = if self.piece_size - block_begin < BLOCK_SIZE {
-                self.piece_size - block_begin
-            } else {
-                BLOCK_SIZE
-            };
-            self.last_updated = Instant::now();
+            if block_idx as usize >= self.requested_blocks.bit_len() {
+                return None
+            }
             self.requested_blocks.set_index(block_idx as usize);
-            Some(BitTorrentMessage::Request { piece_index: self.index, begin: block_begin, length })
+            self.block_requests[block_idx as usize] = 1;
+            Some(self.block_request(block_idx))
+        }
+    }
+
+    ///Returns whether every block of this piece has been requested or obtained
+    pub fn is_fully_requested(&self) -> bool {
+        self.requested_blocks.is_complete()
+    }
+
+    ///returns a request for a block that was already requested from some other
+    ///peer but hasn't arrived, for endgame mode. requested_by_peer tells whether the
+    ///block with the given offset and length is already requested from this peer.
+    ///No block is requested from more than MAX_ENDGAME_REQUESTS peers at once
+    pub fn endgame_request<F: Fn(u32, u32) -> bool>(&mut self, requested_by_peer: F) -> Option<BitTorrentMessage> {
+        let block_idx = (0..self.block_requests.len() as u32).find(|&idx| {
+            let (begin, length) = self.block_bounds(idx);
+            !self.obtained_blocks.index_isset(idx as usize) && self.block_requests[idx as usize] < MAX_ENDGAME_REQUESTS
+                && !requested_by_peer(begin, length)
+        })?;
+        self.requested_blocks.set_index(block_idx as usize);
+        self.block_requests[block_idx as usize] += 1;
+        Some(self.block_request(block_idx))
+    }
+
+    ///Returns whether the block at the given offset is requested from more than one peer
+    pub fn has_duplicate_requests(&self, block_offset: u32) -> bool {
+        self.block_requests.get((block_offset / BLOCK_SIZE) as usize).is_some_and(|&count| count > 1)
+    }
+
+    ///Takes back one request for a block, for when the request timed out or the
+    ///peer won't answer it. Once nobody has it requested it can be requested
+    ///again. Blocks we already have stay put
+    pub fn release_block(&mut self, block_offset: u32) {
+        let block_idx = (block_offset / BLOCK_SIZE) as usize;
+        if block_idx >= self.block_requests.len() {
+            return
+        }
+        self.block_requests[block_idx] = self.block_requests[block_idx].saturating_sub(1);
+        if self.block_requests[block_idx] == 0 && !self.obtained_blocks.index_isset(block_idx) {
+            self.requested_blocks.unset_index(block_idx);
+        }
+    }
+
+    ///makes the request message for a block
+    fn block_request(&self, block_idx: u32) -> BitTorrentMessage {
+        let (begin, length) = self.block_bounds(block_idx);
+        BitTorrentMessage::Request { piece_index: self.index, begin, length }
+    }
+
+    ///the offset and length of a block
+    fn block_bounds(&self, block_idx: u32) -> (u32, u32) {
+        let block_begin = block_idx * BLOCK_SIZE;
+        //get block size, either the predetermined size or the last block size, which may be
+        //smaller
+        let length = if self.piece_size - block_begin < BLOCK_SIZE {
+            self.piece_size - block_begin
+        } else {
+            BLOCK_SIZE
+        };
+        (block_begin, length)
+    }
+
+    ///Puts a block some peer sent into this piece. The block has to start on a block
+    ///boundary and be exactly as long as the block there, otherwise it is a protocol
+    ///error. Returns false if we already had the block, such as when it was
+    ///requested from several peers in endgame mode
+    pub fn add_block(&mut self, block_offset: u32, block: &[u8], supplier: Ipv4Addr) -> BoostResult<bool> {
+        let block_idx = block_offset / BLOCK_SIZE;
+        if !block_offset.is_multiple_of(BLOCK_SIZE) || block_idx as usize >= self.obtained_blocks.bit_len()
+            || block.len() as u32 != self.block_bounds(block_idx).1 {
+            return Err(BoostError::BitTorrentProtocolErr(format!("Peer sent {} bytes at {} of piece {}, which isn't a block", block.len(), block_offset, self.index)))
+        }
+        if self.obtained_blocks.index_isset(block_idx as usize) {
+            return Ok(false)
+        }
+        self.obtained_blocks.set_index(block_idx as usize);
+        self.suppliers[block_idx as usize] = Some(supplier);
+        let begin = block_offset as usize;
+        self.piece[begin..begin + block.len()].copy_from_slice(block);
+        Ok(true)
+    }
+
+    ///Puts back the blocks of this piece a previous run had, which it left in
+    ///data, the whole piece as read from disk. Nobody is blamed for them if the
+    ///piece turns out bad
+    pub fn restore_blocks(&mut self, blocks: &BitVector, data: &[u8]) {
+        let num_blocks = self.obtained_blocks.bit_len();
+        for block_idx in blocks.iter_set().filter(|&idx| idx < num_blocks) {
+            let (begin, length) = self.block_bounds(block_idx as u32);
+            let (begin, end) = (begin as usize, (begin + length) as usize);
+            if end <= data.len() {
+                self.piece[begin..end].copy_from_slice(&data[begin..end]);
+                self.obtained_blocks.set_index(block_idx);
+                self.requested_blocks.set_index(block_idx);
+            }
         }
     }
 
-    ///Takes a block and an offset and updates this piece
-    pub fn add_block(&mut self, block_offset: u32, block: &Vec<u8>) {
-       self.last_updated = Instant::now();
-       self.obtained_blocks.set_index((block_offset / BLOCK_SIZE) as usize);
-       let mut off = block_offset as usize;
-       for byte in block {
-           self.piece[off] = *byte;
-           off += 1;
-       }
+    ///the blocks of this piece we have
+    pub fn obtained_blocks(&self) -> &BitVector {
+        &self.obtained_blocks
     }
 
-    fn is_requests_stale(&self) -> bool {
-        Instant::now() - self.last_updated > Duration::from_millis(REQUESTS_STALE_AFTER_MILLIS)
+    ///the data of the blocks we have, with the offset of each
+    pub fn obtained_data(&self) -> Vec<(u32, &[u8])> {
+        self.obtained_blocks.iter_set().map(|block_idx| {
+            let (begin, length) = self.block_bounds(block_idx as u32);
+            (begin, &self.piece[begin as usize..(begin + length) as usize])
+        }).collect()
     }
 
+    ///the peers that sent the blocks of this piece, each only once
+    pub fn suppliers(&self) -> Vec<Ipv4Addr> {
+        let mut suppliers = Vec::new();
+        for supplier in self.suppliers.iter().filter_map(|supplier| *supplier) {
+            if !suppliers.contains(&supplier) {
+                suppliers.push(supplier);
+            }
+        }
+        suppliers
+    }
+
+    ///the index of this piece in the torrent
+    pub fn index(&self) -> u32 {
+        self.index
+    }
+
+    ///takes the data of this piece, only meaningful once it is complete
+    pub fn into_data(self) -> Vec<u8> {
+        self.piece
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    ///a piece of two whole blocks and a short last one
+    fn piece() -> Piece {
+        Piece::new(7, 2 * BLOCK_SIZE + 1000)
+    }
+
+    fn request(begin: u32, length: u32) -> BitTorrentMessage {
+        BitTorrentMessage::Request { piece_index: 7, begin, length }
+    }
+
+    fn supplier(last: u8) -> Ipv4Addr {
+        Ipv4Addr::new(10, 0, 0, last)
+    }
+
+    #[test]
+    fn requests_every_block_once() {
+        let mut piece = piece();
+        assert!(piece.next_request() == Some(request(0, BLOCK_SIZE)));
+        assert!(piece.next_request() == Some(request(BLOCK_SIZE, BLOCK_SIZE)));
+        assert!(!piece.is_fully_requested());
+        assert!(piece.next_request() == Some(request(2 * BLOCK_SIZE, 1000)));
+        assert!(piece.is_fully_requested());
+        assert!(piece.next_request().is_none());
+        assert!(!piece.has_duplicate_requests(0));
+    }
+
+    #[test]
+    fn endgame_requests_blocks_from_a_few_peers() {
+        let mut piece = piece();
+        while piece.next_request().is_some() {}
+        //the block arrives from the first peer before anyone else is asked for it
+        assert!(piece.add_block(0, &[1; BLOCK_SIZE as usize], supplier(1)).ok().unwrap());
+        //a second and third peer are asked for the first block still missing
+        assert!(piece.endgame_request(|_, _| false) == Some(request(BLOCK_SIZE, BLOCK_SIZE)));
+        assert!(piece.has_duplicate_requests(BLOCK_SIZE));
+        assert!(piece.endgame_request(|_, _| false) == Some(request(BLOCK_SIZE, BLOCK_SIZE)));
+        //a block already requested from MAX_ENDGAME_REQUESTS peers is left alone
+        assert!(piece.endgame_request(|_, _| false) == Some(request(2 * BLOCK_SIZE, 1000)));
+        //as are blocks the peer was already asked for
+        assert!(piece.endgame_request(|begin, _| begin == 2 * BLOCK_SIZE).is_none());
+        assert!(piece.endgame_request(|_, _| false) == Some(request(2 * BLOCK_SIZE, 1000)));
+        //once a duplicated block arrives the other copies are not wanted
+        assert!(piece.add_block(BLOCK_SIZE, &[2; BLOCK_SIZE as usize], supplier(2)).ok().unwrap());
+        assert!(!piece.add_block(BLOCK_SIZE, &[3; BLOCK_SIZE as usize], supplier(3)).ok().unwrap());
+        assert!(piece.endgame_request(|begin, _| begin == 2 * BLOCK_SIZE).is_none());
+        assert!(piece.add_block(2 * BLOCK_SIZE, &[4; 1000], supplier(1)).ok().unwrap());
+        assert!(piece.is_complete());
+        assert!(piece.endgame_request(|_, _| false).is_none());
+        assert_eq!(piece.suppliers(), vec![supplier(1), supplier(2)]);
+        let data = piece.into_data();
+        assert_eq!(data[BLOCK_SIZE as usize], 2);
+        assert_eq!(data[data.len() - 1], 4);
+    }
+
+    #[test]
+    fn released_blocks_are_requested_again() {
+        let mut piece = piece();
+        while piece.next_request().is_some() {}
+        assert!(piece.endgame_request(|_, _| false) == Some(request(0, BLOCK_SIZE)));
+        //one of two requests for the block going away leaves it requested
+        piece.release_block(0);
+        assert!(!piece.has_duplicate_requests(0));
+        assert!(piece.next_request().is_none());
+        piece.release_block(0);
+        assert!(!piece.is_fully_requested());
+        assert!(piece.next_request() == Some(request(0, BLOCK_SIZE)));
+        //blocks we have stay requested, and offsets past the end are ignored
+        assert!(piece.add_block(BLOCK_SIZE, &[0; BLOCK_SIZE as usize], supplier(1)).ok().unwrap());
+        piece.release_block(BLOCK_SIZE);
+        piece.release_block(10 * BLOCK_SIZE);
+        assert!(piece.is_fully_requested());
+        assert!(piece.next_request().is_none());
+    }
+
+    #[test]
+    fn blocks_must_be_whole() {
+        let mut piece = piece();
+        assert!(piece.add_block(1, &[0; BLOCK_SIZE as usize], supplier(1)).is_err());
+        assert!(piece.add_block(0, &[0; 100], supplier(1)).is_err());
+        assert!(piece.add_block(2 * BLOCK_SIZE, &[0; BLOCK_SIZE as usize], supplier(1)).is_err());
+        assert!(piece.add_block(3 * BLOCK_SIZE, &[0; 1000], supplier(1)).is_err());
+        assert!(piece.obtained_blocks().is_empty());
+        assert!(piece.add_block(2 * BLOCK_SIZE, &[5; 1000], supplier(1)).ok().unwrap());
+        let obtained = piece.obtained_data();
+        assert_eq!(obtained.len(), 1);
+        assert_eq!(obtained[0].0, 2 * BLOCK_SIZE);
+        assert_eq!(obtained[0].1, &[5; 1000][..]);
+    }
 }
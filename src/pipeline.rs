@@ -0,0 +1,282 @@
+use std::cmp;
+use std::time::{Duration, Instant};
+
+///Fewest requests we keep outstanding with a peer that has pieces for us
+pub const MIN_QUEUE_DEPTH: usize = 2;
+///Most requests we keep outstanding with any peer, whatever it allows
+pub const MAX_QUEUE_DEPTH: usize = 250;
+///How many requests to keep outstanding before we have measured anything
+const INITIAL_QUEUE_DEPTH: usize = 4;
+///How many times the bandwidth-delay product we keep outstanding, so the
+///queue can grow until the peer's upload is the limit rather than our requests
+const QUEUE_GAIN: f64 = 2.0;
+///The block size the queue depth is worked out for
+const BLOCK_SIZE: f64 = 16384.0;
+///How long throughput is measured over before it is folded into the rate
+const RATE_WINDOW_MILLIS: u64 = 1000;
+///Bounds on how long a single request may go unanswered
+const MIN_REQUEST_TIMEOUT_MILLIS: u64 = 2000;
+const MAX_REQUEST_TIMEOUT_MILLIS: u64 = 60000;
+///How long a request may go unanswered before we have measured any round trips
+const INITIAL_REQUEST_TIMEOUT_MILLIS: u64 = 10000;
+
+///A block we asked a peer for, and when
+struct Outstanding {
+    piece_index: u32,
+    begin: u32,
+    length: u32,
+    sent: Instant
+}
+
+///The requests we have outstanding with one peer. How many we keep outstanding
+///follows the bandwidth-delay product: the rate the peer sends at times the
+///shortest round trip seen, so a fast or far away peer gets a deeper queue than
+///a slow or close one. Each request times out on its own, based on the smoothed
+///round trip time, so one lost request doesn't hold up a whole piece
+pub struct RequestPipeline {
+    outstanding: Vec<Outstanding>,
+    ///the most requests the peer said it will queue, from the reqq of its extended handshake
+    peer_limit: usize,
+    ///smoothed round trip and its variation, in seconds
+    srtt: Option<f64>,
+    rttvar: f64,
+    min_rtt: Option<f64>,
+    ///bytes per second, and the bytes received in the window being measured
+    rate: f64,
+    window_bytes: usize,
+    window_start: Instant
+}
+
+impl RequestPipeline {
+    pub fn new() -> Self {
+        RequestPipeline {
+            outstanding: Vec::new(),
+            peer_limit: MAX_QUEUE_DEPTH,
+            srtt: None,
+            rttvar: 0.0,
+            min_rtt: None,
+            rate: 0.0,
+            window_bytes: 0,
+            window_start: Instant::now()
+        }
+    }
+
+    ///Takes the reqq a peer sent in its extended handshake
+    pub fn set_peer_limit(&mut self, reqq: u32) {
+        self.peer_limit = (reqq as usize).clamp(1, MAX_QUEUE_DEPTH);
+    }
+
+    ///how many requests we want outstanding with this peer right now
+    pub fn depth(&self) -> usize {
+        let depth = match self.min_rtt {
+            Some(min_rtt) if self.rate > 0.0 => (QUEUE_GAIN * self.rate * min_rtt / BLOCK_SIZE).ceil() as usize + MIN_QUEUE_DEPTH,
+            _ => INITIAL_QUEUE_DEPTH
+        };
+        cmp::min(depth, self.peer_limit)
+    }
+
+    ///tells whether there is room for another request
+    pub fn has_room(&self) -> bool {
+        self.outstanding.len() < self.depth()
+    }
+
+    ///Records a request we just sent
+    pub fn push(&mut self, piece_index: u32, begin: u32, length: u32) {
+        self.outstanding.push(Outstanding { piece_index, begin, length, sent: Instant::now() });
+    }
+
+    ///Called when a block arrives. If we asked for it, the round trip and throughput
+    ///estimates are updated and true is returned. Blocks we never asked for, or
+    ///whose requests already timed out, return false
+    pub fn block_received(&mut self, piece_index: u32, begin: u32, length: u32) -> bool {
+        let idx = match self.position(piece_index, begin, length) {
+            Some(idx) => idx,
+            None => return false
+        };
+        let request = self.outstanding.remove(idx);
+        self.add_rtt_sample(secs(request.sent.elapsed()));
+        self.window_bytes += length as usize;
+        self.update_rate();
+        true
+    }
+
+    ///Forgets a request the peer won't answer, such as one it rejected.
+    ///Returns whether we had asked for it
+    pub fn remove(&mut self, piece_index: u32, begin: u32, length: u32) -> bool {
+        match self.position(piece_index, begin, length) {
+            Some(idx) => {
+                self.outstanding.remove(idx);
+                true
+            },
+            None => false
+        }
+    }
+
+    ///tells whether we asked for the given block and are still waiting for it
+    pub fn contains(&self, piece_index: u32, begin: u32, length: u32) -> bool {
+        self.position(piece_index, begin, length).is_some()
+    }
+
+    ///Forgets every outstanding request and returns them, for when the peer drops them all
+    pub fn clear(&mut self) -> Vec<(u32, u32, u32)> {
+        self.outstanding.drain(..).map(|req| (req.piece_index, req.begin, req.length)).collect()
+    }
+
+    ///Takes out and returns the requests for blocks of the given piece
+    pub fn remove_piece(&mut self, piece_index: u32) -> Vec<(u32, u32, u32)> {
+        let mut removed = Vec::new();
+        self.outstanding.retain(|req| {
+            if req.piece_index == piece_index {
+                removed.push((req.piece_index, req.begin, req.length));
+                false
+            } else {
+                true
+            }
+        });
+        removed
+    }
+
+    ///Takes out and returns the requests that have gone unanswered for too long
+    pub fn timed_out(&mut self) -> Vec<(u32, u32, u32)> {
+        self.update_rate();
+        let timeout = self.request_timeout();
+        let mut expired = Vec::new();
+        self.outstanding.retain(|req| {
+            if req.sent.elapsed() > timeout {
+                expired.push((req.piece_index, req.begin, req.length));
+                false
+            } else {
+                true
+            }
+        });
+        expired
+    }
+
+    ///how long a request may go unanswered, the usual smoothed round trip plus
+    ///four times its variation
+    fn request_timeout(&self) -> Duration {
+        let millis = match self.srtt {
+            Some(srtt) => ((srtt + 4.0 * self.rttvar) * 1000.0) as u64,
+            None => INITIAL_REQUEST_TIMEOUT_MILLIS
+        };
+        Duration::from_millis(millis.clamp(MIN_REQUEST_TIMEOUT_MILLIS, MAX_REQUEST_TIMEOUT_MILLIS))
+    }
+
+    fn add_rtt_sample(&mut self, rtt: f64) {
+        match self.srtt {
+            Some(srtt) => {
+                self.rttvar = 0.75 * self.rttvar + 0.25 * (srtt - rtt).abs();
+                self.srtt = Some(0.875 * srtt + 0.125 * rtt);
+            },
+            None => {
+                self.rttvar = rtt / 2.0;
+                self.srtt = Some(rtt);
+            }
+        }
+        if self.min_rtt.is_none_or(|min_rtt| rtt < min_rtt) {
+            self.min_rtt = Some(rtt);
+        }
+    }
+
+    ///folds the bytes of the current window into the rate once the window is over
+    fn update_rate(&mut self) {
+        let elapsed = self.window_start.elapsed();
+        if elapsed >= Duration::from_millis(RATE_WINDOW_MILLIS) {
+            let window_rate = self.window_bytes as f64 / secs(elapsed);
+            self.rate = if self.rate == 0.0 { window_rate } else { 0.5 * self.rate + 0.5 * window_rate };
+            self.window_bytes = 0;
+            self.window_start = Instant::now();
+        }
+    }
+
+    fn position(&self, piece_index: u32, begin: u32, length: u32) -> Option<usize> {
+        self.outstanding.iter().position(|req| req.piece_index == piece_index && req.begin == begin && req.length == length)
+    }
+}
+
+fn secs(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1e9
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn depth_follows_the_bandwidth_delay_product() {
+        let mut pipeline = RequestPipeline::new();
+        assert_eq!(pipeline.depth(), INITIAL_QUEUE_DEPTH);
+        //100ms at 1MB/s is 100KB in flight, twice that is 12.2 blocks
+        pipeline.add_rtt_sample(0.1);
+        pipeline.rate = 1_000_000.0;
+        assert_eq!(pipeline.depth(), 13 + MIN_QUEUE_DEPTH);
+        //a slower round trip doesn't change the shortest one
+        pipeline.add_rtt_sample(0.5);
+        assert_eq!(pipeline.depth(), 13 + MIN_QUEUE_DEPTH);
+        pipeline.rate = 1000.0;
+        assert_eq!(pipeline.depth(), 1 + MIN_QUEUE_DEPTH);
+        pipeline.rate = 1e9;
+        assert_eq!(pipeline.depth(), MAX_QUEUE_DEPTH);
+    }
+
+    #[test]
+    fn depth_is_clamped_to_the_peers_reqq() {
+        let mut pipeline = RequestPipeline::new();
+        pipeline.set_peer_limit(2);
+        assert_eq!(pipeline.depth(), 2);
+        pipeline.add_rtt_sample(0.1);
+        pipeline.rate = 1_000_000.0;
+        assert_eq!(pipeline.depth(), 2);
+        pipeline.set_peer_limit(0);
+        assert_eq!(pipeline.depth(), 1);
+        pipeline.set_peer_limit(100_000);
+        assert_eq!(pipeline.depth(), 13 + MIN_QUEUE_DEPTH);
+        pipeline.push(0, 0, 16384);
+        assert!(pipeline.has_room());
+        pipeline.set_peer_limit(1);
+        assert!(!pipeline.has_room());
+    }
+
+    #[test]
+    fn timeout_follows_srtt_and_rttvar() {
+        let mut pipeline = RequestPipeline::new();
+        assert_eq!(pipeline.request_timeout(), Duration::from_millis(INITIAL_REQUEST_TIMEOUT_MILLIS));
+        //srtt 4s and rttvar 2s
+        pipeline.add_rtt_sample(4.0);
+        assert_eq!(pipeline.request_timeout(), Duration::from_millis(12000));
+        //srtt 3.75s and rttvar still 2s
+        pipeline.add_rtt_sample(2.0);
+        assert_eq!(pipeline.request_timeout(), Duration::from_millis(11750));
+        pipeline.add_rtt_sample(1000.0);
+        assert_eq!(pipeline.request_timeout(), Duration::from_millis(MAX_REQUEST_TIMEOUT_MILLIS));
+        let mut fast = RequestPipeline::new();
+        fast.add_rtt_sample(0.01);
+        assert_eq!(fast.request_timeout(), Duration::from_millis(MIN_REQUEST_TIMEOUT_MILLIS));
+    }
+
+    #[test]
+    fn requests_are_answered_removed_or_time_out() {
+        let mut pipeline = RequestPipeline::new();
+        pipeline.push(0, 0, 16384);
+        pipeline.push(0, 16384, 16384);
+        pipeline.push(1, 0, 16384);
+        assert!(pipeline.contains(0, 16384, 16384));
+        assert!(!pipeline.block_received(2, 0, 16384));
+        assert!(pipeline.block_received(0, 0, 16384));
+        assert!(!pipeline.block_received(0, 0, 16384));
+        assert!(pipeline.srtt.is_some());
+        assert!(pipeline.remove(1, 0, 16384));
+        assert!(!pipeline.remove(1, 0, 16384));
+        assert!(pipeline.timed_out().is_empty());
+        pipeline.outstanding[0].sent = Instant::now() - Duration::from_millis(MAX_REQUEST_TIMEOUT_MILLIS + 1000);
+        assert_eq!(pipeline.timed_out(), vec![(0, 16384, 16384)]);
+        assert!(!pipeline.contains(0, 16384, 16384));
+        pipeline.push(3, 0, 100);
+        pipeline.push(4, 0, 100);
+        pipeline.push(3, 100, 100);
+        assert_eq!(pipeline.remove_piece(3), vec![(3, 0, 100), (3, 100, 100)]);
+        assert!(pipeline.remove_piece(3).is_empty());
+        assert_eq!(pipeline.clear(), vec![(4, 0, 100)]);
+        assert!(pipeline.clear().is_empty());
+    }
+}
@@ -0,0 +1,211 @@
+use bitvector::BitVector;
+use meta::{MetaInfo, FileInfo};
+
+///How much we want a file of a multifile torrent
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum FilePriority {
+    ///not downloaded at all
+    Skip,
+    Low,
+    Normal,
+    High
+}
+
+impl FilePriority {
+    ///Gets the priority from its name on the command line
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "skip" => Some(FilePriority::Skip),
+            "low" => Some(FilePriority::Low),
+            "normal" => Some(FilePriority::Normal),
+            "high" => Some(FilePriority::High),
+            _ => None
+        }
+    }
+
+    ///the name of the priority, as from_name takes it
+    pub fn name(&self) -> &'static str {
+        match *self {
+            FilePriority::Skip => "skip",
+            FilePriority::Low => "low",
+            FilePriority::Normal => "normal",
+            FilePriority::High => "high"
+        }
+    }
+}
+
+///Parses a file priority like 2=high, the file counting from 0 in metafile order
+pub fn parse_file_priority(file_priority: &str) -> Option<(usize, FilePriority)> {
+    let mut parts = file_priority.splitn(2, '=');
+    let file = parts.next().and_then(|file| file.trim().parse().ok())?;
+    let priority = parts.next().and_then(|priority| FilePriority::from_name(priority.trim()))?;
+    Some((file, priority))
+}
+
+///The priority of every file in a torrent and the piece priorities that follow
+///from them. A piece that straddles a file boundary takes the highest priority
+///of the files it holds part of, so it is only skipped if all of them are.
+///Shared with the session, which notices changes by the generation going up
+pub struct Priorities {
+    ///where each file starts in the torrent and how long it is
+    file_ranges: Vec<(u64, u64)>,
+    files: Vec<FilePriority>,
+    pieces: Vec<FilePriority>,
+    piece_len: u64,
+    generation: usize
+}
+
+impl Priorities {
+    ///Starts every file of the torrent at normal priority
+    pub fn new(meta_info: &MetaInfo) -> Self {
+        let lengths = match meta_info.file_info {
+            FileInfo::Single { filelength, .. } => vec![filelength],
+            FileInfo::Multi { ref files, .. } => files.iter().map(|file| file.total_bytes()).collect()
+        };
+        let mut offset = 0;
+        let mut file_ranges = Vec::new();
+        for length in lengths {
+            file_ranges.push((offset, length));
+            offset += length;
+        }
+        Priorities {
+            files: vec![FilePriority::Normal; file_ranges.len()],
+            file_ranges,
+            pieces: vec![FilePriority::Normal; meta_info.num_pieces()],
+            piece_len: meta_info.piece_len,
+            generation: 0
+        }
+    }
+
+    ///Sets the priority of the file with the given index, in the order the metafile
+    ///lists them, and updates the pieces it is in. Returns false if there is no such file
+    pub fn set_file(&mut self, file: usize, priority: FilePriority) -> bool {
+        if file >= self.files.len() {
+            return false
+        }
+        self.files[file] = priority;
+        let (start, length) = self.file_ranges[file];
+        if length > 0 {
+            for piece in start / self.piece_len..=(start + length - 1) / self.piece_len {
+                self.pieces[piece as usize] = self.piece_priority(piece);
+            }
+        }
+        self.generation += 1;
+        true
+    }
+
+    ///the priority of each file
+    pub fn files(&self) -> &[FilePriority] {
+        self.files.as_slice()
+    }
+
+    ///the priority of a piece
+    pub fn piece(&self, piece: usize) -> FilePriority {
+        self.pieces.get(piece).cloned().unwrap_or(FilePriority::Skip)
+    }
+
+    ///tells whether a piece is wanted at all
+    pub fn is_wanted(&self, piece: usize) -> bool {
+        self.piece(piece) != FilePriority::Skip
+    }
+
+    ///the pieces that are skipped, because every file they hold part of is
+    pub fn skipped_pieces(&self) -> BitVector {
+        let mut skipped = BitVector::new(self.pieces.len());
+        for piece in (0..self.pieces.len()).filter(|&piece| !self.is_wanted(piece)) {
+            skipped.set_index(piece);
+        }
+        skipped
+    }
+
+    ///goes up every time a priority changes
+    pub fn generation(&self) -> usize {
+        self.generation
+    }
+
+    ///the highest priority of the files overlapping a piece
+    fn piece_priority(&self, piece: u64) -> FilePriority {
+        let piece_start = piece * self.piece_len;
+        let piece_end = piece_start + self.piece_len;
+        self.file_ranges.iter().zip(self.files.iter())
+            .filter(|&(&(start, length), _)| length > 0 && start < piece_end && start + length > piece_start)
+            .map(|(_, &priority)| priority)
+            .max()
+            .unwrap_or(FilePriority::Skip)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    ///a torrent of 10 byte pieces made of files of the given lengths
+    fn priorities(lens: &[u64]) -> Priorities {
+        let files: Vec<FileInfo> = lens.iter().enumerate()
+            .map(|(idx, &len)| FileInfo::Single { filename: format!("{}", idx), filelength: len })
+            .collect();
+        let num_pieces = lens.iter().sum::<u64>().div_ceil(10) as usize;
+        Priorities::new(&MetaInfo {
+            announce_url: None,
+            piece_len: 10,
+            info_hash: [0; 20],
+            piece_hashes: vec![[0; 20]; num_pieces],
+            file_info: FileInfo::Multi { rootdir: String::from("priorities"), files },
+            private: false,
+            nodes: Vec::new()
+        })
+    }
+
+    fn pieces(priorities: &Priorities) -> Vec<FilePriority> {
+        (0..priorities.pieces.len()).map(|piece| priorities.piece(piece)).collect()
+    }
+
+    #[test]
+    fn straddling_pieces_take_the_highest_priority() {
+        use self::FilePriority::*;
+        //piece 1 holds the end of file 0 and the start of file 1, piece 2 is all
+        //file 1 and piece 3 holds the end of file 1, empty file 2 and file 3
+        let mut priorities = priorities(&[15, 17, 0, 8]);
+        assert_eq!(pieces(&priorities), vec![Normal; 4]);
+        assert!(priorities.set_file(1, Skip));
+        assert_eq!(pieces(&priorities), vec![Normal, Normal, Skip, Normal]);
+        assert_eq!(priorities.skipped_pieces().iter_set().collect::<Vec<_>>(), vec![2]);
+        assert!(priorities.set_file(3, Low));
+        assert_eq!(pieces(&priorities), vec![Normal, Normal, Skip, Low]);
+        assert!(priorities.set_file(0, High));
+        assert_eq!(pieces(&priorities), vec![High, High, Skip, Low]);
+        assert!(priorities.set_file(0, Skip));
+        assert!(priorities.set_file(3, Skip));
+        assert_eq!(pieces(&priorities), vec![Skip; 4]);
+        assert!(!priorities.is_wanted(1));
+        //an empty file has no pieces to change
+        assert!(priorities.set_file(2, High));
+        assert_eq!(pieces(&priorities), vec![Skip; 4]);
+        assert!(priorities.set_file(1, Low));
+        assert_eq!(pieces(&priorities), vec![Skip, Low, Low, Low]);
+        assert_eq!(priorities.files(), &[Skip, Low, High, Skip][..]);
+        assert!(!priorities.is_wanted(4));
+    }
+
+    #[test]
+    fn changes_bump_the_generation() {
+        let mut priorities = priorities(&[10, 10]);
+        assert_eq!(priorities.generation(), 0);
+        assert!(priorities.set_file(1, FilePriority::High));
+        assert_eq!(priorities.generation(), 1);
+        assert!(!priorities.set_file(2, FilePriority::High));
+        assert_eq!(priorities.generation(), 1);
+    }
+
+    #[test]
+    fn parses_file_priorities() {
+        assert_eq!(parse_file_priority("2=high"), Some((2, FilePriority::High)));
+        assert_eq!(parse_file_priority(" 0 = skip "), Some((0, FilePriority::Skip)));
+        assert_eq!(parse_file_priority("2=urgent"), None);
+        assert_eq!(parse_file_priority("two=low"), None);
+        assert_eq!(parse_file_priority("2"), None);
+        for priority in &[FilePriority::Skip, FilePriority::Low, FilePriority::Normal, FilePriority::High] {
+            assert_eq!(FilePriority::from_name(priority.name()), Some(*priority));
+        }
+    }
+}
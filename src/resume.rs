@@ -0,0 +1,216 @@
+use bencode::BencodeValue;
+use bitvector::BitVector;
+use error::{BoostError, BoostResult};
+use meta::MetaInfo;
+use priority::FilePriority;
+use std::fs::{self, File};
+use torrentfile::TorrentFile;
+use std::io::{Read, Write};
+use std::time::UNIX_EPOCH;
+
+///What a run leaves behind so the next one can pick up where it stopped
+///without checking the data again, kept bencoded in a file per torrent
+pub struct ResumeData {
+    pub info_hash: [u8; 20],
+    ///the pieces we had verified and written to disk
+    pub completed: BitVector,
+    ///the blocks we had of unfinished pieces, which were written to disk too
+    pub partial: Vec<(u32, BitVector)>,
+    pub priorities: Vec<FilePriority>,
+    pub uploaded: u64,
+    pub downloaded: u64,
+    ///the size and latest modification time of the torrent's files when we
+    ///saved, see data_stamp, so we can tell if something else changed them since
+    pub file_len: u64,
+    pub file_mtime: u64
+}
+
+impl ResumeData {
+    ///Saves the resume data to a bencoded file
+    pub fn save(&self, path: &str) -> BoostResult<()> {
+        let priorities: Vec<BencodeValue> = self.priorities.iter()
+            .map(|priority| BencodeValue::Str(priority.name().as_bytes()))
+            .collect();
+        let partial: Vec<BencodeValue> = self.partial.iter()
+            .map(|&(index, ref blocks)| BencodeValue::Dict(vec![
+                (&b"blocks"[..], BencodeValue::Str(blocks.as_bytes())),
+                (b"piece", BencodeValue::Integer(index as i64))
+            ]))
+            .collect();
+        //keys in sorted order, as bencoding requires
+        let dict = BencodeValue::Dict(vec![
+            (&b"completed"[..], BencodeValue::Str(self.completed.as_bytes())),
+            (b"downloaded", BencodeValue::Integer(self.downloaded as i64)),
+            (b"file length", BencodeValue::Integer(self.file_len as i64)),
+            (b"file mtime", BencodeValue::Integer(self.file_mtime as i64)),
+            (b"info hash", BencodeValue::Str(&self.info_hash)),
+            (b"partial", BencodeValue::List(partial)),
+            (b"priorities", BencodeValue::List(priorities)),
+            (b"uploaded", BencodeValue::Integer(self.uploaded as i64))
+        ]);
+        let mut file = File::create(path).map_err(|_| BoostError::FileOpenErr(String::from(path)))?;
+        file.write_all(dict.bencode().as_slice()).map_err(|_| BoostError::FileWriteErr(String::from(path)))
+    }
+
+    ///Loads resume data saved with save, as long as it is for the given torrent
+    pub fn load(path: &str, meta_info: &MetaInfo) -> BoostResult<Self> {
+        let mut file = File::open(path).map_err(|_| BoostError::FileOpenErr(String::from(path)))?;
+        let mut buf = Vec::new();
+        file.read_to_end(&mut buf).map_err(|_| BoostError::FileReadErr(String::from(path)))?;
+        let dict = BencodeValue::bdecode(buf.as_slice())?;
+        match dict.dict_get("info hash") {
+            Some(&BencodeValue::Str(info_hash)) if info_hash == meta_info.info_hash => (),
+            _ => return Err(BoostError::BencodeValueErr(String::from("Resume data is for another torrent")))
+        }
+        let completed = match dict.dict_get("completed") {
+            Some(&BencodeValue::Str(completed)) => BitVector::from_bytes(completed, meta_info.num_pieces())?,
+            _ => return Err(BoostError::BencodeValueErr(String::from("Resume data has no completed pieces")))
+        };
+        let mut partial = Vec::new();
+        if let Some(BencodeValue::List(pieces)) = dict.dict_get("partial") {
+            for piece in pieces {
+                if let (Some(&BencodeValue::Integer(index)), Some(&BencodeValue::Str(blocks))) = (piece.dict_get("piece"), piece.dict_get("blocks")) {
+                    if index >= 0 && (index as usize) < meta_info.num_pieces() {
+                        partial.push((index as u32, BitVector::from_bytes(blocks, blocks.len() * 8)?));
+                    }
+                }
+            }
+        }
+        let mut priorities = Vec::new();
+        if let Some(BencodeValue::List(names)) = dict.dict_get("priorities") {
+            for name in names {
+                match *name {
+                    BencodeValue::Str(name) => priorities.push(::std::str::from_utf8(name).ok().and_then(FilePriority::from_name)
+                        .ok_or_else(|| BoostError::BencodeValueErr(String::from("Resume data has an unknown file priority")))?),
+                    _ => return Err(BoostError::BencodeValueErr(String::from("Resume data has an unknown file priority")))
+                }
+            }
+        }
+        let integer = |key: &str| match dict.dict_get(key) {
+            Some(&BencodeValue::Integer(value)) if value >= 0 => value as u64,
+            _ => 0
+        };
+        Ok(ResumeData {
+            info_hash: meta_info.info_hash,
+            completed,
+            partial,
+            priorities,
+            uploaded: integer("uploaded"),
+            downloaded: integer("downloaded"),
+            file_len: integer("file length"),
+            file_mtime: integer("file mtime")
+        })
+    }
+
+    ///tells whether the torrent's files are the same size and were last changed
+    ///at the same time as when we saved, so what we had on disk can be trusted
+    pub fn matches_data(&self, meta_info: &MetaInfo) -> bool {
+        data_stamp(meta_info).is_some_and(|(len, mtime)| len == self.file_len && mtime == self.file_mtime)
+    }
+}
+
+///the total size and latest modification time in seconds of the torrent's
+///files that exist, if any do
+pub fn data_stamp(meta_info: &MetaInfo) -> Option<(u64, u64)> {
+    TorrentFile::data_paths(meta_info).iter()
+        .filter_map(|(path, _)| file_stamp(path))
+        .fold(None, |stamp, (len, mtime)| {
+            let (total_len, latest) = stamp.unwrap_or((0, 0));
+            Some((total_len + len, latest.max(mtime)))
+        })
+}
+
+///the size and modification time in seconds of a file, if it exists
+fn file_stamp(path: &str) -> Option<(u64, u64)> {
+    let metadata = fs::metadata(path).ok()?;
+    let mtime = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?.as_secs();
+    Some((metadata.len(), mtime))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use meta::FileInfo;
+    use std::env;
+    use std::process;
+    use std::time::Duration;
+
+    fn meta_info() -> MetaInfo {
+        MetaInfo {
+            announce_url: None,
+            piece_len: 4,
+            info_hash: [7; 20],
+            piece_hashes: vec![[0; 20]; 10],
+            file_info: FileInfo::Single { filename: String::from("data"), filelength: 40 },
+            private: false,
+            nodes: Vec::new()
+        }
+    }
+
+    fn temp_path(name: &str) -> String {
+        env::temp_dir().join(format!("boost-resume-{}-{}", name, process::id())).to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn saves_and_loads() {
+        let path = temp_path("roundtrip");
+        let mut completed = BitVector::new(10);
+        completed.set_index(0);
+        completed.set_index(9);
+        let mut blocks = BitVector::new(8);
+        blocks.set_index(3);
+        let resume = ResumeData {
+            info_hash: [7; 20],
+            completed,
+            partial: vec![(4, blocks)],
+            priorities: vec![FilePriority::High, FilePriority::Skip],
+            uploaded: 1234,
+            downloaded: 5678,
+            file_len: 40,
+            file_mtime: 1_600_000_000
+        };
+        resume.save(&path).ok().unwrap();
+        let loaded = ResumeData::load(&path, &meta_info()).ok().unwrap();
+        assert!(loaded.completed == resume.completed);
+        assert_eq!(loaded.partial.len(), 1);
+        assert_eq!(loaded.partial[0].0, 4);
+        assert!(loaded.partial[0].1 == resume.partial[0].1);
+        assert_eq!(loaded.priorities, resume.priorities);
+        assert_eq!((loaded.uploaded, loaded.downloaded), (1234, 5678));
+        assert_eq!((loaded.file_len, loaded.file_mtime), (40, 1_600_000_000));
+        let mut other = meta_info();
+        other.info_hash = [8; 20];
+        assert!(ResumeData::load(&path, &other).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn a_changed_file_needs_checking() {
+        let path = temp_path("stamp");
+        File::create(&path).unwrap().write_all(&[0; 40]).unwrap();
+        let mut meta_info = meta_info();
+        meta_info.file_info = FileInfo::Single { filename: path.clone(), filelength: 40 };
+        let (file_len, file_mtime) = data_stamp(&meta_info).unwrap();
+        let resume = ResumeData {
+            info_hash: [7; 20],
+            completed: BitVector::new(10),
+            partial: Vec::new(),
+            priorities: Vec::new(),
+            uploaded: 0,
+            downloaded: 0,
+            file_len,
+            file_mtime
+        };
+        assert!(resume.matches_data(&meta_info));
+        let file = fs::OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_modified(UNIX_EPOCH + Duration::from_secs(file_mtime + 60)).unwrap();
+        assert!(!resume.matches_data(&meta_info));
+        file.set_modified(UNIX_EPOCH + Duration::from_secs(file_mtime)).unwrap();
+        assert!(resume.matches_data(&meta_info));
+        file.set_len(41).unwrap();
+        file.set_modified(UNIX_EPOCH + Duration::from_secs(file_mtime)).unwrap();
+        assert!(!resume.matches_data(&meta_info));
+        fs::remove_file(&path).unwrap();
+        assert!(!resume.matches_data(&meta_info));
+    }
+}
@@ -0,0 +1,952 @@
+use mio::{Events, Poll, PollOpt, Ready, Token};
+use mio::net::{TcpListener, TcpStream};
+use std::collections::{HashMap, HashSet};
+use std::io::{self, ErrorKind};
+use std::os::unix::io::AsRawFd;
+use std::net::{self, Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::{Arc, RwLock, mpsc};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use std::thread;
+use bitvector::BitVector;
+use cache::ReadCache;
+use choker::{self, ChokeCandidate, Choker};
+use error::{BoostError, BoostResult};
+use extension::{ExtensionRegistry, ExtendedHandshake};
+use hashpool::{HashJob, HashPool, Hashed};
+use libc;
+use message::BitTorrentMessage;
+use meta::MetaInfo;
+use mse::{self, EncryptionPolicy, MseStream};
+use net2::{TcpBuilder, TcpStreamExt};
+use peer::{self, Peer};
+use pex::{self, PeerExchange};
+use picker::{PickMode, PiecePicker};
+use priority::{FilePriority, Priorities};
+use piece::Piece;
+use rand;
+use torrentfile::TorrentFile;
+use tracker::PotentialPeer;
+
+///Most peers we connect to at once
+const MAX_OUTGOING_PEERS: usize = 30;
+///Most peers that connected to us we keep at once, on top of the ones we connected to
+const MAX_INCOMING_PEERS: usize = 20;
+///How many bytes of messages a peer may have waiting to be sent before we stop
+///serving its requests, so blocks are only read from disk as the socket takes them
+const UPLOAD_BUFFER_LEN: usize = 64 * 1024;
+///How long poll waits for events before the session does its periodic work
+const TICK_MILLIS: u64 = 100;
+///How many bad pieces a peer may have sent part of before it is banned. A peer
+///that sent the whole of a bad piece is banned right away
+const HASH_FAILS_BEFORE_BAN: u32 = 3;
+///The listener's token, peers get tokens counting up after it
+const LISTENER: Token = Token(0);
+
+///A peer the event loop is talking to
+type LivePeer = Peer<MseStream<TcpStream>>;
+
+///The result of a handshake done on a helper thread, and whether the peer connected to us
+struct Handshaken {
+    incoming: bool,
+    peer: Option<Peer>
+}
+
+///How the session talks to peers, fixed for the whole run
+pub struct SessionSettings {
+    ///the address we connect to peers from
+    pub bind_ip: Ipv4Addr,
+    pub my_id: String,
+    pub encryption: EncryptionPolicy,
+    pub pick_mode: PickMode,
+    pub extensions: Arc<ExtensionRegistry>,
+    pub ext_handshake: ExtendedHandshake,
+    pub pex: Option<Arc<PeerExchange>>
+}
+
+///What the session shares with the rest of the program: the progress it picks
+///up from the resume data and reports back, and the signal to wrap up
+pub struct SessionShared {
+    pub completed: Arc<RwLock<BitVector>>,
+    pub priorities: Arc<RwLock<Priorities>>,
+    pub total_uploaded: Arc<AtomicUsize>,
+    pub total_downloaded: Arc<AtomicUsize>,
+    pub potential_peers: Arc<RwLock<Vec<PotentialPeer>>>,
+    pub wrap_up: Arc<AtomicBool>
+}
+
+///The peer side of a torrent. A single event loop owns the sockets of every
+///connected peer and reacts to them as they become readable or writable, so no
+///peer can hold up another. Only the blocking handshakes are done on short lived
+///helper threads, which hand finished peers back over a channel
+pub struct Session {
+    poll: Poll,
+    listener: TcpListener,
+    peers: HashMap<Token, LivePeer>,
+    next_token: usize,
+    ///the address we connect to peers from
+    bind_ip: Ipv4Addr,
+    my_id: String,
+    info_hash: [u8; 20],
+    num_pieces: usize,
+    piece_len: u64,
+    total_len: u64,
+    piece_hashes: Vec<[u8; 20]>,
+    ///the pieces we have verified and written to disk
+    completed: Arc<RwLock<BitVector>>,
+    ///the pieces we are downloading blocks of
+    working_pieces: Vec<Piece>,
+    ///checks finished pieces against their hashes off the event loop
+    hash_pool: HashPool,
+    ///the pieces being checked, with the peers that sent them
+    verifying: HashMap<u32, Vec<Ipv4Addr>>,
+    picker: PiecePicker,
+    ///which files and pieces we want, may be changed while we run
+    priorities: Arc<RwLock<Priorities>>,
+    ///the generation of the priorities we last acted on, none until the first tick
+    priorities_seen: Option<usize>,
+    torrent_file: TorrentFile,
+    read_cache: ReadCache,
+    total_uploaded: Arc<AtomicUsize>,
+    total_downloaded: Arc<AtomicUsize>,
+    potential_peers: Arc<RwLock<Vec<PotentialPeer>>>,
+    extensions: Arc<ExtensionRegistry>,
+    ext_handshake: ExtendedHandshake,
+    pex: Option<Arc<PeerExchange>>,
+    encryption: EncryptionPolicy,
+    handshake_sender: mpsc::Sender<Handshaken>,
+    handshake_receiver: mpsc::Receiver<Handshaken>,
+    ///outgoing and incoming handshakes still going on helper threads
+    connecting: usize,
+    accepting: usize,
+    last_pex: Instant,
+    choker: Choker<Token>,
+    last_choke: Instant,
+    ///how many bad pieces each address sent blocks of
+    hash_fails: HashMap<Ipv4Addr, u32>,
+    ///addresses that sent us bad data, we don't talk to them again
+    banned: HashSet<Ipv4Addr>,
+    wrap_up: Arc<AtomicBool>
+}
+
+impl Session {
+    ///Sets up the event loop around our listener. Nothing happens until run is called
+    pub fn new(listener: net::TcpListener,
+               meta_info: &MetaInfo,
+               torrent_file: TorrentFile,
+               settings: SessionSettings,
+               shared: SessionShared) -> BoostResult<Self> {
+        let SessionSettings { bind_ip, my_id, encryption, pick_mode, extensions, ext_handshake, pex } = settings;
+        let SessionShared { completed, priorities, total_uploaded, total_downloaded, potential_peers, wrap_up } = shared;
+        let poll = Poll::new().map_err(|_| BoostError::EventLoopErr)?;
+        let listener = TcpListener::from_std(listener).map_err(|_| BoostError::EventLoopErr)?;
+        poll.register(&listener, LISTENER, Ready::readable(), PollOpt::edge()).map_err(|_| BoostError::EventLoopErr)?;
+        let (handshake_sender, handshake_receiver) = mpsc::channel();
+        Ok(Session {
+            poll,
+            listener,
+            peers: HashMap::new(),
+            next_token: LISTENER.0 + 1,
+            bind_ip,
+            my_id,
+            info_hash: meta_info.info_hash,
+            num_pieces: meta_info.num_pieces(),
+            piece_len: meta_info.piece_len,
+            total_len: meta_info.file_info.total_bytes(),
+            piece_hashes: meta_info.piece_hashes.clone(),
+            completed,
+            working_pieces: Vec::new(),
+            hash_pool: HashPool::new(),
+            verifying: HashMap::new(),
+            picker: PiecePicker::new(meta_info.num_pieces(), pick_mode, &meta_info.file_pieces()),
+            priorities_seen: None,
+            priorities,
+            torrent_file,
+            read_cache: ReadCache::new(meta_info.piece_len),
+            total_uploaded,
+            total_downloaded,
+            potential_peers,
+            extensions,
+            ext_handshake,
+            pex,
+            encryption,
+            handshake_sender,
+            handshake_receiver,
+            connecting: 0,
+            accepting: 0,
+            last_pex: Instant::now(),
+            choker: Choker::new(),
+            last_choke: Instant::now(),
+            hash_fails: HashMap::new(),
+            banned: HashSet::new(),
+            wrap_up
+        })
+    }
+
+    ///Puts back the pieces a previous run had some blocks of, see ResumeData.
+    ///The blocks are read back from the file they were saved to
+    pub fn restore_partial(&mut self, partial: &[(u32, BitVector)]) -> BoostResult<()> {
+        for &(index, ref blocks) in partial {
+            if self.completed.read().expect("The completed lock was poisoned").index_isset(index as usize)
+                || self.working_pieces.iter().any(|piece| piece.index() == index) {
+                continue
+            }
+            let mut data = vec![0; self.piece_size(index) as usize];
+            self.torrent_file.read(index as u64 * self.piece_len, &mut data)?;
+            let mut piece = Piece::new(index, data.len() as u32);
+            piece.restore_blocks(blocks, &data);
+            if piece.is_complete() {
+                self.verifying.insert(index, Vec::new());
+                self.hash_pool.submit(HashJob { index, data: piece.into_data(), expected: self.piece_hashes[index as usize] });
+            } else {
+                self.working_pieces.push(piece);
+            }
+        }
+        Ok(())
+    }
+
+    ///Runs the event loop until it is time to wrap up, then disconnects every peer.
+    ///Returns the blocks we have of the pieces that weren't finished, which are
+    ///written to the file so the next run can pick them up again
+    pub fn run(mut self) -> Vec<(u32, BitVector)> {
+        let mut events = Events::with_capacity(1024);
+        let tick = Duration::from_millis(TICK_MILLIS);
+        while !self.wrap_up.load(Ordering::Relaxed) {
+            if self.poll.poll(&mut events, Some(tick)).is_err() {
+                break
+            }
+            for event in events.iter() {
+                match event.token() {
+                    LISTENER => self.accept_all(),
+                    token => self.peer_ready(token, event.readiness())
+                }
+            }
+            self.add_handshaken();
+            self.check_hashed();
+            self.check_priorities();
+            self.check_requests();
+            self.connect_more();
+            if self.last_choke.elapsed() > Duration::from_secs(choker::CHOKE_INTERVAL_SECS) {
+                self.run_choker();
+            }
+            if self.last_pex.elapsed() > Duration::from_secs(pex::PEX_INTERVAL_SECS) {
+                self.last_pex = Instant::now();
+                self.send_pex();
+            }
+        }
+        let tokens: Vec<Token> = self.peers.keys().cloned().collect();
+        for token in tokens {
+            self.drop_peer(token);
+        }
+        //finish checking the pieces that were complete, they don't need saving
+        while let Some(hashed) = self.hash_pool.recv() {
+            self.piece_hashed(hashed);
+        }
+        self.save_partial()
+    }
+
+    ///writes the blocks we have of unfinished pieces to the file, and returns
+    ///which ones they are. Pieces that couldn't be written are left out
+    fn save_partial(&mut self) -> Vec<(u32, BitVector)> {
+        let mut partial = Vec::new();
+        let torrent_file = &mut self.torrent_file;
+        for piece in self.working_pieces.iter().filter(|piece| !piece.obtained_blocks().is_empty()) {
+            let offset = piece.index() as u64 * self.piece_len;
+            let written = piece.obtained_data().into_iter()
+                .all(|(begin, data)| torrent_file.write(offset + begin as u64, data).is_ok());
+            if written {
+                partial.push((piece.index(), piece.obtained_blocks().clone()));
+            }
+        }
+        if self.torrent_file.flush().is_err() {
+            return Vec::new()
+        }
+        partial
+    }
+
+    ///Reads and handles everything a peer sent, and sends what it was waiting
+    ///to send. Peers with errors are dropped
+    fn peer_ready(&mut self, token: Token, readiness: Ready) {
+        //take the peer out while we work on it, so the rest of the session can be used alongside it
+        let mut peer = match self.peers.remove(&token) {
+            Some(peer) => peer,
+            None => return
+        };
+        let result = self.service_peer(&mut peer, readiness);
+        self.peers.insert(token, peer);
+        if result.is_err() {
+            self.drop_peer(token);
+        }
+    }
+
+    ///Sends whatever a peer was waiting to send, handles every message it sent us,
+    ///serves its requests and keeps our request pipeline full
+    fn service_peer(&mut self, peer: &mut LivePeer, readiness: Ready) -> BoostResult<()> {
+        if readiness.is_writable() {
+            peer.flush()?;
+        }
+        if readiness.is_readable() {
+            for message in peer.read_messages()? {
+                self.handle_message(peer, message)?;
+            }
+        }
+        self.serve_requests(peer)?;
+        self.request_blocks(peer)
+    }
+
+    ///Updates the session and a peer from one message the peer sent
+    fn handle_message(&mut self, peer: &mut LivePeer, message: BitTorrentMessage) -> BoostResult<()> {
+        match message {
+            BitTorrentMessage::KeepAlive => (),
+            BitTorrentMessage::Choke => {
+                let dropped = peer.choked_by_peer();
+                self.release_blocks(&dropped);
+            },
+            BitTorrentMessage::Unchoke => peer.unchoked_by_peer(),
+            BitTorrentMessage::Interested => peer.set_interested_in_me(true),
+            BitTorrentMessage::NotInterested => peer.set_interested_in_me(false),
+            BitTorrentMessage::Have(piece) => {
+                let had = peer.has_piece(piece);
+                peer.handle_have(piece)?;
+                if !had {
+                    self.picker.peer_has(piece);
+                }
+                peer.update_interest(&self.wanted_pieces())?;
+            },
+            BitTorrentMessage::Bitfield(ref bitfield) => {
+                //the bitfield replaces whatever the peer told us before
+                self.picker.remove_peer(peer.pieces());
+                let result = peer.handle_bitfield(bitfield);
+                self.picker.add_peer(peer.pieces());
+                result?;
+                peer.update_interest(&self.wanted_pieces())?;
+            },
+            BitTorrentMessage::Request { piece_index, begin, length } => self.queue_upload(peer, piece_index, begin, length)?,
+            BitTorrentMessage::Cancel { piece_index, begin, length } => peer.cancel_request(piece_index, begin, length)?,
+            BitTorrentMessage::Piece { piece_index, begin, ref block } => {
+                //blocks we never asked this peer for are dropped, and a peer that
+                //keeps sending them is dropped too
+                if !peer.block_received(piece_index, begin, block.len() as u32) {
+                    if peer.unrequested_blocks() > peer::MAX_UNREQUESTED_BLOCKS {
+                        return Err(BoostError::BitTorrentProtocolErr(format!("Peer sent {} blocks we didn't ask for", peer.unrequested_blocks())))
+                    }
+                    return Ok(())
+                }
+                self.cancel_duplicates(piece_index, begin, block.len() as u32);
+                self.add_block(peer, piece_index, begin, block)?;
+            },
+            BitTorrentMessage::Extended { id, ref payload } => peer.handle_extended(id, payload, &self.extensions)?,
+            //a rejection of a request we didn't make falls through and is ignored
+            BitTorrentMessage::RejectRequest { piece_index, begin, length }
+                if peer.request_rejected(piece_index, begin, length) => {
+                self.release_blocks(&[(piece_index, begin, length)]);
+            },
+            BitTorrentMessage::HaveAll | BitTorrentMessage::HaveNone => {
+                self.picker.remove_peer(peer.pieces());
+                peer.handle_fast_message(&message);
+                self.picker.add_peer(peer.pieces());
+                peer.update_interest(&self.wanted_pieces())?;
+            },
+            ref msg if msg.is_fast_extension() => peer.handle_fast_message(msg),
+            _ => ()
+        };
+        Ok(())
+    }
+
+    ///Checks a request a peer sent us before queueing it. Requests for blocks that
+    ///aren't inside a piece are a protocol error, requests for pieces we don't have are rejected
+    fn queue_upload(&mut self, peer: &mut LivePeer, piece_index: u32, begin: u32, length: u32) -> BoostResult<()> {
+        if length == 0 || length > peer::MAX_REQUEST_LEN {
+            return Err(BoostError::BitTorrentProtocolErr(format!("Peer requested a block of {} bytes", length)))
+        }
+        if piece_index as usize >= self.num_pieces || begin as u64 + length as u64 > self.piece_size(piece_index) {
+            return Err(BoostError::BitTorrentProtocolErr(format!("Peer requested {} bytes at {} of piece {}, which isn't in the torrent", length, begin, piece_index)))
+        }
+        if self.completed.read().expect("The completed lock was poisoned").index_isset(piece_index as usize) {
+            peer.queue_request(piece_index, begin, length)
+        } else {
+            peer.reject_request(piece_index, begin, length)
+        }
+    }
+
+    ///Sends a peer the blocks it requested, until the socket is full
+    fn serve_requests(&mut self, peer: &mut LivePeer) -> BoostResult<()> {
+        while peer.send_buffer_len() < UPLOAD_BUFFER_LEN {
+            let (piece_index, begin, length) = match peer.next_queued_request() {
+                Some(request) => request,
+                None => break
+            };
+            let piece_size = self.piece_size(piece_index) as usize;
+            let block = self.read_cache.read_block(&mut self.torrent_file, piece_index, piece_index as u64 * self.piece_len,
+                                                   piece_size, begin, length)?;
+            peer.send_block(piece_index, begin, block)?;
+            self.total_uploaded.fetch_add(length as usize, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    ///In endgame mode a block may be requested from several peers. Once one of them
+    ///sends it, the requests to the others are cancelled so they don't send it too.
+    ///The peer that sent it is out of the map while its messages are handled
+    fn cancel_duplicates(&mut self, piece_index: u32, begin: u32, length: u32) {
+        let duplicated = self.working_pieces.iter()
+            .any(|piece| piece.index() == piece_index && piece.has_duplicate_requests(begin));
+        if !duplicated {
+            return
+        }
+        let mut failed = Vec::new();
+        for (&token, peer) in self.peers.iter_mut() {
+            if peer.withdraw_request(piece_index, begin, length).is_err() {
+                failed.push(token);
+            }
+        }
+        for token in failed {
+            self.drop_peer(token);
+        }
+    }
+
+    ///Puts a block a peer sent into its piece. Once the piece is complete it is
+    ///handed to the hash pool to be checked, see check_hashed. Blocks that don't
+    ///fit the piece are an error
+    fn add_block(&mut self, peer: &LivePeer, piece_index: u32, begin: u32, block: &[u8]) -> BoostResult<()> {
+        //blocks of pieces we aren't working on were never requested, or are from a finished piece
+        let idx = match self.working_pieces.iter().position(|piece| piece.index() == piece_index) {
+            Some(idx) => idx,
+            None => return Ok(())
+        };
+        //every peer in the session has an address, it is set during the handshake
+        let supplier = peer.addr.map_or(Ipv4Addr::UNSPECIFIED, |addr| *addr.ip());
+        if !self.working_pieces[idx].add_block(begin, block, supplier)? {
+            return Ok(())
+        }
+        self.total_downloaded.fetch_add(block.len(), Ordering::Relaxed);
+        if self.working_pieces[idx].is_complete() {
+            let piece = self.working_pieces.remove(idx);
+            self.verifying.insert(piece_index, piece.suppliers());
+            let expected = self.piece_hashes[piece_index as usize];
+            self.hash_pool.submit(HashJob { index: piece_index, data: piece.into_data(), expected });
+        }
+        Ok(())
+    }
+
+    ///Takes the pieces the hash pool has checked. Good pieces are written to disk
+    ///and announced, bad ones are thrown away to be downloaded again and the peers
+    ///that sent them are held to account
+    fn check_hashed(&mut self) {
+        while let Some(hashed) = self.hash_pool.try_recv() {
+            self.piece_hashed(hashed);
+        }
+    }
+
+    ///acts on the result of checking one piece
+    fn piece_hashed(&mut self, hashed: Hashed) {
+        let Hashed { index, data, correct } = hashed;
+        let suppliers = self.verifying.remove(&index).unwrap_or_default();
+        if !correct {
+            self.hash_failed(&suppliers);
+            return
+        }
+        if self.write_piece(index, &data).is_err() {
+            //try again later rather than claim a piece we couldn't save
+            return
+        }
+        self.completed.write().expect("The completed lock was poisoned").set_index(index as usize);
+        self.piece_finished(index);
+    }
+
+    ///writes a checked piece to disk
+    fn write_piece(&mut self, index: u32, data: &[u8]) -> BoostResult<()> {
+        self.torrent_file.write(index as u64 * self.piece_len, data)?;
+        //anyone reading the file once the piece is marked complete has to see it
+        self.torrent_file.flush()
+    }
+
+    ///Blames the peers that sent a piece that failed its hash check. A peer that
+    ///sent all of it is banned, otherwise each of them is banned once it has sent
+    ///part of too many bad pieces. Banned peers are disconnected
+    fn hash_failed(&mut self, suppliers: &[Ipv4Addr]) {
+        for &supplier in suppliers {
+            let fails = self.hash_fails.entry(supplier).or_insert(0);
+            *fails += 1;
+            if suppliers.len() == 1 || *fails >= HASH_FAILS_BEFORE_BAN {
+                self.banned.insert(supplier);
+            }
+        }
+        let banned: Vec<Token> = self.peers.iter()
+            .filter(|&(_, peer)| self.is_banned(peer))
+            .map(|(&token, _)| token)
+            .collect();
+        for token in banned {
+            if let Some(peer) = self.peers.get(&token) {
+                println!("Banned {} for sending data that failed the hash check", peer);
+            }
+            self.drop_peer(token);
+        }
+    }
+
+    ///tells whether a peer is at a banned address
+    fn is_banned(&self, peer: &LivePeer) -> bool {
+        peer.addr.is_some_and(|addr| self.banned.contains(addr.ip()))
+    }
+
+    ///Tells every peer we now have a piece, and stops being interested in the
+    ///ones that have nothing left for us
+    fn piece_finished(&mut self, piece: u32) {
+        let wanted = self.wanted_pieces();
+        let mut failed = Vec::new();
+        for (&token, peer) in self.peers.iter_mut() {
+            if peer.send_message(BitTorrentMessage::Have(piece)).and_then(|_| peer.update_interest(&wanted)).is_err() {
+                failed.push(token);
+            }
+        }
+        for token in failed {
+            self.drop_peer(token);
+        }
+    }
+
+    ///Requests blocks from a peer until its pipeline is full, as long as it has
+    ///pieces we want and lets us request them
+    fn request_blocks(&mut self, peer: &mut LivePeer) -> BoostResult<()> {
+        //nothing completes while we request, so what we want stays the same
+        let wanted = self.wanted_pieces();
+        let available = peer.pieces().and(&wanted);
+        while peer.is_interesting() && peer.wants_requests() {
+            match self.next_request(peer, &wanted, &available) {
+                Some(BitTorrentMessage::Request { piece_index, begin, length }) => peer.request_block(piece_index, begin, length)?,
+                _ => break
+            }
+        }
+        Ok(())
+    }
+
+    ///Picks a block for endgame mode, if we are in it: no wanted piece is left to
+    ///start and every block of the pieces being worked on has been requested
+    fn endgame_request(&mut self, peer: &LivePeer, wanted: &BitVector) -> Option<BitTorrentMessage> {
+        let unstarted = wanted.iter_set()
+            .any(|idx| !self.working_pieces.iter().any(|piece| piece.index() as usize == idx)
+                && !self.verifying.contains_key(&(idx as u32)));
+        if unstarted || !self.working_pieces.iter().all(|piece| piece.is_fully_requested()) {
+            return None
+        }
+        self.working_pieces.iter_mut()
+            .filter(|piece| peer.has_piece(piece.index()) && peer.can_request(piece.index()))
+            .find_map(|piece| {
+                let index = piece.index();
+                piece.endgame_request(|begin, length| peer.has_requested(index, begin, length))
+            })
+    }
+
+    ///the pieces we still want: the ones we don't have, except those only in skipped files
+    fn wanted_pieces(&self) -> BitVector {
+        let skipped = self.priorities.read().expect("The priorities lock was poisoned").skipped_pieces();
+        let mut wanted = BitVector::new(self.num_pieces);
+        wanted.set_all();
+        wanted.and_not(&skipped.or(&self.completed.read().expect("The completed lock was poisoned")))
+    }
+
+    ///Once file priorities change, works out again which peers have pieces we
+    ///want and tells the torrent file which files are skipped. Pieces being worked
+    ///on that are skipped now are dropped and their requests cancelled
+    fn check_priorities(&mut self) {
+        let (generation, skipped) = {
+            let priorities = self.priorities.read().expect("The priorities lock was poisoned");
+            let skipped: Vec<bool> = priorities.files().iter().map(|&priority| priority == FilePriority::Skip).collect();
+            (priorities.generation(), skipped)
+        };
+        if self.priorities_seen == Some(generation) {
+            return
+        }
+        self.priorities_seen = Some(generation);
+        self.torrent_file.set_skipped_files(skipped);
+        let wanted = self.wanted_pieces();
+        let dropped: Vec<u32> = self.working_pieces.iter()
+            .map(|piece| piece.index())
+            .filter(|&idx| !wanted.index_isset(idx as usize))
+            .collect();
+        self.working_pieces.retain(|piece| wanted.index_isset(piece.index() as usize));
+        let mut failed = Vec::new();
+        for (&token, peer) in self.peers.iter_mut() {
+            let result = dropped.iter().try_for_each(|&idx| peer.withdraw_piece(idx))
+                .and_then(|_| peer.update_interest(&wanted));
+            if result.is_err() {
+                failed.push(token);
+            }
+        }
+        for token in failed {
+            self.drop_peer(token);
+        }
+    }
+
+    ///Cancels the requests each peer has taken too long to answer, so their blocks
+    ///can go to whichever peer has room for them, then refills the peer's pipeline
+    fn check_requests(&mut self) {
+        let tokens: Vec<Token> = self.peers.keys().cloned().collect();
+        for token in tokens {
+            let mut peer = match self.peers.remove(&token) {
+                Some(peer) => peer,
+                None => continue
+            };
+            let result = peer.cancel_timed_out().and_then(|expired| {
+                self.release_blocks(&expired);
+                self.request_blocks(&mut peer)
+            });
+            self.peers.insert(token, peer);
+            if result.is_err() {
+                self.drop_peer(token);
+            }
+        }
+    }
+
+    ///Makes blocks we requested but won't be getting available to request again
+    fn release_blocks(&mut self, requests: &[(u32, u32, u32)]) {
+        for &(piece_index, begin, _) in requests {
+            if let Some(piece) = self.working_pieces.iter_mut().find(|piece| piece.index() == piece_index) {
+                piece.release_block(begin);
+            }
+        }
+    }
+
+    ///Picks the next block to request from a peer. Pieces already being worked on
+    ///are finished first, otherwise the picker chooses a new piece the peer has to start.
+    ///Once every block left has been requested we are in endgame mode, and blocks
+    ///already requested from slower peers are requested from this one too
+    fn next_request(&mut self, peer: &LivePeer, wanted: &BitVector, available: &BitVector) -> Option<BitTorrentMessage> {
+        for piece in self.working_pieces.iter_mut().filter(|piece| peer.has_piece(piece.index()) && peer.can_request(piece.index())) {
+            if let Some(request) = piece.next_request() {
+                return Some(request)
+            }
+        }
+        let index = {
+            let completed = self.completed.read().expect("The completed lock was poisoned");
+            let priorities = self.priorities.read().expect("The priorities lock was poisoned");
+            let working: Vec<u32> = self.working_pieces.iter().map(|piece| piece.index())
+                .chain(self.verifying.keys().cloned())
+                .collect();
+            self.picker.pick(&completed, &working, &priorities, |idx| available.index_isset(idx as usize) && peer.can_request(idx), &mut rand::thread_rng())
+        };
+        let index = match index {
+            Some(index) => index,
+            None => return self.endgame_request(peer, wanted)
+        };
+        let mut piece = Piece::new(index, self.piece_size(index) as u32);
+        let request = piece.next_request();
+        self.working_pieces.push(piece);
+        request
+    }
+
+    ///the size of a piece in bytes, the last piece may be shorter than the rest
+    fn piece_size(&self, index: u32) -> u64 {
+        let start = index as u64 * self.piece_len;
+        if self.total_len - start < self.piece_len { self.total_len - start } else { self.piece_len }
+    }
+
+    ///Disconnects a peer and forgets about it
+    fn drop_peer(&mut self, token: Token) {
+        if let Some(mut peer) = self.peers.remove(&token) {
+            let requests = peer.take_requests();
+            self.release_blocks(&requests);
+            self.picker.remove_peer(peer.pieces());
+            let _ = self.poll.deregister(peer.socket.get_ref());
+            let _ = peer.socket.get_ref().shutdown(net::Shutdown::Both);
+            self.extensions.peer_disconnected(&peer.id);
+        }
+    }
+
+    ///Accepts every peer waiting on the listener and starts its handshake, up to
+    ///the incoming connection limit, which is separate from the outgoing one
+    fn accept_all(&mut self) {
+        loop {
+            let stream = match self.listener.accept_std() {
+                Ok((stream, _)) => stream,
+                Err(ref err) if err.kind() == ErrorKind::WouldBlock => return,
+                Err(_) => continue
+            };
+            let incoming_count = self.peers.values().filter(|peer| peer.is_incoming()).count() + self.accepting;
+            if incoming_count >= MAX_INCOMING_PEERS || stream.set_nonblocking(false).is_err() {
+                let _ = stream.shutdown(net::Shutdown::Both);
+                continue
+            }
+            self.accepting += 1;
+            let my_id = self.my_id.clone();
+            let info_hash = self.info_hash;
+            let num_pieces = self.num_pieces;
+            let encryption = self.encryption;
+            let handshake_sender = self.handshake_sender.clone();
+            thread::spawn(move || {
+                let peer = accept_peer(stream, my_id.as_bytes(), info_hash, num_pieces, encryption);
+                let _ = handshake_sender.send(Handshaken { incoming: true, peer });
+            });
+        }
+    }
+
+    ///Starts handshakes with potential peers until we have as many outgoing
+    ///connections as we want
+    fn connect_more(&mut self) {
+        let outgoing_count = self.peers.values().filter(|peer| !peer.is_incoming()).count() + self.connecting;
+        for _ in outgoing_count..MAX_OUTGOING_PEERS {
+            //take the potential peer out so we don't try to connect to the same one again later
+            let potential_peer = match self.potential_peers.write().expect("The potential peers lock was poisoned").pop() {
+                Some(potential_peer) => potential_peer,
+                None => return
+            };
+            if self.banned.contains(potential_peer.addr.ip()) {
+                continue
+            }
+            self.connecting += 1;
+            let bind_ip = self.bind_ip;
+            let my_id = self.my_id.clone();
+            let info_hash = self.info_hash;
+            let num_pieces = self.num_pieces;
+            let encryption = self.encryption;
+            let handshake_sender = self.handshake_sender.clone();
+            thread::spawn(move || {
+                let peer = connect_to_peer(&potential_peer, bind_ip, my_id.as_bytes(), &info_hash, num_pieces, encryption);
+                let _ = handshake_sender.send(Handshaken { incoming: false, peer });
+            });
+        }
+    }
+
+    ///Takes the peers whose handshakes finished and adds them to the event loop
+    fn add_handshaken(&mut self) {
+        while let Ok(handshaken) = self.handshake_receiver.try_recv() {
+            if handshaken.incoming {
+                self.accepting -= 1;
+            } else {
+                self.connecting -= 1;
+            }
+            let peer = match handshaken.peer {
+                Some(peer) => peer,
+                None => continue
+            };
+            //we may already be connected to this peer at another address, or it may have
+            //connected to us from a banned one
+            if self.peers.values().any(|active_peer| active_peer.id == peer.id)
+                || peer.addr.is_some_and(|addr| self.banned.contains(addr.ip())) {
+                continue
+            }
+            let mut peer = match peer.map_socket(|socket| socket.map_inner(|stream| {
+                stream.set_nonblocking(true)?;
+                TcpStream::from_stream(stream)
+            }).map_err(|_| BoostError::EventLoopErr)) {
+                Ok(peer) => peer,
+                Err(_) => continue
+            };
+            let token = Token(self.next_token);
+            self.next_token += 1;
+            if self.poll.register(peer.socket.get_ref(), token, Ready::readable() | Ready::writable(), PollOpt::edge()).is_err() {
+                continue
+            }
+            if self.greet(&mut peer).is_err() {
+                let _ = self.poll.deregister(peer.socket.get_ref());
+                continue
+            }
+            self.peers.insert(token, peer);
+            //the peer may have sent more than its handshake already
+            self.peer_ready(token, Ready::readable() | Ready::writable());
+        }
+    }
+
+    ///Sends a peer what it needs to know right after the handshake: which pieces
+    ///we have, then whatever the negotiated extensions call for
+    fn greet(&mut self, peer: &mut LivePeer) -> BoostResult<()> {
+        peer.send_have_set(&self.completed.read().expect("The completed lock was poisoned"))?;
+        if peer.supports_extensions() {
+            peer.send_extended_handshake(&self.ext_handshake)?;
+        }
+        peer.send_allowed_fast(&self.info_hash, self.num_pieces)
+    }
+
+    ///Works out every peer's rates since the last round and lets the choker pick
+    ///who to unchoke, then chokes and unchokes peers to match
+    fn run_choker(&mut self) {
+        let elapsed = self.last_choke.elapsed();
+        let secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+        self.last_choke = Instant::now();
+        let candidates: Vec<ChokeCandidate<Token>> = self.peers.iter_mut().map(|(&token, peer)| {
+            let (downloaded, uploaded) = peer.take_transfer_counts();
+            ChokeCandidate {
+                key: token,
+                interested: peer.is_interested_in_me(),
+                snubbed: peer.is_snubbing(),
+                download_rate: downloaded as f64 / secs,
+                upload_rate: uploaded as f64 / secs,
+                is_new: peer.is_new()
+            }
+        }).collect();
+        //once we have everything we want, there is nobody to trade with
+        let seeding = self.wanted_pieces().is_empty();
+        let unchoked = self.choker.run_round(&candidates, seeding, &mut rand::thread_rng());
+        let mut failed = Vec::new();
+        for (&token, peer) in self.peers.iter_mut() {
+            let result = match (unchoked.contains(&token), peer.is_choking()) {
+                (true, true) => peer.unchoke(),
+                (false, false) => peer.choke(),
+                _ => Ok(())
+            };
+            if result.is_err() {
+                failed.push(token);
+            }
+        }
+        for token in failed {
+            self.drop_peer(token);
+        }
+    }
+
+    ///Tells every peer that supports ut_pex which peers we are connected to
+    fn send_pex(&mut self) {
+        let pex = match self.pex {
+            Some(ref pex) => pex.clone(),
+            None => return
+        };
+        //everyone we could tell other peers about
+        let connected: Vec<(SocketAddrV4, u8)> = self.peers.values()
+            .filter_map(|peer| peer.listen_addr().map(|addr| (addr, pex::peer_flags(peer))))
+            .collect();
+        let mut failed = Vec::new();
+        for (&token, peer) in self.peers.iter_mut() {
+            if !peer.supports_extension(pex::PEX_NAME) {
+                continue
+            }
+            //don't tell a peer about itself
+            let own_addr = peer.listen_addr();
+            let others: Vec<(SocketAddrV4, u8)> = connected.iter()
+                .filter(|&&(addr, _)| Some(addr) != own_addr)
+                .cloned()
+                .collect();
+            if let Some(payload) = pex.prepare_message(&peer.id, &others) {
+                if peer.send_extension_message(pex::PEX_NAME, payload).is_err() {
+                    failed.push(token);
+                }
+            }
+        }
+        for token in failed {
+            self.drop_peer(token);
+        }
+    }
+}
+
+///connects to a potential peer and does the handshake, retrying in plaintext
+///if the policy allows and the peer won't encrypt
+fn connect_to_peer(potential_peer: &PotentialPeer,
+                   bind_ip: Ipv4Addr,
+                   my_id: &[u8],
+                   info_hash: &[u8],
+                   num_pieces: usize,
+                   encryption: EncryptionPolicy) -> Option<Peer> {
+    let mut peer = match encryption {
+        //peers that don't do encryption hang up on the key exchange, so try again in plaintext
+        EncryptionPolicy::Preferred => open_session(potential_peer, bind_ip, my_id, info_hash, num_pieces, EncryptionPolicy::Preferred)
+            .or_else(|| open_session(potential_peer, bind_ip, my_id, info_hash, num_pieces, EncryptionPolicy::Disabled))?,
+        _ => open_session(potential_peer, bind_ip, my_id, info_hash, num_pieces, encryption)?
+    };
+    peer.addr = Some(potential_peer.addr);
+    Some(peer)
+}
+
+///opens a connection to a potential peer and does the encryption and BitTorrent handshakes
+fn open_session(potential_peer: &PotentialPeer,
+                bind_ip: Ipv4Addr,
+                my_id: &[u8],
+                info_hash: &[u8],
+                num_pieces: usize,
+                encryption: EncryptionPolicy) -> Option<Peer> {
+    let stream = connect_from(bind_ip, potential_peer.addr, Duration::from_secs(peer::CONNECT_TIMEOUT_SECS)).ok()?;
+    //don't let a peer that never answers the handshake hold us up
+    let handshake_timeout = Some(Duration::from_secs(peer::HANDSHAKE_TIMEOUT_SECS));
+    stream.set_read_timeout(handshake_timeout).ok()?;
+    stream.set_write_timeout(handshake_timeout).ok()?;
+    let stream = mse::connect(stream, info_hash, encryption).ok()?;
+    let peer = Peer::start_session(stream, my_id, info_hash, num_pieces, potential_peer.id, false).ok()?;
+    peer.socket.get_ref().set_read_timeout(None).ok()?;
+    peer.socket.get_ref().set_write_timeout(None).ok()?;
+    Some(peer)
+}
+
+///connects to addr from bind_ip, giving up if the connection takes longer than the timeout
+fn connect_from(bind_ip: Ipv4Addr, addr: SocketAddrV4, timeout: Duration) -> io::Result<net::TcpStream> {
+    let builder = TcpBuilder::new_v4()?;
+    builder.bind(SocketAddrV4::new(bind_ip, 0))?;
+    let stream = builder.to_tcp_stream()?;
+    //start connecting without blocking, then wait until the socket is writable
+    stream.set_nonblocking(true)?;
+    match TcpStreamExt::connect(&stream, addr) {
+        Ok(()) => (),
+        Err(ref err) if err.raw_os_error() == Some(libc::EINPROGRESS) => {
+            let mut pollfd = libc::pollfd { fd: stream.as_raw_fd(), events: libc::POLLOUT, revents: 0 };
+            let millis = timeout.as_secs() * 1000 + u64::from(timeout.subsec_millis());
+            match unsafe { libc::poll(&mut pollfd, 1, millis as libc::c_int) } {
+                1 => (),
+                0 => return Err(io::Error::new(ErrorKind::TimedOut, "Connecting timed out")),
+                _ => return Err(io::Error::last_os_error())
+            }
+            //the socket is writable whether the connection worked or not
+            if let Some(err) = stream.take_error()? {
+                return Err(err)
+            }
+        },
+        Err(err) => return Err(err)
+    }
+    stream.set_nonblocking(false)?;
+    Ok(stream)
+}
+
+///does the encryption and BitTorrent handshakes with a peer that connected to
+///our listener, accepting whatever the encryption policy allows. The peer's
+///handshake is read first, and it is dropped if it asks for a torrent we don't have
+fn accept_peer(stream: net::TcpStream,
+               my_id: &[u8],
+               info_hash: [u8; 20],
+               num_pieces: usize,
+               encryption: EncryptionPolicy) -> Option<Peer> {
+    let addr = match stream.peer_addr() {
+        Ok(SocketAddr::V4(addr)) => addr,
+        _ => return None
+    };
+    let handshake_timeout = Some(Duration::from_secs(peer::HANDSHAKE_TIMEOUT_SECS));
+    stream.set_read_timeout(handshake_timeout).ok()?;
+    stream.set_write_timeout(handshake_timeout).ok()?;
+    let (stream, encrypted_for) = mse::accept(stream, &[info_hash], encryption).ok()?;
+    let lookup = |requested: &[u8; 20]| if *requested == info_hash { Some(num_pieces) } else { None };
+    let (mut peer, requested) = Peer::accept_session(stream, my_id, lookup).ok()?;
+    //the key exchange already named a torrent, the handshake has to agree
+    if encrypted_for.is_some_and(|encrypted_for| encrypted_for != requested) {
+        return None
+    }
+    peer.socket.get_ref().set_read_timeout(None).ok()?;
+    peer.socket.get_ref().set_write_timeout(None).ok()?;
+    peer.addr = Some(addr);
+    Some(peer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::IpAddr;
+
+    fn listen() -> (net::TcpListener, SocketAddrV4) {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        match listener.local_addr().unwrap() {
+            SocketAddr::V4(addr) => (listener, addr),
+            SocketAddr::V6(_) => panic!("Listening on IPv6")
+        }
+    }
+
+    #[test]
+    fn connects_from_the_bind_address() {
+        let (listener, addr) = listen();
+        let stream = connect_from(Ipv4Addr::new(127, 0, 0, 2), addr, Duration::from_secs(5)).unwrap();
+        let (_, from) = listener.accept().unwrap();
+        assert_eq!(from.ip(), IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2)));
+        assert_eq!(stream.peer_addr().unwrap(), SocketAddr::V4(addr));
+    }
+
+    #[test]
+    fn connecting_fails() {
+        let (listener, addr) = listen();
+        drop(listener);
+        assert!(connect_from(Ipv4Addr::new(127, 0, 0, 1), addr, Duration::from_secs(5)).is_err());
+        //an address that isn't on this machine can't be bound to
+        let (_listener, addr) = listen();
+        assert!(connect_from(Ipv4Addr::new(192, 0, 2, 1), addr, Duration::from_secs(5)).is_err());
+    }
+}
@@ -0,0 +1,157 @@
+use std::io::{self, Read, Seek, SeekFrom};
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+use bitvector::BitVector;
+use meta::MetaInfo;
+use torrentfile::TorrentFile;
+
+///How often a reader waiting on a piece checks whether it arrived
+const WAIT_MILLIS: u64 = 100;
+
+///Reads the torrent's data in order while it downloads, waiting for each piece
+///to be verified before reading from it. Wherever the reader is tells the
+///sequential picker which pieces are needed next, so it should be used along
+///with PickMode::Sequential sharing the same playhead
+pub struct StreamReader {
+    torrent_file: TorrentFile,
+    completed: Arc<RwLock<BitVector>>,
+    playhead: Arc<AtomicUsize>,
+    piece_len: u64,
+    total_len: u64,
+    pos: u64,
+    wrap_up: Arc<AtomicBool>
+}
+
+impl StreamReader {
+    ///Reads the files the torrent is downloading into, see TorrentFile.
+    ///Reads give up once wrap_up is set
+    pub fn new(meta_info: &MetaInfo,
+               completed: Arc<RwLock<BitVector>>,
+               playhead: Arc<AtomicUsize>,
+               wrap_up: Arc<AtomicBool>) -> Self {
+        playhead.store(0, Ordering::Relaxed);
+        StreamReader {
+            torrent_file: TorrentFile::open(meta_info),
+            completed,
+            playhead,
+            piece_len: meta_info.piece_len,
+            total_len: meta_info.file_info.total_bytes(),
+            pos: 0,
+            wrap_up
+        }
+    }
+
+    ///waits until the piece is verified and on disk, or it is time to wrap up.
+    ///Not Interrupted, which io::copy and read_exact would retry forever
+    fn wait_for_piece(&self, piece: usize) -> io::Result<()> {
+        while !self.completed.read().expect("The completed lock was poisoned").index_isset(piece) {
+            if self.wrap_up.load(Ordering::Relaxed) {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "The download stopped before the piece arrived"))
+            }
+            thread::sleep(Duration::from_millis(WAIT_MILLIS));
+        }
+        Ok(())
+    }
+}
+
+impl Read for StreamReader {
+    ///Reads from the current position up to the end of its piece at most,
+    ///blocking until that piece has been downloaded
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.total_len || buf.is_empty() {
+            return Ok(0)
+        }
+        let piece = (self.pos / self.piece_len) as usize;
+        self.playhead.store(piece, Ordering::Relaxed);
+        self.wait_for_piece(piece)?;
+        let piece_end = ((piece as u64 + 1) * self.piece_len).min(self.total_len);
+        let len = buf.len().min((piece_end - self.pos) as usize);
+        let read = self.torrent_file.read(self.pos, &mut buf[0..len])
+            .map_err(|err| io::Error::other(err.to_string()))?;
+        self.pos += read as u64;
+        Ok(read)
+    }
+}
+
+impl Seek for StreamReader {
+    ///Moves the reader, and the playhead along with it so the picker starts on
+    ///the pieces there
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.total_len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset)
+        };
+        let new_pos = new_pos.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Seeked before the start of the torrent"))?;
+        self.pos = new_pos;
+        if new_pos < self.total_len {
+            self.playhead.store((new_pos / self.piece_len) as usize, Ordering::Relaxed);
+        }
+        Ok(new_pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use meta::FileInfo;
+    use std::env;
+    use std::fs;
+    use std::sync::mpsc;
+
+    ///a reader over a file of 3 pieces of 4 bytes and one of 2, with the given pieces done
+    fn reader(name: &str, done: &[usize]) -> (StreamReader, Arc<AtomicUsize>, Arc<AtomicBool>) {
+        let path = env::temp_dir().join(format!("boost-stream-{}-{}", name, ::std::process::id()));
+        fs::write(&path, (0..14).collect::<Vec<u8>>()).unwrap();
+        let mut completed = BitVector::new(4);
+        for &piece in done {
+            completed.set_index(piece);
+        }
+        let playhead = Arc::new(AtomicUsize::new(0));
+        let wrap_up = Arc::new(AtomicBool::new(false));
+        let meta_info = MetaInfo {
+            announce_url: None,
+            piece_len: 4,
+            info_hash: [0; 20],
+            piece_hashes: vec![[0; 20]; 4],
+            file_info: FileInfo::Single { filename: path.to_string_lossy().into_owned(), filelength: 14 },
+            private: false,
+            nodes: Vec::new()
+        };
+        let reader = StreamReader::new(&meta_info, Arc::new(RwLock::new(completed)), playhead.clone(), wrap_up.clone());
+        (reader, playhead, wrap_up)
+    }
+
+    #[test]
+    fn reads_finished_pieces_in_order() {
+        let (mut reader, playhead, _) = reader("order", &[0, 1, 2, 3]);
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data).unwrap();
+        assert_eq!(data, (0..14).collect::<Vec<u8>>());
+        assert_eq!(playhead.load(Ordering::Relaxed), 3);
+        assert_eq!(reader.seek(SeekFrom::End(-6)).unwrap(), 8);
+        assert_eq!(playhead.load(Ordering::Relaxed), 2);
+        let mut buf = [0; 10];
+        assert_eq!(reader.read(&mut buf).unwrap(), 4);
+        assert_eq!(&buf[..4], &[8, 9, 10, 11]);
+        assert!(reader.seek(SeekFrom::Current(-20)).is_err());
+    }
+
+    #[test]
+    fn copying_stops_when_the_download_does() {
+        let (mut reader, playhead, wrap_up) = reader("wrap-up", &[0]);
+        let (done, copied) = mpsc::channel();
+        thread::spawn(move || {
+            let _ = done.send(io::copy(&mut reader, &mut io::sink()).map_err(|err| err.kind()));
+        });
+        //the copy gets through the first piece and waits on the second
+        thread::sleep(Duration::from_millis(3 * WAIT_MILLIS));
+        assert_eq!(playhead.load(Ordering::Relaxed), 1);
+        assert!(copied.try_recv().is_err());
+        wrap_up.store(true, Ordering::Relaxed);
+        let result = copied.recv_timeout(Duration::from_secs(5)).expect("The copy didn't return");
+        assert_eq!(result, Err(io::ErrorKind::UnexpectedEof));
+    }
+}
@@ -1,98 +1,180 @@
-use std::fs::{File, create_dir_all, create_dir};
+use std::fs::{File, OpenOptions, create_dir_all};
 use std::io::prelude::*;
-use std::io::{BufWriter, SeekFrom};
+use std::io::SeekFrom;
+use std::path::Path;
 use meta::{MetaInfo, FileInfo};
 use error::{BoostError, BoostResult};
 
-///A struct that holds the reader and writer for the torrent file.
-///Writes will be buffered until a read.
-///Both Reads and Writes are random access
+///One of the torrent's files as it is on disk
+struct DataFile {
+    path: String,
+    ///where the file starts in the torrent's data
+    start: u64,
+    ///how long the metafile says it is
+    len: u64,
+    ///the file, once it is there
+    file: Option<File>,
+    ///whether the file was opened for writing yet
+    writable: bool
+}
+
+impl DataFile {
+    ///opens the file if it is there now, it may have been created since
+    fn readable(&mut self) -> Option<&mut File> {
+        if self.file.is_none() {
+            self.file = File::open(&self.path).ok();
+        }
+        self.file.as_mut()
+    }
+
+    ///opens the file for writing, creating it and the directories it is in
+    ///first if need be. A skipped file only gets the parts of the pieces it
+    ///shares with wanted files, anything else is given all of its space
+    fn writable(&mut self, skipped: bool) -> BoostResult<&mut File> {
+        if !self.writable {
+            if let Some(dir) = Path::new(&self.path).parent() {
+                let _ = create_dir_all(dir);
+            }
+            let file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&self.path)
+                .map_err(|_| BoostError::FileOpenErr(self.path.clone()))?;
+            //nothing is written yet so the file is sparse. A file that is already the
+            //right size is left alone so its modification time stays put for resuming
+            if !skipped && file.metadata().map(|metadata| metadata.len()).ok() != Some(self.len) {
+                file.set_len(self.len).map_err(|_| BoostError::TorrentFileAllocationErr)?;
+            }
+            self.file = Some(file);
+            self.writable = true;
+        }
+        Ok(self.file.as_mut().expect("The file was just opened"))
+    }
+}
+
+///Reads and writes the torrent's data right where it ends up: the one file, or
+///each file of a multifile torrent under its root directory. Offsets are into
+///all the files back to back, so a piece spanning files is read and written
+///in each in turn. Both Reads and Writes are random access
 pub struct TorrentFile {
-    file_reader: File,
-    file_writer: BufWriter<File>,
-    meta: MetaInfo
+    files: Vec<DataFile>,
+    ///which files of a multifile torrent are skipped and don't take up space
+    skipped: Vec<bool>
 }
 
 impl TorrentFile {
-    ///Given a metainfo, creates a torrent file with all the space
-    ///needed for the torrent
-    pub fn init(meta: MetaInfo) -> BoostResult<Self> {
-        //working name is filename or root directory of multifile structure
-        let working_name = match &meta.file_info {
-            &FileInfo::Single {ref filename, ..} => filename.clone(),
-            &FileInfo::Multi {ref rootdir,..} => rootdir.clone()
-        };
-        //working len is the file len or the sum of all the file len
-        let working_len = meta.file_info.total_bytes();
-        //create the file
-        let writer = File::create(&working_name).map_err(|_| BoostError::FileOpenErr(working_name.clone()))?;
-        //set file size
-        let _ = writer.set_len(working_len).map_err(|_| BoostError::TorrentFileAllocationErr)?;
-        //get buffered writer withbuffer capacity for 10 whole pieces
-        let file_writer = BufWriter::with_capacity(meta.piece_len as usize * 10, writer);
-        let file_reader = File::open(&working_name).map_err(|_|
-                                                            BoostError::FileOpenErr(working_name.clone()))?;
+    ///Opens whichever of the torrent's files are already there, so a previous
+    ///run's data can be resumed or checked. The rest are created when
+    ///something is first written to them
+    pub fn open(meta: &MetaInfo) -> Self {
+        let mut start = 0;
+        let files = TorrentFile::data_paths(meta).into_iter().map(|(path, len)| {
+            let file = File::open(&path).ok();
+            let data_file = DataFile { path, start, len, file, writable: false };
+            start += len;
+            data_file
+        }).collect();
+        TorrentFile { files, skipped: Vec::new() }
+    }
+
+    ///the files the torrent's data ends up in and their lengths, in order. A
+    ///multifile torrent's files are under its root directory
+    pub fn data_paths(meta: &MetaInfo) -> Vec<(String, u64)> {
+        match meta.file_info {
+            FileInfo::Single { ref filename, filelength } => vec![(filename.clone(), filelength)],
+            FileInfo::Multi { ref rootdir, ref files } => files.iter()
+                .map(|file| match *file {
+                    FileInfo::Single { ref filename, filelength } => (format!("{}/{}", rootdir, filename), filelength),
+                    FileInfo::Multi { ref rootdir, .. } => (rootdir.clone(), file.total_bytes())
+                })
+                .collect()
+        }
+    }
 
-        Ok(TorrentFile { file_reader, file_writer, meta })
+    ///writes all of the buffer into the files at the given offset in the torrent's data
+    pub fn write(&mut self, offset: u64, buffer: &[u8]) -> BoostResult<usize> {
+        let mut written = 0;
+        for (idx, data_file) in self.files.iter_mut().enumerate().filter(|(_, file)| file.start + file.len > offset) {
+            if written == buffer.len() {
+                break
+            }
+            let file_offset = offset + written as u64 - data_file.start;
+            let len = (buffer.len() - written).min((data_file.len - file_offset) as usize);
+            let skipped = self.skipped.get(idx).cloned().unwrap_or(false);
+            let path = data_file.path.clone();
+            let file = data_file.writable(skipped)?;
+            file.seek(SeekFrom::Start(file_offset))
+                .and_then(|_| file.write_all(&buffer[written..written + len]))
+                .map_err(|_| BoostError::FileWriteErr(path))?;
+            written += len;
+        }
+        Ok(written)
     }
 
-    ///writes into the file from the buffer to the given offset in the file
-    pub fn write(&mut self, offset: u64, buffer: &[u8]) -> BoostResult<usize>{
-        let _ = self.file_writer.seek(SeekFrom::Start(offset));
-        self.file_reader.write(buffer).map_err(|_| BoostError::FileWriteErr(
-            match &self.meta.file_info {
-                &FileInfo::Single {ref filename, ..} => filename.clone(),
-                &FileInfo::Multi {ref rootdir,..} => rootdir.clone()
+    ///Sets which files are skipped, by index in the metafile. They aren't
+    ///given any space unless a piece they share with a wanted file is written
+    pub fn set_skipped_files(&mut self, skipped: Vec<bool>) {
+        self.skipped = skipped;
+    }
+
+    ///makes sure everything written so far is handed to the files
+    pub fn flush(&mut self) -> BoostResult<()> {
+        for data_file in self.files.iter_mut().filter(|file| file.writable) {
+            let path = data_file.path.clone();
+            if let Some(ref mut file) = data_file.file {
+                file.flush().map_err(|_| BoostError::FileWriteErr(path))?;
             }
-        ))
+        }
+        Ok(())
     }
 
-    ///reads into the buffer from the file at the given offset.
-    pub fn read(&mut self, offset: u64, buffer: &mut [u8]) -> BoostResult<usize>{
-        let _ = self.file_writer.flush();
-        let _ = self.file_reader.seek(SeekFrom::Start(offset));
-        self.file_reader.read(buffer).map_err(|_| BoostError::FileReadErr(
-            match &self.meta.file_info {
-                &FileInfo::Single {ref filename, ..} => filename.clone(),
-                &FileInfo::Multi {ref rootdir,..} => rootdir.clone()
+    ///tells whether any of the torrent's files are there at all
+    pub fn has_data(&mut self) -> bool {
+        self.files.iter_mut().any(|file| file.readable().is_some())
+    }
+
+    ///describes each file that is missing or not the length the metafile says
+    pub fn problems(&mut self) -> Vec<String> {
+        self.files.iter_mut().filter_map(|data_file| {
+            let len = data_file.len;
+            let path = data_file.path.clone();
+            match data_file.readable().map(|file| file.metadata().map(|metadata| metadata.len())) {
+                None => Some(format!("{} is missing", path)),
+                Some(Ok(disk_len)) if disk_len != len => Some(format!("{} is {} bytes but should be {} bytes", path, disk_len, len)),
+                Some(_) => None
             }
-        ))
+        }).collect()
     }
-}
 
-impl Drop for TorrentFile {
-    ///will turn the single torrent file into multiple files if the torrent is multifile
-    fn drop(&mut self) {
-        match &self.meta.file_info {
-            &FileInfo::Single {..} => (), //single file, nothing to do
-            &FileInfo::Multi { ref rootdir, ref files } => {
-                let mut xbuf = [0u8;4096];
-                let _ = self.file_reader.seek(SeekFrom::Start(0));
-                //create root directory
-                let _ = create_dir(rootdir) ;
-                for file in files {
-                   if let &FileInfo::Single { ref filename, filelength} = file {
-                       //if the file is part of a path, make sure the entire path exists
-                        if let Some(idx) = filename.rfind("/") {
-                            let (path, file) = filename.split_at(idx);
-                            let _ = create_dir_all(path);
-                        }
-                        //open up a new file
-                        if let Ok(mut new_file) = File::create(rootdir.clone() + "/" + filename) {
-                            let rem = (filelength % 4096) as usize;
-                            //copy from master file to new file in 4096 byte blocks
-                            for idx in 0..(filelength/4096) {
-                                let _ = self.file_reader.read(&mut xbuf);
-                                let _ = new_file.write(&xbuf);
-                            }
-                            //copy the last block
-                            let _ = self.file_reader.read(&mut xbuf[0..rem]);
-                            let _ = new_file.write(&xbuf[0..rem]);
-                        }
-                        //new file dropped here, closes
-                   }
+    ///reads into the buffer from the given offset in the torrent's data. The
+    ///buffer is filled unless a missing or short file comes first
+    pub fn read(&mut self, offset: u64, buffer: &mut [u8]) -> BoostResult<usize> {
+        let mut total = 0;
+        for data_file in self.files.iter_mut().filter(|file| file.start + file.len > offset) {
+            if total == buffer.len() {
+                break
+            }
+            let file_offset = offset + total as u64 - data_file.start;
+            let wanted = (buffer.len() - total).min((data_file.len - file_offset) as usize);
+            if wanted == 0 {
+                continue
+            }
+            let path = data_file.path.clone();
+            let file = match data_file.readable() {
+                Some(file) => file,
+                None => break
+            };
+            file.seek(SeekFrom::Start(file_offset)).map_err(|_| BoostError::FileReadErr(path.clone()))?;
+            let mut read = 0;
+            while read < wanted {
+                match file.read(&mut buffer[total + read..total + wanted]) {
+                    Ok(0) => break,
+                    Ok(len) => read += len,
+                    Err(_) => return Err(BoostError::FileReadErr(path))
                 }
             }
+            total += read;
+            if read < wanted {
+                break
+            }
         }
+        Ok(total)
     }
 }
@@ -1,4 +1,4 @@
-use std::net::{TcpStream, SocketAddrV4, Ipv4Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::net::{SocketAddrV4, Ipv4Addr, SocketAddr, ToSocketAddrs, UdpSocket};
 use byteorder::{NetworkEndian, ByteOrder};
 use bencode::BencodeValue;
 use regex::Regex;
@@ -7,6 +7,8 @@ use std::str;
 use std::io::{Read, Write};
 use rand;
 use error::{BoostError, BoostResult};
+use net2::TcpBuilder;
+use compact;
 
 
 #[derive(Debug)]
@@ -31,17 +33,22 @@ pub enum TrackerEvent {
     Completed
 }
 
+///What we tell a tracker about ourselves and how the torrent is going
+pub struct Announce<'a> {
+    pub info_hash: &'a [u8],
+    pub peer_id: &'a [u8],
+    pub listen_port: u16,
+    pub uploaded_bytes: u64,
+    pub downloaded_bytes: u64,
+    pub bytes_left: u64,
+    pub event: TrackerEvent,
+    pub tracker_id: Option<String>
+}
+
 impl TrackerInfo {
-    ///send a request to the tracker at the given url, regardless of UDP or HTTP
-    pub fn tracker_request(url: &str,
-                           info_hash: &[u8],
-                           peer_id: &[u8],
-                           listen_port: u16,
-                           uploaded_bytes: u64,
-                           downloaded_bytes: u64,
-                           bytes_left: u64,
-                           event: TrackerEvent,
-                           tracker_id: Option<String>) -> BoostResult<Self> {
+    ///send a request to the tracker at the given url, regardless of UDP or HTTP.
+    ///Either way the request is sent from bind_ip
+    pub fn tracker_request(url: &str, bind_ip: Ipv4Addr, announce: Announce) -> BoostResult<Self> {
 
 
         //regext to match url, capture proto, domain, port and location
@@ -60,11 +67,9 @@ impl TrackerInfo {
 
 
         if proto == "udp" {
-            udp_tracker_request(ip, info_hash, peer_id, listen_port, uploaded_bytes, downloaded_bytes,
-                                bytes_left, event)
+            udp_tracker_request(ip, bind_ip, announce)
         } else if proto == "http" {
-            http_tracker_request(ip, domain, location, info_hash, peer_id, listen_port, uploaded_bytes, downloaded_bytes,
-                                 bytes_left, event, tracker_id)
+            http_tracker_request(ip, bind_ip, domain, location, announce)
         } else {
             Err(BoostError::TrackerURLParseErr)
         }
@@ -72,17 +77,10 @@ impl TrackerInfo {
 }
 
 ///Performs a UDP tracker request to the given address
-fn udp_tracker_request(server: SocketAddrV4,
-                       info_hash: &[u8],
-                       peer_id: &[u8],
-                       listen_port: u16,
-                       uploaded_bytes: u64,
-                       downloaded_bytes: u64,
-                       bytes_left: u64,
-                       event: TrackerEvent) -> BoostResult<TrackerInfo> {
+fn udp_tracker_request(server: SocketAddrV4, bind_ip: Ipv4Addr, announce: Announce) -> BoostResult<TrackerInfo> {
 
     let mut buf : [u8;512]= [0;512];
-    let udp_sock = UdpSocket::bind("0.0.0.0:0").map_err(|_| BoostError::TrackerUDPSendErr)?;
+    let udp_sock = UdpSocket::bind(SocketAddrV4::new(bind_ip, 0)).map_err(|_| BoostError::TrackerUDPSendErr)?;
     let transaction_id = rand::random::<u32>();
 
     //start udp tracker protocol, send magic number
@@ -112,16 +110,16 @@ fn udp_tracker_request(server: SocketAddrV4,
     NetworkEndian::write_u32(&mut buf[8..12], 1);
     NetworkEndian::write_u32(&mut buf[12..16], transaction_id);
     for i in 0..20 {
-        buf[i+16] = info_hash[i];
+        buf[i+16] = announce.info_hash[i];
     }
     for i in 0..20 {
-        buf[i+36] = peer_id[i];
+        buf[i+36] = announce.peer_id[i];
     }
-    NetworkEndian::write_u64(&mut buf[56..64], downloaded_bytes);
-    NetworkEndian::write_u64(&mut buf[64..72], bytes_left);
-    NetworkEndian::write_u64(&mut buf[72..80], uploaded_bytes);
+    NetworkEndian::write_u64(&mut buf[56..64], announce.downloaded_bytes);
+    NetworkEndian::write_u64(&mut buf[64..72], announce.bytes_left);
+    NetworkEndian::write_u64(&mut buf[72..80], announce.uploaded_bytes);
     //send which event
-    let event = match event {
+    let event = match announce.event {
         TrackerEvent::None => 0,
         TrackerEvent::Completed => 1,
         TrackerEvent::Started => 2,
@@ -133,7 +131,7 @@ fn udp_tracker_request(server: SocketAddrV4,
     NetworkEndian::write_u32(&mut buf[88..92], 0);
     NetworkEndian::write_i32(&mut buf[92..96], -1);
     //write listen port
-    NetworkEndian::write_u16(&mut buf[96..98], listen_port);
+    NetworkEndian::write_u16(&mut buf[96..98], announce.listen_port);
     udp_sock.send_to(&buf[0..98], server).map_err(|_| BoostError::TrackerUDPSendErr)?;
 
     //recieve tracker info
@@ -156,9 +154,7 @@ fn udp_tracker_request(server: SocketAddrV4,
     let mut ipbuf = &buf[20+6*idx..20+6*(idx+1)];
     //loop while not at a zeroed out part or not at end
     while ipbuf[0] != 0 && 20+6*idx < 512 {
-        let ip = Ipv4Addr::new(ipbuf[0], ipbuf[1], ipbuf[2], ipbuf[3]);
-        let port = NetworkEndian::read_u16(&ipbuf[4..6]);
-        potential_peers.push(PotentialPeer { addr: SocketAddrV4::new(ip,port), id: None});
+        potential_peers.push(PotentialPeer { addr: compact::parse_compact_addr(ipbuf), id: None});
         idx += 1;
         ipbuf = &buf[20+6*idx..20+6*(idx+1)];
     }
@@ -183,36 +179,32 @@ fn url_encode(data: &[u8]) -> String {
 
 ///performs an HTTP tracker request to the given address
 fn http_tracker_request(server: SocketAddrV4,
+                        bind_ip: Ipv4Addr,
                         host: &str,
                         location: &str,
-                        info_hash: &[u8],
-                        peer_id: &[u8],
-                        listen_port: u16,
-                        uploaded_bytes: u64,
-                        downloaded_bytes: u64,
-                        bytes_left: u64,
-                        event: TrackerEvent,
-                        tracker_id: Option<String>) -> BoostResult<TrackerInfo> {
-    let encoded_hash = url_encode(info_hash);
-    let encoded_id = url_encode(peer_id);
+                        announce: Announce) -> BoostResult<TrackerInfo> {
+    let encoded_hash = url_encode(announce.info_hash);
+    let encoded_id = url_encode(announce.peer_id);
 
     //why did i roll my own http lol
     //build my request string
     let request_string = format!("GET {}?info_hash={}&peer_id={}&port={}&uploaded={}&downloaded={}&left={}&compact=1&no_peer_id=1",
-                                 location, encoded_hash, encoded_id, listen_port,
-                                 uploaded_bytes, downloaded_bytes, bytes_left);
-    let event_string = match event {
+                                 location, encoded_hash, encoded_id, announce.listen_port,
+                                 announce.uploaded_bytes, announce.downloaded_bytes, announce.bytes_left);
+    let event_string = match announce.event {
         TrackerEvent::None => "",
-        TrackerEvent::Completed => "event=completed",
-        TrackerEvent::Started => "event=started",
-        TrackerEvent::Stopped => "event=stopped"
+        TrackerEvent::Completed => "&event=completed",
+        TrackerEvent::Started => "&event=started",
+        TrackerEvent::Stopped => "&event=stopped"
     };
-    let tracker_string = tracker_id.unwrap_or(String::new());
+    let tracker_string = announce.tracker_id.unwrap_or(String::new());
     let request_string = format!("{}{}{} HTTP/1.1\r\nUser-Agent: BoostTorrent/0.2\r\nAccept:*/*\r\nHost: {}:{}\r\n\r\n",
                                  request_string, event_string, tracker_string, host, server.port());
 
-    //connect to server and send request
-    let mut http_sock = TcpStream::connect(server).map_err(|_| BoostError::TrackerHTTPSendErr)?;
+    //connect to server from the bind address and send request
+    let mut http_sock = TcpBuilder::new_v4()
+        .and_then(|builder| builder.bind(SocketAddrV4::new(bind_ip, 0))?.connect(server))
+        .map_err(|_| BoostError::TrackerHTTPSendErr)?;
     let _ = http_sock.write(request_string.as_bytes()).map_err(|_| BoostError::TrackerHTTPSendErr)?;
     let mut response = Vec::new();
     let _ = http_sock.read_to_end(&mut response).map_err(|_| BoostError::TrackerHTTPRecvErr)?;
@@ -278,13 +270,8 @@ fn http_tracker_request(server: SocketAddrV4,
                         &BencodeValue::Str(ref peers) => {
                             //peers are in compact mode, ip/port are in 6 byte network byte
                             //order tuples
-                            let mut pos = 0;
-                            while pos < peers.len() {
-                                let ip = Ipv4Addr::from(NetworkEndian::read_u32(&peers[pos..pos+4]));
-                                let port = NetworkEndian::read_u16(&peers[pos+4..pos+6]);
-                                potential_peers.push(PotentialPeer {addr: SocketAddrV4::new(ip,port), id: None});
-                                pos += 6;
-                            }
+                            potential_peers.extend(compact::parse_compact_peers(peers).into_iter()
+                                                   .map(|addr| PotentialPeer { addr, id: None }));
                         },
                         &BencodeValue::List(ref peers) => {
                             //peers are not compact, they are each a dict
@@ -343,3 +330,62 @@ fn http_tracker_request(server: SocketAddrV4,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    ///answers one announce with a single compact peer, returning where the
+    ///request came from and its request line
+    fn serve_once(listener: TcpListener) -> thread::JoinHandle<(SocketAddr, String)> {
+        thread::spawn(move || {
+            let (mut sock, from) = listener.accept().unwrap();
+            let mut request = [0u8; 1024];
+            let len = sock.read(&mut request).unwrap();
+            let request_line = String::from_utf8_lossy(&request[..len]).lines().next().unwrap_or("").to_string();
+            let mut response = b"HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\n\r\n".to_vec();
+            response.extend_from_slice(b"d8:intervali1800e5:peers6:");
+            response.extend_from_slice(&compact::compact_addr(SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 6881)));
+            response.extend_from_slice(b"e");
+            sock.write_all(&response).unwrap();
+            (from, request_line)
+        })
+    }
+
+    fn announce(url: &str, bind_ip: Ipv4Addr) -> BoostResult<TrackerInfo> {
+        TrackerInfo::tracker_request(url, bind_ip, Announce {
+            info_hash: &[0xaa; 20],
+            peer_id: &[1; 20],
+            listen_port: 6881,
+            uploaded_bytes: 0,
+            downloaded_bytes: 0,
+            bytes_left: 100,
+            event: TrackerEvent::Started,
+            tracker_id: None
+        })
+    }
+
+    #[test]
+    fn http_requests_come_from_the_bind_address() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://127.0.0.1:{}/announce", listener.local_addr().unwrap().port());
+        let server = serve_once(listener);
+        let info = announce(&url, Ipv4Addr::new(127, 0, 0, 1)).ok().unwrap();
+        assert_eq!(info.interval, 1800);
+        assert_eq!(info.potential_peers.len(), 1);
+        assert_eq!(info.potential_peers[0].addr, SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 6881));
+        let (from, request_line) = server.join().unwrap();
+        assert_eq!(from.ip(), Ipv4Addr::new(127, 0, 0, 1));
+        assert!(request_line.contains("&left=100&compact=1&no_peer_id=1&event=started HTTP/1.1"));
+    }
+
+    #[test]
+    fn http_requests_fail_on_a_bad_bind_address() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://127.0.0.1:{}/announce", listener.local_addr().unwrap().port());
+        //an address that isn't on this machine can't be bound to
+        assert!(announce(&url, Ipv4Addr::new(192, 0, 2, 1)).is_err());
+    }
+}